@@ -0,0 +1,35 @@
+//! Lifting, recompiling, and running arbitrary blocks.
+//!
+//! Blocks run with [Backend::Lockstep], so recompiled code is also checked
+//! against the IR evaluator. Errors are expected (plenty of instructions 
+//! can't be recompiled or interpreted), but host crashes and divergence 
+//! aren't.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libfuzzer_sys::arbitrary::{ self, Arbitrary };
+
+use nil::Backend;
+use nil::error::ErrorKind;
+
+mod common;
+use common::{ State, machine };
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    code: Vec<u32>,
+    state: State,
+}
+
+fuzz_target!(|input: Input| {
+    if input.code.is_empty() || input.code.len() > 16 {
+        return;
+    }
+    let mut jit = machine(&input.code, &input.state.guest_state());
+    jit.set_backend(Backend::Lockstep);
+    if let Err(e) = jit.step() {
+        if let ErrorKind::Divergence(_) = e.kind {
+            panic!("{}", e);
+        }
+    }
+});
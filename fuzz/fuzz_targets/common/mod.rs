@@ -0,0 +1,53 @@
+//! Setup shared between fuzz targets.
+
+use libfuzzer_sys::arbitrary::{ self, Arbitrary };
+
+use nil::Jit;
+use nil::guest::{ GuestState, Psr, CpuMode };
+
+/// Address of the code under test.
+pub const CODE: u32 = 0x0000_1000;
+/// Registers selected by [State::ptrs] point into this region.
+pub const DATA: u32 = 0x0000_8000;
+
+/// An arbitrary initial guest state.
+#[derive(Arbitrary, Debug)]
+pub struct State {
+    pub regs: [u32; 15],
+    /// Registers which are turned into pointers into [DATA] (so that most
+    /// memory accesses hit RAM instead of unmapped memory).
+    pub ptrs: u16,
+    /// The top bits of the CPSR (the NZCVQ flags).
+    pub flags: u8,
+    /// Start in user mode instead of supervisor mode.
+    pub user: bool,
+}
+impl State {
+    pub fn guest_state(&self) -> GuestState {
+        let mut cpsr = Psr((((self.flags as u32) << 24) & 0xf800_0000) | 0xc0);
+        cpsr.set_mode(if self.user { CpuMode::Usr } else { CpuMode::Svc });
+        let mut state = GuestState::new(CODE, cpsr.0);
+        for idx in 0..15 {
+            state.reg[idx] = if (self.ptrs & (1 << idx)) != 0 {
+                DATA + (self.regs[idx] & 0x0ffc)
+            } else {
+                self.regs[idx]
+            };
+        }
+        state
+    }
+}
+
+/// Create a machine with `code` at [CODE].
+///
+/// There's a breakpoint after the last instruction, so that blocks never run
+/// past the end of `code`.
+pub fn machine(code: &[u32], state: &GuestState) -> Jit {
+    let mut jit = Jit::new();
+    jit.state = *state;
+    for (idx, opcd) in code.iter().enumerate() {
+        jit.mmu.store32(CODE + 4 * idx as u32, *opcd, true).unwrap();
+    }
+    jit.add_breakpoint(CODE + 4 * code.len() as u32);
+    jit
+}
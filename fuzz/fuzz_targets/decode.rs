@@ -0,0 +1,22 @@
+//! Decoding arbitrary instruction words.
+//!
+//! The lookup tables only index on some bits of each instruction, so they
+//! must agree with the decoders on every encoding.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use nil::lift::decode::{ ArmInst, ThumbInst };
+use nil::lift::dispatch::{ ArmFn, ThumbFn };
+use nil::lift::lut::LUT;
+
+fuzz_target!(|opcd: u32| {
+    let inst = ArmInst::decode(opcd);
+    assert_eq!(LUT.arm.lookup(opcd).0 as usize, 
+        ArmFn::from_inst(inst).0 as usize, "{:08x} {:?}", opcd, inst);
+
+    let opcd = opcd as u16;
+    let inst = ThumbInst::decode(opcd);
+    assert_eq!(LUT.thumb.lookup(opcd).0 as usize, 
+        ThumbFn::from_inst(inst.clone()).0 as usize, "{:04x} {:?}", opcd, inst);
+});
@@ -0,0 +1,56 @@
+//! Running arbitrary instructions, comparing recompiled code against the 
+//! interpreter in [nil::interp].
+//!
+//! NOTE: Encodings which are UNPREDICTABLE (i.e. `mul` with `rd == rm`) may
+//! legitimately disagree.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libfuzzer_sys::arbitrary::{ self, Arbitrary };
+
+use nil::guest::{ GuestMmu, ExceptionType };
+use nil::runtime::RuntimeExitCode;
+use nil::error::ErrorKind;
+
+mod common;
+use common::{ State, CODE, DATA, machine };
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    opcd: u32,
+    state: State,
+}
+
+fuzz_target!(|input: Input| {
+    let init = input.state.guest_state();
+    let mut jit = machine(&[input.opcd], &init);
+    let mut mmu = GuestMmu::new();
+    mmu.store32(CODE, input.opcd, true).unwrap();
+    let mut reference = init;
+
+    let res = jit.step();
+    let ref_res = nil::interp::step(&mut reference, &mut mmu, input.opcd);
+    match (&res, &ref_res) {
+        (Ok(_), Ok(RuntimeExitCode::DataAbort)) => {
+            let pc = reference.pc.0;
+            reference.enter_exception(ExceptionType::DataAbort, 
+                pc.wrapping_add(8), mmu.vmsa.vector_base());
+        },
+        (Ok(_), Ok(_)) => {},
+        (Err(e), Ok(RuntimeExitCode::InvalidMode)) 
+            if e.kind == ErrorKind::InvalidMode => return,
+        // The interpreter doesn't model this
+        (Ok(_), Err(_)) | (Err(_), Err(_)) => return,
+        _ => panic!("{:?} (jit) != {:?} (interp)", res, ref_res),
+    }
+
+    let (s, r) = (&jit.state, &reference);
+    assert_eq!(s.reg, r.reg);
+    assert_eq!(s.pc.0, r.pc.0);
+    assert_eq!(s.cpsr, r.cpsr);
+    assert!(s.bank == r.bank, "banked registers");
+    for addr in ((DATA - 0x1000)..(DATA + 0x2000)).step_by(4) {
+        assert_eq!(jit.mmu.load32(addr, true).unwrap(), 
+            mmu.load32(addr, true).unwrap(), "[{:08x}]", addr);
+    }
+});
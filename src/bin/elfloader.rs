@@ -3,9 +3,6 @@ extern crate elf;
 
 use nil::Jit;
 use nil::guest::*;
-use nil::block::*;
-
-use std::collections::HashMap;
 
 pub fn main() {
     let arg: Vec<String> = std::env::args().collect();
@@ -16,6 +13,7 @@ pub fn main() {
 
     // Setup the initial state
     let mut jit = Jit::new();
+    jit.set_debug(true);
     jit.state.reg[11] = 0xdead_0011;
     jit.state.reg[13] = 0x0000_8000;
     jit.state.reg[14] = 0xdead_0014;
@@ -35,6 +33,9 @@ pub fn main() {
     }
 
     // Just run until we terminate
-    jit.run();
+    match jit.run() {
+        Ok(reason) => println!("Stopped: {:?}", reason),
+        Err(e) => println!("Error: {}", e),
+    }
 
 }
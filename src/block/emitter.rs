@@ -1,12 +1,19 @@
 
 use dynasmrt::x64::{ Assembler, Rq };
-use dynasmrt::{ dynasm, DynasmApi, ExecutableBuffer };
+use dynasmrt::{ 
+    dynasm, DynasmApi, DynasmLabelApi, AssemblyOffset, 
+    DynamicLabel 
+};
 
-use crate::block::{ BasicBlock, BlockLink };
+use crate::block::{ BasicBlock, BlockLink, BlockExit };
 use crate::ir::*;
 use crate::regalloc;
 use crate::regalloc::{ HostRegister, IntervalMap, StorageMap, StorageLoc };
-use crate::runtime::RuntimeContext;
+use crate::runtime::{ RuntimeContext, BranchCache };
+use crate::guest::{ self, GuestMmu, CpuMode, Psr };
+use crate::mem::Endianness;
+use crate::fastmem::{ self, FastmemSite };
+use crate::error::{ Error, ErrorKind, Result };
 
 macro_rules! emit { 
     ($ops:ident $($t:tt)*) => {
@@ -17,15 +24,427 @@ macro_rules! emit {
     }
 }
 
+/// Registers used to pass arguments to runtime helpers (the first argument
+/// is always a pointer to the [RuntimeContext]).
+const HELPER_ARG_REGS: [Rq; 2] = [ Rq::RSI, Rq::RDX ];
+
+/// Emit a move from any storage location into the host register `dst`.
+fn emit_load_loc(asm: &mut Assembler, dst: u8, src: &StorageLoc) {
+    use StorageLoc::*;
+    match src {
+        Gpr(r) => if *r != dst { emit!(asm; mov Rd(dst), Rd(*r)) },
+        Const(c) => emit!(asm; mov Rd(dst), *c as _),
+        Spill(off) => emit!(asm; mov Rd(dst), DWORD [rsp + *off]),
+    }
+}
+
+/// Emit a move from the host register `src` into a register or stack slot.
+fn emit_store_loc(asm: &mut Assembler, dst: &StorageLoc, src: u8) {
+    use StorageLoc::*;
+    match dst {
+        Gpr(r) => if *r != src { emit!(asm; mov Rd(*r), Rd(src)) },
+        Spill(off) => emit!(asm; mov DWORD [rsp + *off], Rd(src)),
+        Const(_) => unreachable!("store to a constant"),
+    }
+}
+
+/// Emit the moves between storage locations for spilling or reloading 
+/// values before an instruction (see [StorageMap::moves]).
+fn emit_moves(asm: &mut Assembler, storage: &StorageMap) {
+    use StorageLoc::*;
+    for (from, to) in storage.moves().iter() {
+        match (from, to) {
+            (Gpr(r), to) => emit_store_loc(asm, to, *r),
+            (from, Gpr(r)) => emit_load_loc(asm, *r, from),
+            _ => unreachable!("move from {:?} to {:?}", from, to),
+        }
+    }
+}
+
+/// Conditions on the host flags.
+#[derive(Clone, Copy)]
+enum HostCond { B, AE, O, S, Z }
+
+/// Capture a host flag as a 0/1 value in some storage location, without 
+/// changing the host flags.
+fn emit_setcc(asm: &mut Assembler, cond: HostCond, dst: &StorageLoc) {
+    match cond {
+        HostCond::B => emit!(asm; setb al),
+        HostCond::AE => emit!(asm; setae al),
+        HostCond::O => emit!(asm; seto al),
+        HostCond::S => emit!(asm; sets al),
+        HostCond::Z => emit!(asm; setz al),
+    }
+    emit!(asm; movzx eax, al);
+    emit_store_loc(asm, dst, HostRegister::RAX as u8);
+}
+
+/// Capture the carry and overflow flags produced by an arithmetic 
+/// instruction (if they're bound to variables), where `overflow` is `None` 
+/// if the overflow flag is always clear.
+fn emit_flags(asm: &mut Assembler, storage: &StorageMap, inst: &Instruction,
+    carry: HostCond, overflow: Option<HostCond>)
+{
+    if let Some(c) = inst.lh_c {
+        emit_setcc(asm, carry, storage.get(&c).unwrap());
+    }
+    if let Some(v) = inst.lh_v {
+        let dst = storage.get(&v).unwrap();
+        match overflow {
+            Some(cond) => emit_setcc(asm, cond, dst),
+            None => {
+                // NOTE: `mov` (unlike `xor`) leaves the host flags intact
+                emit_load_loc(asm, HostRegister::RAX as u8, &StorageLoc::Const(0));
+                emit_store_loc(asm, dst, HostRegister::RAX as u8);
+            },
+        }
+    }
+}
+
+/// Emit a call to [RuntimeContext::materialize_flags] if there's a pending 
+/// flag-setting operation (see [crate::runtime::LazyFlags]).
+fn emit_materialize_flags(asm: &mut Assembler, saved: &[HostRegister]) {
+    let done = asm.new_dynamic_label();
+    emit!(asm
+        ; cmp   QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_LAZY_OP], 0
+        ; je    =>done
+    );
+    emit_helper_call(asm, 
+        RuntimeContext::materialize_flags as *const () as usize, &[], saved);
+    emit!(asm; =>done);
+}
+
+/// Write the flags in `mask` (collected in `edi` by [BindOp::WriteFlag], in
+/// the layout of the CPSR) to the guest CPSR.
+fn emit_flag_flush(asm: &mut Assembler, mask: u32) {
+    emit!(asm
+        ; and   DWORD [Rq(RuntimeContext::CTX_CPSR as u8)], !mask as i32
+        ; or    DWORD [Rq(RuntimeContext::CTX_CPSR as u8)], edi
+    );
+}
+
+/// Emit code to tear down the stack frame for spilled values (before 
+/// leaving the block).
+fn emit_frame_teardown(asm: &mut Assembler, frame: i32) {
+    if frame != 0 {
+        emit!(asm; add rsp, frame);
+    }
+}
+
+/// Emit a call to a runtime helper function.
+///
+/// `saved` are the caller-save registers holding values which are live 
+/// across the call (see [StorageMap::saved]), which are preserved on the 
+/// stack (keeping it aligned for the call).
+fn emit_helper_call(asm: &mut Assembler, func: usize, args: &[StorageLoc],
+    saved: &[HostRegister]) 
+{
+    use StorageLoc::*;
+    let pad = if !saved.len().is_multiple_of(2) { 8 } else { 0 };
+    let push_size = (saved.len() * 8) as i32 + pad;
+    for reg in saved.iter() {
+        emit!(asm; push Rq(*reg as u8));
+    }
+    if pad != 0 {
+        emit!(asm; sub rsp, pad);
+    }
+    for (arg, reg) in args.iter().zip(HELPER_ARG_REGS.iter()) {
+        match arg {
+            Gpr(r) => emit!(asm; mov Rd(*reg as u8), Rd(*r)),
+            Const(c) => emit!(asm; mov Rd(*reg as u8), *c as _),
+            Spill(off) => emit!(asm
+                ; mov Rd(*reg as u8), DWORD [rsp + *off + push_size]
+            ),
+        }
+    }
+    emit!(asm
+        ; mov   rdi, Rq(RuntimeContext::CTX_SELF as u8)
+        ; mov   rax, QWORD func as _
+        ; call  rax
+    );
+    if pad != 0 {
+        emit!(asm; add rsp, pad);
+    }
+    for reg in saved.iter().rev() {
+        emit!(asm; pop Rq(*reg as u8));
+    }
+}
+
+/// Record the address of the current guest instruction before calling into
+/// a helper, in case the helper raises an exception.
+fn emit_guest_pc(asm: &mut Assembler, pc: u32) {
+    emit!(asm
+        ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x3c], pc as _
+    );
+}
+
+/// Emit a memory access through a runtime helper.
+fn emit_slow_access(asm: &mut Assembler, access: &SlowAccess) {
+    emit_guest_pc(asm, access.guest_pc);
+    emit_helper_call(asm, access.func, &access.args, &access.saved);
+    if let Some(dst) = access.dst {
+        emit_store_loc(asm, &dst, HostRegister::RAX as u8);
+    }
+    emit_exit_check(asm);
+}
+
+/// Reverse the byte order of the low `width` bits in some register.
+fn emit_bswap(asm: &mut Assembler, reg: u8, width: usize) {
+    match width {
+        16 => emit!(asm; rol Rw(reg), 8),
+        32 => emit!(asm; bswap Rd(reg)),
+        _ => unreachable!(),
+    }
+}
+
+/// Pad a fast memory access (starting at `start`) so that it can be patched
+/// into a jump to its slow path.
+fn emit_site_padding(asm: &mut Assembler, start: AssemblyOffset) {
+    while asm.offset().0 - start.0 < FastmemSite::MIN_SIZE {
+        emit!(asm; nop);
+    }
+}
+
+/// A memory access performed by calling a runtime helper.
+struct SlowAccess {
+    /// Address of the runtime helper.
+    func: usize,
+    /// Arguments to the runtime helper.
+    args: Vec<StorageLoc>,
+    /// Destination for loads.
+    dst: Option<StorageLoc>,
+    /// Address of the guest instruction performing the access.
+    guest_pc: u32,
+    /// Registers to preserve around the call.
+    saved: Vec<HostRegister>,
+}
+
+/// A fast memory access, along with the information used to emit an 
+/// out-of-line slow path for it.
+struct SlowPath {
+    access: SlowAccess,
+    /// The fast memory access.
+    start: AssemblyOffset,
+    end: AssemblyOffset,
+    /// Label after the fast memory access, where the slow path returns.
+    ret: DynamicLabel,
+}
+
+/// Emit a check for whether we should return to the dispatcher instead of
+/// branching to another block (because a runtime helper asked us to, or 
+/// because the cycle budget is exhausted), branching to `label` if so.
+fn emit_stop_check(asm: &mut Assembler, label: DynamicLabel) {
+    emit!(asm
+        ; cmp   QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_STOP], 0
+        ; jne   =>label
+        ; cmp   QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_CYCLES], 0
+        ; jle   =>label
+    );
+}
+
+/// Emit a branch to the block at `target` (see [BlockExit]), returning the
+/// offset of the patchable `jmp`.
+///
+/// The `jmp` initially falls through to a return to the dispatcher.
+fn emit_link(asm: &mut Assembler, target: u32) -> AssemblyOffset {
+    let unlinked = asm.new_dynamic_label();
+    emit!(asm
+        ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x3c], target as _
+    );
+    emit_stop_check(asm, unlinked);
+    let site = asm.offset();
+    emit!(asm
+        ; jmp   =>unlinked
+        ; =>unlinked
+        ; mov   rax, 0x0
+        ; ret
+    );
+    site
+}
+
+/// Emit a branch to `label` when `cond` fails on the guest CPSR.
+///
+/// The condition is evaluated by looking up the NZCV bits in a mask with
+/// one bit for each of the 16 combinations that pass.
+fn emit_cond_check(asm: &mut Assembler, cond: guest::Cond, label: DynamicLabel) {
+    let mask = (0..16u32).filter(|nzcv| cond.passes(Psr(nzcv << 28)))
+        .fold(0u32, |mask, nzcv| mask | (1 << nzcv));
+    emit!(asm
+        ; mov   eax, DWORD [Rq(RuntimeContext::CTX_CPSR as u8)]
+        ; shr   eax, 28
+        ; mov   edi, mask as _
+        ; bt    edi, eax
+        ; jnc   =>label
+    );
+}
+
+/// Emit an indirect branch to the address in the register `target`.
+///
+/// The target is looked up in the [BranchCache] (after checking the top of 
+/// the return address stack, if this is a return), and we only return to the
+/// dispatcher if it misses.
+fn emit_indirect(asm: &mut Assembler, target: u8, ret: bool) {
+    const RAS_MASK: i32 = (BranchCache::RAS_SIZE - 1) as i32;
+    const TABLE_MASK: i32 = (BranchCache::TABLE_SIZE - 1) as i32;
+
+    let lookup = asm.new_dynamic_label();
+    let miss = asm.new_dynamic_label();
+    emit!(asm
+        ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x3c], Rd(target)
+    );
+    emit_stop_check(asm, miss);
+    emit!(asm
+        ; mov   rsi, QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_BRANCH_CACHE]
+        ; mov   eax, Rd(target)
+    );
+
+    // Pop the return address stack, and use the prediction if it matches
+    if ret {
+        emit!(asm
+            ; mov   rdi, QWORD [rsi + BranchCache::OFF_RAS_TOP]
+            ; dec   rdi
+            ; and   rdi, RAS_MASK
+            ; mov   QWORD [rsi + BranchCache::OFF_RAS_TOP], rdi
+            ; inc   rdi
+            ; and   rdi, RAS_MASK
+            ; shl   rdi, 4
+            ; add   rdi, rsi
+            ; cmp   eax, DWORD [rdi + BranchCache::OFF_RAS]
+            ; jne   =>lookup
+            ; mov   rdi, QWORD [rdi + BranchCache::OFF_RAS + 8]
+            ; test  rdi, rdi
+            ; jz    =>lookup
+            ; jmp   rdi
+        );
+    }
+
+    emit!(asm
+        ; =>lookup
+        ; mov   edi, eax
+        ; shr   edi, 2
+        ; and   edi, TABLE_MASK
+        ; shl   rdi, 4
+        ; add   rdi, rsi
+        ; cmp   eax, DWORD [rdi + BranchCache::OFF_TABLE]
+        ; jne   =>miss
+        ; mov   rdi, QWORD [rdi + BranchCache::OFF_TABLE + 8]
+        ; test  rdi, rdi
+        ; jz    =>miss
+        ; jmp   rdi
+        ; =>miss
+        ; mov   rax, 0x0
+        ; ret
+    );
+}
+
+/// Push the return address `lr` onto the return address stack, along with 
+/// the recompiled code for `lr` (if it's in the [BranchCache]).
+fn emit_ras_push(asm: &mut Assembler, lr: u32) {
+    const RAS_MASK: i32 = (BranchCache::RAS_SIZE - 1) as i32;
+    let entry = BranchCache::OFF_TABLE 
+        + (BranchCache::index(lr) * BranchCache::ENTRY_SIZE) as i32;
+    emit!(asm
+        ; mov   rsi, QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_BRANCH_CACHE]
+        ; mov   rdi, QWORD [rsi + BranchCache::OFF_RAS_TOP]
+        ; inc   rdi
+        ; and   rdi, RAS_MASK
+        ; mov   QWORD [rsi + BranchCache::OFF_RAS_TOP], rdi
+        ; shl   rdi, 4
+        ; add   rdi, rsi
+        ; mov   DWORD [rdi + BranchCache::OFF_RAS], lr as _
+        ; xor   eax, eax
+        ; cmp   DWORD [rsi + entry], lr as _
+        ; cmove rax, QWORD [rsi + entry + 8]
+        ; mov   QWORD [rdi + BranchCache::OFF_RAS + 8], rax
+    );
+}
+
+/// Return an error for an IR instruction that we can't emit code for.
+fn codegen_error(inst: &Instruction, msg: String) -> Error {
+    Error::new(inst.guest_pc, inst.guest_op, ErrorKind::Codegen(msg))
+}
+
+/// Emit a check for an exit requested by a runtime helper.
+fn emit_exit_check(asm: &mut Assembler) {
+    emit!(asm
+        ; cmp   QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_EXIT_CODE], 0
+        ; jne   ->exit
+    );
+}
+
 impl BasicBlock {
-    pub fn recompile(&mut self) {
+    pub fn recompile(&mut self, mmu: &GuestMmu) -> Result<()> {
         use StorageLoc::*;
 
+        // When the MMU is enabled, we can't access fast memory directly.
+        // Otherwise, accesses to pages that aren't backed by host memory 
+        // (i.e. memory-mapped I/O) fault, and are patched to use a slow path.
+        let slowmem = mmu.vmsa.enabled;
+
+        // Fast memory holds guest data in guest byte order, so values need 
+        // to be swapped on big-endian guests.
+        let swap = mmu.endianness == Endianness::Big;
+
         let mut asm = Assembler::new().unwrap();
+        let mut slow_paths: Vec<SlowPath> = Vec::new();
+        let mut links: Vec<(AssemblyOffset, u32)> = Vec::new();
         self.intervals = IntervalMap::from_block(self);
         self.storage = regalloc::allocate_registers(&self.intervals);
 
-        for inst in self.data.iter_mut() {
+        // Blocks containing code which can only be fetched in privileged 
+        // modes may still be entered through a link after a switch to user 
+        // mode. Return to the dispatcher (which takes the prefetch abort).
+        if self.privileged {
+            let ok = asm.new_dynamic_label();
+            emit!(asm
+                ; mov   eax, DWORD [Rq(RuntimeContext::CTX_CPSR as u8)]
+                ; and   eax, 0x1f
+                ; cmp   eax, CpuMode::Usr as _
+                ; jne   =>ok
+                ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x3c], self.base_pc.fetch() as _
+                ; mov   rax, 0x0
+                ; ret
+                ; =>ok
+            );
+        }
+
+        // Charge the cost of the whole block on entry
+        emit!(asm
+            ; sub   QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_CYCLES], self.cycles as _
+        );
+
+        // Set up a stack frame for spilled values. This must be torn down 
+        // on every path out of the block (including linked branches).
+        let frame = self.storage.frame_size() as i32;
+        if frame != 0 {
+            emit!(asm; sub rsp, frame);
+        }
+
+        // The value that the host SF and ZF currently describe (if any)
+        let mut host_flags: Option<Var> = None;
+        // Guest flags written by a run of [BindOp::WriteFlag] instructions 
+        // which haven't been written back to the CPSR yet
+        let mut pending_flags: u32 = 0;
+
+        for (idx, inst) in self.data.iter_mut().enumerate() {
+            match inst.rh {
+                Operation::Bind(BindOp::WriteFlag(..)) => {},
+                _ => if pending_flags != 0 {
+                    emit_flag_flush(&mut asm, pending_flags);
+                    pending_flags = 0;
+                },
+            }
+            match inst.rh {
+                Operation::Bind(BindOp::Const(_)) |
+                Operation::Bind(BindOp::ReadGuestReg(_)) |
+                Operation::Bind(BindOp::WriteGuestReg(..)) |
+                Operation::Arith(ArithOp::IsZero(_)) |
+                Operation::Arith(ArithOp::IsNegative(_)) => {},
+                _ => host_flags = None,
+            }
+
+            self.storage.seek(idx);
+            emit_moves(&mut asm, &self.storage);
             match inst.rh {
                 Operation::Bind(ref op) => match op {
                     BindOp::Const(_) => {},
@@ -36,7 +455,12 @@ impl BasicBlock {
                             Gpr(r) => emit!(asm 
                                 ; mov Rd(r), DWORD [Rq(RuntimeContext::CTX_REG as u8) + off]
                             ),
-                            _ => panic!("read_reg unimpl {:?}", lh), 
+                            Spill(s) => emit!(asm
+                                ; mov eax, DWORD [Rq(RuntimeContext::CTX_REG as u8) + off]
+                                ; mov DWORD [rsp + *s], eax
+                            ),
+                            _ => return Err(codegen_error(inst, 
+                                format!("read_reg to {:?}", lh))),
                         }
                     },
                     BindOp::WriteGuestReg(idx, v) => {
@@ -49,36 +473,171 @@ impl BasicBlock {
                             Const(c) => emit!(asm
                                 ; mov DWORD [Rq(RuntimeContext::CTX_REG as u8) + off], *c as _
                             ),
-                        }
-                    },
-                    _ => panic!("emitter doesn't implement {:?}", op),
-                },
-
-                Operation::Memory(ref op) => match op {
-                    MemoryOp::Store32(addr, val) => {
-                        let addr = self.storage.get(addr).unwrap();
-                        let val = self.storage.get(val).unwrap();
-                        match (addr, val) {
-                            (Gpr(a), Gpr(v)) => emit!(asm
-                                ; mov   DWORD [Rq(RuntimeContext::CTX_FASTMEM as u8) + Rq(a)], Rd(v)
+                            Spill(s) => emit!(asm
+                                ; mov eax, DWORD [rsp + *s]
+                                ; mov DWORD [Rq(RuntimeContext::CTX_REG as u8) + off], eax
                             ),
-                            (Gpr(a), Const(v)) => emit!(asm
-                                ; mov   DWORD [Rq(RuntimeContext::CTX_FASTMEM as u8) + Rq(a)], *v as _
-                            ),
-                            _ => panic!("store32 unimpl {:?} {:?}", addr, val),
                         }
                     },
-                    MemoryOp::Load32(addr) => {
+                    BindOp::ReadFlag(kind) => {
                         let lh = self.storage.get(&inst.lh.unwrap()).unwrap();
-                        let addr = self.storage.get(addr).unwrap();
-                        match (lh, addr) {
-                            (Gpr(dst), Gpr(addr)) => emit!(asm
-                                ; mov   Rd(dst), DWORD [Rq(RuntimeContext::CTX_FASTMEM as u8) + Rq(addr)]
-                            ),
-                            _ => panic!("load32 unimpl {:?} {:?}", lh, addr),
+                        emit_materialize_flags(&mut asm, &self.storage.saved());
+                        emit!(asm
+                            ; mov   eax, DWORD [Rq(RuntimeContext::CTX_CPSR as u8)]
+                            ; shr   eax, kind.bit() as i8
+                            ; and   eax, 1
+                        );
+                        emit_store_loc(&mut asm, lh, HostRegister::RAX as u8);
+                    },
+                    // Consecutive flag writes are packed together in `edi`
+                    BindOp::WriteFlag(kind, v) => {
+                        if pending_flags == 0 {
+                            emit_materialize_flags(&mut asm, &self.storage.saved());
+                            emit!(asm; xor edi, edi);
+                        }
+                        pending_flags |= 1 << kind.bit();
+                        match self.storage.get(v).unwrap() {
+                            Const(c) => if (c & 1) != 0 {
+                                emit!(asm; or edi, (1u32 << kind.bit()) as i32);
+                            },
+                            loc => {
+                                emit_load_loc(&mut asm, HostRegister::RAX as u8, loc);
+                                emit!(asm
+                                    ; and   eax, 1
+                                    ; shl   eax, kind.bit() as i8
+                                    ; or    edi, eax
+                                );
+                            },
+                        }
+                    },
+                    // Record the operation instead of computing the flags
+                    BindOp::SetFlags(op, x, y) => {
+                        emit!(asm
+                            ; mov   QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_LAZY_OP], *op as i32
+                        );
+                        let operands = [
+                            (x, RuntimeContext::OFF_LAZY_X), 
+                            (y, RuntimeContext::OFF_LAZY_Y),
+                        ];
+                        for (v, off) in operands.iter() {
+                            match self.storage.get(v).unwrap() {
+                                Gpr(r) => emit!(asm
+                                    ; mov DWORD [Rq(RuntimeContext::CTX_SELF as u8) + *off], Rd(*r)
+                                ),
+                                Const(c) => emit!(asm
+                                    ; mov DWORD [Rq(RuntimeContext::CTX_SELF as u8) + *off], *c as _
+                                ),
+                                Spill(s) => emit!(asm
+                                    ; mov eax, DWORD [rsp + *s]
+                                    ; mov DWORD [Rq(RuntimeContext::CTX_SELF as u8) + *off], eax
+                                ),
+                            }
                         }
                     },
-                    _ => panic!("emitter doesn't implement {:?}", op),
+                    BindOp::WriteCpsr(v, mask) => {
+                        let val = *self.storage.get(v).unwrap();
+                        emit_guest_pc(&mut asm, inst.guest_pc);
+                        emit_helper_call(&mut asm, 
+                            RuntimeContext::write_cpsr as *const () as usize,
+                            &[val, Const(*mask as usize)],
+                            &self.storage.saved()
+                        );
+                        emit_exit_check(&mut asm);
+                    },
+                },
+
+                Operation::Memory(ref op) => {
+                    let access = match op {
+                        MemoryOp::Store32(addr, val) => SlowAccess {
+                            func: RuntimeContext::store32 as *const () as usize,
+                            args: vec![ 
+                                *self.storage.get(addr).unwrap(), 
+                                *self.storage.get(val).unwrap() 
+                            ],
+                            dst: None, 
+                            guest_pc: inst.guest_pc,
+                            saved: self.storage.saved(),
+                        },
+                        MemoryOp::Load32(addr) => SlowAccess {
+                            func: RuntimeContext::load32 as *const () as usize,
+                            args: vec![ *self.storage.get(addr).unwrap() ],
+                            dst: match self.storage.get(&inst.lh.unwrap()) {
+                                Some(dst @ Gpr(_)) | Some(dst @ Spill(_)) => {
+                                    Some(*dst)
+                                },
+                                lh => return Err(codegen_error(inst, 
+                                    format!("load32 to {:?}", lh))),
+                            },
+                            guest_pc: inst.guest_pc,
+                            saved: self.storage.saved(),
+                        },
+                    };
+                    if slowmem {
+                        emit_slow_access(&mut asm, &access);
+                        continue;
+                    }
+
+                    // NOTE: Everything emitted here is part of the patchable
+                    // site, so it must not have any side-effects on live 
+                    // values before the access itself.
+                    let start = asm.offset();
+                    let a = match access.args[0] {
+                        Gpr(a) => a,
+                        Spill(s) => {
+                            emit!(asm; mov esi, DWORD [rsp + s]);
+                            HostRegister::RSI as u8
+                        },
+                        _ => return Err(codegen_error(inst, 
+                            format!("fastmem {:?}", access.args))),
+                    };
+                    match (op, &access.args[1..], access.dst) {
+                        (MemoryOp::Store32(..), [Const(v)], _) => {
+                            let v = if swap { 
+                                (*v as u32).swap_bytes() 
+                            } else { 
+                                *v as u32 
+                            };
+                            emit!(asm
+                                ; mov   DWORD [Rq(RuntimeContext::CTX_FASTMEM as u8) + Rq(a)], v as _
+                            );
+                        },
+                        (MemoryOp::Store32(..), [Gpr(v)], _) if !swap => {
+                            emit!(asm
+                                ; mov   DWORD [Rq(RuntimeContext::CTX_FASTMEM as u8) + Rq(a)], Rd(*v)
+                            );
+                        },
+                        (MemoryOp::Store32(..), [v], _) => {
+                            emit_load_loc(&mut asm, HostRegister::RAX as u8, v);
+                            if swap {
+                                emit_bswap(&mut asm, HostRegister::RAX as u8, 32);
+                            }
+                            emit!(asm
+                                ; mov   DWORD [Rq(RuntimeContext::CTX_FASTMEM as u8) + Rq(a)], eax
+                            );
+                        },
+                        (MemoryOp::Load32(..), [], Some(Gpr(dst))) => {
+                            emit!(asm
+                                ; mov   Rd(dst), DWORD [Rq(RuntimeContext::CTX_FASTMEM as u8) + Rq(a)]
+                            );
+                            if swap { emit_bswap(&mut asm, dst, 32); }
+                        },
+                        (MemoryOp::Load32(..), [], Some(Spill(s))) => {
+                            emit!(asm
+                                ; mov   eax, DWORD [Rq(RuntimeContext::CTX_FASTMEM as u8) + Rq(a)]
+                            );
+                            if swap { 
+                                emit_bswap(&mut asm, HostRegister::RAX as u8, 32); 
+                            }
+                            emit!(asm; mov DWORD [rsp + s], eax);
+                        },
+                        _ => return Err(codegen_error(inst, 
+                            format!("fastmem {:?}", access.args))),
+                    }
+                    emit_site_padding(&mut asm, start);
+                    let end = asm.offset();
+                    let ret = asm.new_dynamic_label();
+                    emit!(asm; =>ret);
+                    slow_paths.push(SlowPath { access, start, end, ret });
                 },
 
                 Operation::Arith(ref op) => match op {
@@ -97,27 +656,24 @@ impl BasicBlock {
                                     ; sub Rd(d), *y as _);
                                 }
                             },
-                            _ => panic!("unimpl sub32 {:?} {:?}", x, y),
+                            // Operands in memory go through a scratch register
+                            (d @ Gpr(_), x, y) | (d @ Spill(_), x, y) => {
+                                emit_load_loc(&mut asm, HostRegister::RAX as u8, x);
+                                match y {
+                                    Gpr(y) => emit!(asm; sub eax, Rd(*y)),
+                                    Const(y) => emit!(asm; sub eax, *y as _),
+                                    Spill(s) => emit!(asm; sub eax, DWORD [rsp + *s]),
+                                }
+                                emit_store_loc(&mut asm, d, HostRegister::RAX as u8);
+                            },
+                            _ => return Err(codegen_error(inst, 
+                                format!("sub32 {:?} {:?}", x, y))),
                         }
 
-                        // How to deal with flags?
-                        //if inst.lh_c.is_some() {
-                        //    let c_var = inst.lh_c.unwrap();
-                        //    let c_loc = self.storage.get(&c_var).unwrap();
-                        //    match c_loc {
-                        //        Gpr(c) => { emit!(asm; setc Rb(c)); },
-                        //        _ => panic!("lh_c is a constant?"),
-                        //    }
-                        //}
-                        //if inst.lh_v.is_some() {
-                        //    let v_var = inst.lh_v.unwrap();
-                        //    let v_loc = self.storage.get(&v_var).unwrap();
-                        //    match v_loc {
-                        //        Gpr(v) => { emit!(asm; seto Rb(v)); },
-                        //        _ => panic!("lh_v is a constant?"),
-                        //    }
-                        //}
-
+                        // The ARM carry flag is the inverse of the borrow
+                        emit_flags(&mut asm, &self.storage, inst, 
+                            HostCond::AE, Some(HostCond::O));
+                        host_flags = inst.lh;
                     },
 
                     ArithOp::Add32(x, y) => {
@@ -135,36 +691,132 @@ impl BasicBlock {
                                     );
                                 }
                             },
-                            _ => panic!("unimpl add32 {:?} {:?}", x, y),
+                            (d @ Gpr(_), x, y) | (d @ Spill(_), x, y) => {
+                                emit_load_loc(&mut asm, HostRegister::RAX as u8, x);
+                                match y {
+                                    Gpr(y) => emit!(asm; add eax, Rd(*y)),
+                                    Const(y) => emit!(asm; add eax, *y as _),
+                                    Spill(s) => emit!(asm; add eax, DWORD [rsp + *s]),
+                                }
+                                emit_store_loc(&mut asm, d, HostRegister::RAX as u8);
+                            },
+                            _ => return Err(codegen_error(inst, 
+                                format!("add32 {:?} {:?}", x, y))),
                         }
-                        if inst.lh_c.is_some() { unimplemented!(""); }
-                        if inst.lh_v.is_some() { unimplemented!(""); }
+                        emit_flags(&mut asm, &self.storage, inst, 
+                            HostCond::B, Some(HostCond::O));
+                        host_flags = inst.lh;
                     },
-                    ArithOp::IsNegative(x) => {
+
+                    // NOTE: The host masks the shift count (and doesn't 
+                    // change the flags when it's zero), so only constant 
+                    // shifts by 1-31 bits are supported here.
+                    ArithOp::Lsl32(x, y) | ArithOp::Shl32(x, y) => {
                         let lh = self.storage.get(&inst.lh.unwrap()).unwrap();
                         let x = self.storage.get(x).unwrap();
-                        match (lh, x) {
-                            _ => panic!("unimpl is_negative {:?}", x),
+                        let y = self.storage.get(y).unwrap();
+                        match (lh, y) {
+                            (d @ Gpr(_), Const(s)) | (d @ Spill(_), Const(s))
+                                if (1..32).contains(s) => 
+                            {
+                                emit_load_loc(&mut asm, HostRegister::RAX as u8, x);
+                                emit!(asm; shl eax, *s as i8);
+                                emit_store_loc(&mut asm, d, HostRegister::RAX as u8);
+                            },
+                            _ => return Err(codegen_error(inst, 
+                                format!("lsl32 {:?} {:?}", x, y))),
+                        }
+                        emit_flags(&mut asm, &self.storage, inst, 
+                            HostCond::B, None);
+                        host_flags = inst.lh;
+                    },
+
+                    // Reuse the host flags if they already describe `x`
+                    ArithOp::IsZero(x) | ArithOp::IsNegative(x) => {
+                        let zero = matches!(op, ArithOp::IsZero(_));
+                        let lh = self.storage.get(&inst.lh.unwrap()).unwrap();
+                        match self.storage.get(x).unwrap() {
+                            Const(c) => {
+                                let c = *c as u32;
+                                let val = if zero { (c == 0) as u32 } else { c >> 31 };
+                                emit_load_loc(&mut asm, HostRegister::RAX as u8, 
+                                    &Const(val as usize));
+                                emit_store_loc(&mut asm, lh, HostRegister::RAX as u8);
+                            },
+                            loc => {
+                                if host_flags != Some(*x) {
+                                    match loc {
+                                        Gpr(r) => emit!(asm; test Rd(*r), Rd(*r)),
+                                        _ => {
+                                            emit_load_loc(&mut asm, 
+                                                HostRegister::RAX as u8, loc);
+                                            emit!(asm; test eax, eax);
+                                        },
+                                    }
+                                    host_flags = Some(*x);
+                                }
+                                let cond = if zero { HostCond::Z } else { HostCond::S };
+                                emit_setcc(&mut asm, cond, lh);
+                            },
                         }
                     },
 
-                    _ => panic!("emitter doesn't implement {:?}", op),
+                    _ => return Err(codegen_error(inst, format!("{:?}", op))),
                 },
             }
         }
 
+        if pending_flags != 0 {
+            emit_flag_flush(&mut asm, pending_flags);
+        }
+        self.storage.seek(self.data.len());
+        emit_moves(&mut asm, &self.storage);
+
+        // Errors in the terminal are associated with the last instruction
+        let last_pc = self.base_pc.fetch()
+            .wrapping_add(4 * (self.guest_ops.len() as u32 - 1));
+        let last_opcd = self.last_opcd();
+        let link_error = |msg: String| {
+            Error::new(last_pc, last_opcd, ErrorKind::Codegen(msg))
+        };
         if let Some(link) = self.link {
             match link {
                 BlockLink::Branch(ref addr) => {
                     let addr = self.storage.get(addr).unwrap();
                     match addr {
-                        // NOTE: Is the layout of GuestState stable enough for this?
-                        Const(c) => emit!(asm
-                            ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x3c], *c as _
-                            ; mov   rax, 0x0
-                            ; ret
-                        ),
-                        _ => panic!("unimpl branch to {:?}", addr),
+                        Const(c) => {
+                            let target = *c as u32;
+                            emit_frame_teardown(&mut asm, frame);
+                            links.push((emit_link(&mut asm, target), target));
+                        },
+                        Gpr(r) => {
+                            emit_frame_teardown(&mut asm, frame);
+                            emit_indirect(&mut asm, *r, false);
+                        },
+                        Spill(s) => {
+                            emit!(asm; mov eax, DWORD [rsp + *s]);
+                            emit_frame_teardown(&mut asm, frame);
+                            emit_indirect(&mut asm, HostRegister::RAX as u8, false);
+                        },
+                    }
+                },
+
+                BlockLink::Return(ref addr) => {
+                    match self.storage.get(addr).unwrap() {
+                        Const(c) => {
+                            let target = *c as u32;
+                            emit_frame_teardown(&mut asm, frame);
+                            links.push((emit_link(&mut asm, target), target));
+                        },
+                        Gpr(r) => {
+                            emit_frame_teardown(&mut asm, frame);
+                            emit_indirect(&mut asm, *r, true);
+                        },
+                        Spill(s) => {
+                            emit!(asm; mov eax, DWORD [rsp + *s]);
+                            emit_frame_teardown(&mut asm, frame);
+                            emit_indirect(&mut asm, HostRegister::RAX as u8, true);
+                        },
                     }
                 },
 
@@ -173,23 +825,87 @@ impl BasicBlock {
                     let new_lr = self.storage.get(new_lr).unwrap();
                     match (addr, new_lr) {
                         // NOTE: Is the layout of GuestState stable enough for this?
-                        (Const(d), Const(l)) => emit!(asm
-                            ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x38], *l as _
-                            ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x3c], *d as _
-                            ; mov   rax, 0x0
-                            ; ret
-                        ),
-                        _ => panic!("unimpl branch_link to {:?}", addr),
+                        (Const(d), Const(l)) => {
+                            emit_frame_teardown(&mut asm, frame);
+                            emit!(asm
+                                ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x38], *l as _
+                            );
+                            emit_ras_push(&mut asm, *l as u32);
+                            let target = *d as u32;
+                            links.push((emit_link(&mut asm, target), target));
+                        },
+                        _ => return Err(link_error(
+                            format!("branch_link to {:?}", addr))),
                     }
                 },
-                _ => panic!("emitter terminal {:?} unimpl", link),
+                BlockLink::BranchCond(cond, ref t, ref f) => {
+                    let t = self.storage.get(t).unwrap();
+                    let f = self.storage.get(f).unwrap();
+                    match (t, f) {
+                        (Const(t), Const(f)) => {
+                            // Flags from a previous block may still be 
+                            // pending
+                            let not_taken = asm.new_dynamic_label();
+                            emit_materialize_flags(&mut asm, &[]);
+                            emit_frame_teardown(&mut asm, frame);
+                            emit_cond_check(&mut asm, cond, not_taken);
+                            let (t, f) = (*t as u32, *f as u32);
+                            links.push((emit_link(&mut asm, t), t));
+                            emit!(asm; =>not_taken);
+                            links.push((emit_link(&mut asm, f), f));
+                        },
+                        _ => return Err(link_error(
+                            format!("branch_cond to {:?}", t))),
+                    }
+                },
+                BlockLink::Exit(code, pc) => {
+                    emit_frame_teardown(&mut asm, frame);
+                    emit!(asm
+                        ; mov   DWORD [Rq(RuntimeContext::CTX_REG as u8) + 0x3c], pc as _
+                        ; mov   rax, code as i32
+                        ; ret
+                    );
+                },
             }
         } else {
-            panic!("Block has no terminal element");
+            return Err(link_error("no terminal".to_string()));
+        }
+
+        // Exit requested by a runtime helper
+        emit!(asm; ->exit:);
+        emit_frame_teardown(&mut asm, frame);
+        emit!(asm
+            ; mov   rax, QWORD [Rq(RuntimeContext::CTX_SELF as u8) + RuntimeContext::OFF_EXIT_CODE]
+            ; ret
+        );
+
+        // Out-of-line slow paths for fast memory accesses
+        let mut sites = Vec::new();
+        for path in slow_paths.iter() {
+            let stub = asm.offset();
+            emit_slow_access(&mut asm, &path.access);
+            emit!(asm; jmp =>path.ret);
+            sites.push((path.start, path.end, stub));
         }
 
         asm.commit().unwrap();
         self.code = asm.finalize().unwrap();
+        // Sites which were already registered are unregistered when the 
+        // block is dropped
+        for (start, end, stub) in sites.iter() {
+            let registered = fastmem::register(FastmemSite {
+                start: self.code.ptr(*start) as usize,
+                end: self.code.ptr(*end) as usize,
+                stub: self.code.ptr(*stub) as usize,
+            });
+            if !registered {
+                return Err(link_error("too many fast memory sites".to_string()));
+            }
+        }
+        self.exits = links.iter().map(|(site, target)| BlockExit {
+            site: self.code.ptr(*site) as usize, target: *target,
+        }).collect();
+        Ok(())
     }
 }
 
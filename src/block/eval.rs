@@ -0,0 +1,304 @@
+//! Evaluating the IR for a block directly, without recompiling it.
+//!
+//! This is the reference semantics for the IR: the emitter is expected to
+//! generate code which behaves exactly like [BasicBlock::eval].
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::block::{ BasicBlock, BlockLink };
+use crate::ir::*;
+use crate::guest::{ GuestState, GuestMmu, MemorySnapshot, CpuMode, Psr, RegIdx };
+use crate::mmu::Fault;
+use crate::runtime::RuntimeExitCode;
+use crate::error::{ Error, ErrorKind, Result };
+
+/// Shift left, where shifting by 32 or more bits results in zero.
+fn shl(x: u32, y: u32) -> u32 {
+    if y >= 32 { 0 } else { x << y }
+}
+
+/// Shift right, where shifting by 32 or more bits results in zero.
+fn shr(x: u32, y: u32) -> u32 {
+    if y >= 32 { 0 } else { x >> y }
+}
+
+/// A store performed while evaluating a block.
+#[derive(Clone, Copy, Debug)]
+pub struct StoreRecord {
+    /// The virtual address.
+    pub addr: u32,
+    /// The value in memory before the store (or `None` if the store went 
+    /// to memory-mapped I/O, where it can't be read back without side 
+    /// effects).
+    pub old: Option<u32>,
+    /// The value stored.
+    pub new: u32,
+    pub privileged: bool,
+}
+
+/// The values of IR variables while evaluating a block.
+struct Env {
+    vars: HashMap<VarId, u32>,
+}
+impl Env {
+    fn get(&self, var: &Var) -> Option<u32> {
+        match var.kind {
+            VarKind::Constant(val) => Some(val as u32),
+            _ => self.vars.get(&var.id).cloned(),
+        }
+    }
+    fn set(&mut self, var: Option<Var>, val: u32) {
+        if let Some(var) = var {
+            self.vars.insert(var.id, val);
+        }
+    }
+}
+
+fn read_reg(state: &GuestState, idx: RegIdx) -> u32 {
+    if idx == 15 { state.pc.0 } else { state.reg[idx as usize] }
+}
+fn write_reg(state: &mut GuestState, idx: RegIdx, val: u32) {
+    if idx == 15 { state.pc.0 = val; } else { state.reg[idx as usize] = val; }
+}
+
+impl BasicBlock {
+    /// Run this block by evaluating its IR against `state` and `mmu`.
+    ///
+    /// This follows the same conventions as recompiled code: the result is
+    /// the exit code that the block would return to the dispatcher, and for
+    /// anything other than [RuntimeExitCode::NextBlock], the program counter
+    /// is left at the guest instruction which caused the exit. The cost of
+    /// the block isn't charged here.
+    pub fn eval(&self, state: &mut GuestState, mmu: &mut GuestMmu)
+        -> Result<RuntimeExitCode>
+    {
+        self.eval_logged(state, mmu, None)
+    }
+
+    /// Like [BasicBlock::eval], but also record every store that completes
+    /// in `stores` (so that the caller can undo them).
+    pub fn eval_logged(&self, state: &mut GuestState, mmu: &mut GuestMmu,
+        mut stores: Option<&mut Vec<StoreRecord>>) -> Result<RuntimeExitCode>
+    {
+        let mut env = Env { vars: HashMap::new() };
+
+        for inst in self.data.iter() {
+            let get = |env: &Env, var: &Var| env.get(var).ok_or_else(|| {
+                Error::new(inst.guest_pc, inst.guest_op, 
+                    ErrorKind::InvalidIr(format!("{} is undefined", var)))
+            });
+            match inst.rh {
+                Operation::Bind(ref op) => match op {
+                    BindOp::Const(c) => env.set(inst.lh, c.value as u32),
+                    BindOp::ReadGuestReg(idx) => {
+                        env.set(inst.lh, read_reg(state, *idx));
+                    },
+                    BindOp::WriteGuestReg(idx, v) => {
+                        let val = get(&env, v)?;
+                        write_reg(state, *idx, val);
+                    },
+                    BindOp::ReadFlag(kind) => {
+                        let val = (state.cpsr.0 >> kind.bit()) & 1;
+                        env.set(inst.lh, val);
+                    },
+                    BindOp::WriteFlag(kind, v) => {
+                        let bit = 1 << kind.bit();
+                        if (get(&env, v)? & 1) != 0 {
+                            state.cpsr.0 |= bit;
+                        } else {
+                            state.cpsr.0 &= !bit;
+                        }
+                    },
+                    // Flags are always computed right away here
+                    BindOp::SetFlags(op, x, y) => {
+                        let (x, y) = (get(&env, x)?, get(&env, y)?);
+                        state.cpsr.0 = op.apply(state.cpsr.0, x, y);
+                    },
+                    // Like [RuntimeContext::write_cpsr]
+                    BindOp::WriteCpsr(v, mask) => {
+                        let val = get(&env, v)?;
+                        let mask = if state.cpsr.is_privileged() {
+                            *mask
+                        } else {
+                            *mask & 0xf000_0000
+                        };
+                        let new_cpsr = Psr((state.cpsr.0 & !mask) | (val & mask));
+                        match CpuMode::try_from(new_cpsr.0 & 0x1f) {
+                            Ok(mode) => {
+                                state.switch_mode(mode);
+                                state.cpsr = new_cpsr;
+                            },
+                            Err(_) => {
+                                state.pc.0 = inst.guest_pc;
+                                return Ok(RuntimeExitCode::InvalidMode);
+                            },
+                        }
+                    },
+                },
+
+                // Like the runtime helpers for memory accesses
+                Operation::Memory(ref op) => {
+                    let privileged = state.cpsr.is_privileged();
+                    mmu.take_unmapped();
+                    let res: std::result::Result<(), Fault> = match op {
+                        MemoryOp::Load32(addr) => {
+                            let addr = get(&env, addr)?;
+                            mmu.load32(addr, privileged)
+                                .map(|val| env.set(inst.lh, val))
+                        },
+                        MemoryOp::Store32(addr, val) => {
+                            let (addr, val) = (get(&env, addr)?, get(&env, val)?);
+                            // Only read the old value if we're logging
+                            let old = match stores {
+                                Some(_) => mmu.peek32(addr, privileged),
+                                None => None,
+                            };
+                            mmu.store32(addr, val, privileged).map(|_| {
+                                if let Some(stores) = stores.as_deref_mut() {
+                                    stores.push(StoreRecord { 
+                                        addr, old, new: val, privileged 
+                                    });
+                                }
+                            })
+                        },
+                    };
+                    let code = match res {
+                        Err(fault) => {
+                            mmu.vmsa.record_fault(fault);
+                            RuntimeExitCode::DataAbort
+                        },
+                        Ok(()) if mmu.unmapped().is_some() => {
+                            RuntimeExitCode::MemoryFault
+                        },
+                        Ok(()) => continue,
+                    };
+                    state.pc.0 = inst.guest_pc;
+                    return Ok(code);
+                },
+
+                Operation::Arith(ref op) => match op {
+                    ArithOp::Add32(x, y) => {
+                        let (x, y) = (get(&env, x)?, get(&env, y)?);
+                        let (res, c) = x.overflowing_add(y);
+                        let (_, v) = (x as i32).overflowing_add(y as i32);
+                        env.set(inst.lh, res);
+                        env.set(inst.lh_c, c as u32);
+                        env.set(inst.lh_v, v as u32);
+                    },
+                    // NOTE: The carry flag is the ARM carry (not borrow).
+                    ArithOp::Sub32(x, y) => {
+                        let (x, y) = (get(&env, x)?, get(&env, y)?);
+                        let res = x.wrapping_sub(y);
+                        let (_, v) = (x as i32).overflowing_sub(y as i32);
+                        env.set(inst.lh, res);
+                        env.set(inst.lh_c, (x >= y) as u32);
+                        env.set(inst.lh_v, v as u32);
+                    },
+                    ArithOp::And32(x, y) => {
+                        env.set(inst.lh, get(&env, x)? & get(&env, y)?);
+                    },
+                    ArithOp::Or32(x, y) => {
+                        env.set(inst.lh, get(&env, x)? | get(&env, y)?);
+                    },
+                    // The carry flag is the last bit shifted out (and the
+                    // overflow flag is always clear).
+                    ArithOp::Lsl32(x, y) | ArithOp::Shl32(x, y) => {
+                        let (x, y) = (get(&env, x)?, get(&env, y)?);
+                        let c = match y {
+                            1..=32 => (x >> (32 - y)) & 1,
+                            _ => 0,
+                        };
+                        env.set(inst.lh, shl(x, y));
+                        env.set(inst.lh_c, c);
+                        env.set(inst.lh_v, 0);
+                    },
+                    ArithOp::Shr32(x, y) => {
+                        let (x, y) = (get(&env, x)?, get(&env, y)?);
+                        let c = match y {
+                            1..=32 => (x >> (y - 1)) & 1,
+                            _ => 0,
+                        };
+                        env.set(inst.lh, shr(x, y));
+                        env.set(inst.lh_c, c);
+                        env.set(inst.lh_v, 0);
+                    },
+                    ArithOp::IsZero(x) => {
+                        env.set(inst.lh, (get(&env, x)? == 0) as u32);
+                    },
+                    ArithOp::IsNegative(x) => {
+                        env.set(inst.lh, get(&env, x)? >> 31);
+                    },
+                },
+            }
+        }
+
+        // Errors in the terminal are associated with the last instruction
+        let last_pc = self.base_pc.fetch()
+            .wrapping_add(4 * (self.guest_ops.len() as u32 - 1));
+        let link_error = |msg: String| {
+            Error::new(last_pc, self.last_opcd(), ErrorKind::InvalidIr(msg))
+        };
+        let link = match self.link {
+            Some(link) => link,
+            None => return Err(link_error("no terminal".to_string())),
+        };
+        let get = |var: Var| env.get(&var)
+            .ok_or_else(|| link_error(format!("{} is undefined", var)));
+        match link {
+            BlockLink::Branch(addr) | BlockLink::Return(addr) => {
+                state.pc.0 = get(addr)?;
+            },
+            BlockLink::BranchAndLink(addr, lr) => {
+                state.reg[14] = get(lr)?;
+                state.pc.0 = get(addr)?;
+            },
+            BlockLink::BranchCond(cond, t, f) => {
+                let target = if cond.passes(state.cpsr) { t } else { f };
+                state.pc.0 = get(target)?;
+            },
+            BlockLink::Exit(code, pc) => {
+                state.pc.0 = pc;
+                return Ok(code);
+            },
+        }
+        Ok(RuntimeExitCode::NextBlock)
+    }
+}
+
+/// Compare the results of running a block in the evaluator and in recompiled
+/// code, describing the first difference (if any).
+///
+/// `expected` is a snapshot of memory after running the evaluator, which is
+/// compared against memory after running the recompiled code (so that a 
+/// store which only one of them performed is also noticed).
+pub fn divergence(eval: (&GuestState, RuntimeExitCode), 
+    native: (&GuestState, RuntimeExitCode), expected: &MemorySnapshot, 
+    mmu: &GuestMmu) -> Option<String>
+{
+    let ((es, ecode), (ns, ncode)) = (eval, native);
+    if ecode != ncode {
+        return Some(format!("exit code {:?} (eval) != {:?} (native)", 
+            ecode, ncode));
+    }
+    if es.pc.0 != ns.pc.0 {
+        return Some(format!("pc {:08x} (eval) != {:08x} (native)", 
+            es.pc.0, ns.pc.0));
+    }
+    for idx in 0..15 {
+        if es.reg[idx] != ns.reg[idx] {
+            return Some(format!("r{} {:08x} (eval) != {:08x} (native)", 
+                idx, es.reg[idx], ns.reg[idx]));
+        }
+    }
+    if es.cpsr != ns.cpsr {
+        return Some(format!("cpsr {:08x} (eval) != {:08x} (native)", 
+            es.cpsr.0, ns.cpsr.0));
+    }
+    if es.bank != ns.bank {
+        return Some("banked registers".to_string());
+    }
+    // Stores to memory-mapped I/O can't be compared
+    mmu.compare(expected).map(|(pa, eval, native)| format!(
+        "[{:08x}] {:08x} (eval) != {:08x} (native)", pa, eval, native))
+}
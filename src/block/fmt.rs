@@ -10,6 +10,8 @@ impl fmt::Display for BlockLink {
                 write!(f, "BranchAndLink({}, {})", addr, lr),
             BlockLink::BranchCond(c, t_addr, f_addr) => 
                 write!(f, "BranchCond({:?}, {}, {})", c, t_addr, f_addr),
+            BlockLink::Return(a) => write!(f, "Return({})", a),
+            BlockLink::Exit(code, pc) => write!(f, "Exit({:?}, {:08x})", code, pc),
         }
     }
 }
@@ -1,30 +1,111 @@
 
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
 use crate::ir::*;
 use crate::guest;
+use crate::mmu::Access;
+use crate::error::{ Error, ErrorKind, Result };
 use crate::block::{ BasicBlock, BlockLink };
 
 use crate::lift::lut::LUT;
-use crate::lift::decode::ArmInst;
 
 impl BasicBlock {
-    pub fn lift(state: &guest::GuestState, mmu: &guest::GuestMmu) -> Self {
+    /// Lift a block starting at the current program counter.
+    ///
+    /// Blocks never contain an instruction at one of the addresses in 
+    /// `stops` (other than the first), like breakpoints.
+    ///
+    /// If some instruction can't be lifted, the block ends before it, and 
+    /// the error is only returned when it's the first instruction.
+    pub fn lift(state: &guest::GuestState, mmu: &mut guest::GuestMmu,
+        stops: &HashSet<u32>) -> Result<Self> 
+    {
+        let privileged = state.cpsr.is_privileged();
+
         // Make a new basic block
         let mut bb = BasicBlock::new(state.pc);
         loop {
+            let pc = bb.read_fetch_pc();
+            if !bb.guest_ops.is_empty() && stops.contains(&pc) {
+                let next = bb.constant(32, pc as usize);
+                bb.terminate(BlockLink::Branch(next));
+                break;
+            }
 
-            // Fetch the next instruction
-            let opcd = mmu.read32( bb.read_fetch_pc() );
+            // Fetch the next instruction.
+            // If the fetch would abort (or touches unmapped memory), end the 
+            // block before this instruction (the dispatcher deals with it 
+            // when the next block is fetched).
+            mmu.take_unmapped();
+            let res = mmu.fetch32(pc, privileged);
+            let unmapped = mmu.take_unmapped();
+            let opcd = match res {
+                Ok(opcd) if unmapped.is_none() => opcd,
+                _ => {
+                    if bb.guest_ops.is_empty() {
+                        return Err(Error::new(pc, 0, ErrorKind::Fetch));
+                    }
+                    let next = bb.constant(32, pc as usize);
+                    bb.terminate(BlockLink::Branch(next));
+                    break;
+                },
+            };
             bb.guest_ops.push(opcd);
 
-            // Lift the instruction into the basic block
-            LUT.arm.lookup(opcd).0(&mut bb, opcd);
+            // Track writes to each page of guest code in this block
+            if bb.guest_ops.len() == 1 || pc & 0xfff == 0 {
+                let pa = mmu.translate(pc, Access::Fetch, privileged)
+                    .map_err(|_| Error::new(pc, opcd, ErrorKind::Fetch))?;
+                if let Some(page) = mmu.track_code(pa) {
+                    bb.code_pages.push(page);
+                }
+                if privileged && mmu.translate(pc, Access::Fetch, false).is_err() {
+                    bb.privileged = true;
+                }
+            }
+
+            // Lift the instruction into the basic block.
+            // On failure, throw away anything that was partially lifted.
+            let (len, lb) = (bb.data.len(), bb.lb.clone());
+            if let Err(e) = LUT.arm.lookup(opcd).0(&mut bb, opcd) {
+                if bb.guest_ops.len() == 1 {
+                    return Err(e);
+                }
+                bb.data.truncate(len);
+                bb.lb = lb;
+                bb.link = None;
+                bb.guest_ops.pop();
+                let next = bb.constant(32, pc as usize);
+                bb.terminate(BlockLink::Branch(next));
+                break;
+            }
             match bb.link {
-                Some(link) => break,
+                Some(_) => break,
                 None => bb.increment_pc(),
             }
         }
 
-        bb
+        Ok(bb)
+    }
+
+    /// Return an error associated with the current guest instruction.
+    pub fn error(&self, kind: ErrorKind) -> Error {
+        Error::new(self.read_fetch_pc(), self.last_opcd(), kind)
+    }
+
+    /// Decode the condition field of the current guest instruction.
+    pub fn cond(&self, bits: u32) -> Result<guest::Cond> {
+        guest::Cond::try_from(bits)
+            .map_err(|_| self.error(ErrorKind::InvalidCond(bits)))
+    }
+
+    /// Fail unless the current guest instruction is unconditional.
+    pub fn require_al(&self, bits: u32) -> Result<()> {
+        match self.cond(bits)? {
+            guest::Cond::AL => Ok(()),
+            _ => Err(self.error(ErrorKind::Unsupported("condition"))),
+        }
     }
 }
 
@@ -38,6 +119,8 @@ pub trait BindOpLifter {
     fn write_reg(&mut self, reg: Self::Reg, val: Self::Var);
     fn read_flag(&mut self, kind: Self::Flag) -> Self::Var;
     fn write_flag(&mut self, kind: Self::Flag, val: Self::Var);
+    fn write_cpsr(&mut self, val: Self::Var, mask: u32);
+    fn set_flags(&mut self, op: FlagOp, x: Self::Var, y: Self::Var);
 }
 impl BindOpLifter for BasicBlock {
     type Var = Var;
@@ -75,6 +158,14 @@ impl BindOpLifter for BasicBlock {
     fn write_flag(&mut self, kind: FlagKind, val: Var) {
         self.push(Instruction::write_flag(self.last_opcd(), kind, val));
     }
+
+    fn write_cpsr(&mut self, val: Var, mask: u32) {
+        self.push(Instruction::write_cpsr(self.last_opcd(), val, mask));
+    }
+
+    fn set_flags(&mut self, op: FlagOp, x: Var, y: Var) {
+        self.push(Instruction::set_flags(self.last_opcd(), op, x, y));
+    }
 }
 
 pub trait MemoryOpLifter {
@@ -1,6 +1,7 @@
 
 mod fmt;
 pub mod emitter;
+pub mod eval;
 
 pub mod lifter;
 pub use crate::block::lifter::{ 
@@ -14,6 +15,9 @@ use crate::ir::*;
 use crate::regalloc::{ IntervalMap, StorageMap };
 use crate::guest::ProgramCounter;
 use crate::guest;
+use crate::fastmem;
+use crate::patch;
+use crate::runtime::RuntimeExitCode;
 
 #[derive(Clone)]
 pub struct LocalBindings {
@@ -59,12 +63,46 @@ impl LocalBindings {
         var
     }
 }
+impl Default for LocalBindings {
+    fn default() -> Self { LocalBindings::new() }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BlockLink {
     BranchAndLink(Var, Var),
     Branch(Var),
     BranchCond(guest::Cond, Var, Var),
+    /// An indirect branch which is (probably) a return from a subroutine.
+    Return(Var),
+    /// Return to the dispatcher with some exit code, leaving the program
+    /// counter at the guest instruction which caused the exit.
+    Exit(RuntimeExitCode, u32),
+}
+
+/// A branch from recompiled code to a constant guest address.
+///
+/// Each exit is a patchable `jmp rel32`. When the block at the target address 
+/// hasn't been recompiled, the `jmp` falls through to a return to the 
+/// dispatcher. Otherwise, it can be "linked" into a branch directly into the 
+/// target block (and must be "unlinked" when the target is discarded). 
+#[derive(Clone, Copy, Debug)]
+pub struct BlockExit {
+    /// Host address of the `jmp`.
+    pub site: usize,
+    /// The target guest address.
+    pub target: u32,
+}
+impl BlockExit {
+    /// Branch directly to the recompiled code at `entrypoint`.
+    pub fn link(&self, entrypoint: *const u8) {
+        let ok = unsafe { patch::write_jmp(self.site, entrypoint as usize) };
+        assert!(ok, "Couldn't patch exit at {:016x}", self.site);
+    }
+    /// Return to the dispatcher instead.
+    pub fn unlink(&self) {
+        let ok = unsafe { patch::write_jmp(self.site, self.site + 5) };
+        assert!(ok, "Couldn't patch exit at {:016x}", self.site);
+    }
 }
 
 pub struct BasicBlock {
@@ -85,6 +123,15 @@ pub struct BasicBlock {
     pub code: ExecutableBuffer,
     /// Set of guest instructions in this block
     pub guest_ops: Vec<u32>,
+    /// Physical pages containing the guest instructions in this block
+    pub code_pages: Vec<u32>,
+    /// Branches to constant guest addresses in recompiled code
+    pub exits: Vec<BlockExit>,
+    /// Whether some guest code in this block can only be fetched in 
+    /// privileged modes
+    pub privileged: bool,
+    /// The cost of this block in cycles (see [crate::timing])
+    pub cycles: usize,
 
     _pc: ProgramCounter,
 }
@@ -101,13 +148,18 @@ impl BasicBlock {
             code: ExecutableBuffer::new(0).unwrap(),
 
             guest_ops: Vec::new(),
+            code_pages: Vec::new(),
+            exits: Vec::new(),
+            privileged: false,
+            cycles: 0,
             _pc: pc,
         }
     }
 
     fn last_opcd(&self) -> u32 { *self.guest_ops.last().unwrap() }
-    fn push(&mut self, inst: Instruction) { 
+    fn push(&mut self, mut inst: Instruction) { 
         assert!(self.link.is_none());
+        inst.guest_pc = self.read_fetch_pc();
         self.data.push(inst); 
     }
 
@@ -125,6 +177,16 @@ impl BasicBlock {
     }
 }
 
+impl Drop for BasicBlock {
+    fn drop(&mut self) {
+        // Forget about any fast memory accesses in our recompiled code 
+        // (blocks which were never recompiled don't have any)
+        if !self.code.is_empty() {
+            fastmem::unregister(self.entrypoint() as usize, self.code.len());
+        }
+    }
+}
+
 impl BasicBlock {
     pub fn disas_guest(&self) {
         assert!(!self.guest_ops.is_empty());
@@ -148,7 +210,11 @@ impl BasicBlock {
                     cur += inst.len();
                     pc += 4;
                 },
-                Err(e) => { panic!("{:?}", e) },
+                Err(e) => {
+                    println!("  {:08x} <{:?}>", pc, e);
+                    cur += 4;
+                    pc += 4;
+                },
             }
             if cur as usize >= buffer.len() { break; }
         }
@@ -176,8 +242,9 @@ impl BasicBlock {
         }
     }
 
+    /// NOTE: Blocks which only contain an exit (i.e. `svc`) have no IR 
+    /// instructions before the terminal.
     pub fn disas_ir(&self) {
-        assert!(!self.data.is_empty());
         println!("  // Intermediate Representation");
         for (idx, inst) in self.data.iter().enumerate() {
             println!("  {:08} {:08x} {}", idx, inst.guest_op, inst);
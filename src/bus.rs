@@ -0,0 +1,84 @@
+//! Interfaces for emulating the guest's physical memory map.
+//!
+//! Users describe the physical memory map by implementing [MemoryBus].
+//! Regions of RAM and ROM are backed by host memory (and mapped into fast
+//! memory), while accesses to memory-mapped I/O (or to any address which
+//! isn't covered by a region) are forwarded to the callbacks on the bus.
+
+use crate::mem::Permissions;
+
+/// The kind of a physical memory region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Memory backed by the host which can be read and written.
+    Ram,
+    /// Memory backed by the host which can only be read.
+    Rom,
+    /// Memory-mapped I/O (accesses are handled by [MemoryBus] callbacks).
+    Mmio,
+}
+
+/// A region in the guest physical memory map.
+#[derive(Clone, Debug)]
+pub struct Region {
+    /// A name for this region.
+    pub name: String,
+    /// The base physical address of this region.
+    pub base: u32,
+    /// The length of this region in bytes.
+    pub len: usize,
+    /// The kind of region.
+    pub kind: RegionKind,
+    /// Guest access permissions for this region (RAM and ROM only).
+    pub perm: Permissions,
+}
+impl Region {
+    pub fn new(name: &str, base: u32, len: usize, kind: RegionKind) -> Self {
+        assert!(base & 0xfff == 0 && len & 0xfff == 0,
+            "Region {} must be aligned to 4KiB pages", name);
+        let perm = match kind {
+            RegionKind::Rom => Permissions::RX,
+            RegionKind::Ram | RegionKind::Mmio => Permissions::RWX,
+        };
+        Region { name: name.to_string(), base, len, kind, perm }
+    }
+
+    /// Override the default permissions for this region.
+    pub fn with_perm(mut self, perm: Permissions) -> Self {
+        self.perm = perm;
+        self
+    }
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.base && ((addr - self.base) as usize) < self.len
+    }
+}
+
+/// The action taken after the guest writes to a write-protected region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteProtectAction {
+    /// Discard the write.
+    Ignore,
+    /// Discard the write and raise a data abort on the guest.
+    Abort,
+}
+
+/// A user callback for guest writes to write-protected regions (i.e. ROM),
+/// which takes the target physical address and the value being written.
+pub type WriteProtectHandler = Box<dyn FnMut(u32, u32) -> WriteProtectAction>;
+
+/// A user-defined guest physical memory map.
+///
+/// The read/write callbacks are only used for accesses which don't hit a
+/// region of RAM or ROM.
+pub trait MemoryBus {
+    /// Return the set of regions in the physical memory map.
+    fn regions(&self) -> Vec<Region>;
+
+    fn read8(&mut self, addr: u32) -> u8;
+    fn read16(&mut self, addr: u32) -> u16;
+    fn read32(&mut self, addr: u32) -> u32;
+    fn write8(&mut self, addr: u32, val: u8);
+    fn write16(&mut self, addr: u32, val: u16);
+    fn write32(&mut self, addr: u32, val: u32);
+}
+
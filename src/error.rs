@@ -0,0 +1,75 @@
+//! Errors which prevent guest code from being recompiled or executed.
+
+use std::fmt;
+use crate::lift::decode::ArmInst;
+
+/// Different kinds of [Error].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// There's no implementation of this instruction in the lifter.
+    Unimplemented(ArmInst),
+    /// Some form of an instruction isn't supported by the lifter.
+    Unsupported(&'static str),
+    /// The condition field of an instruction is invalid.
+    InvalidCond(u32),
+    /// An instruction tried to enter an invalid CPU mode.
+    InvalidMode,
+    /// The first instruction in a block can't be fetched.
+    Fetch,
+    /// The emitter can't generate code for some IR instruction (or some
+    /// combination of storage locations).
+    Codegen(String),
+    /// The register allocator ran out of host registers.
+    OutOfRegisters,
+    /// The IR for a block is malformed (i.e. a variable is used before it's
+    /// defined).
+    InvalidIr(String),
+    /// Recompiled code returned an invalid exit code to the dispatcher.
+    InvalidExitCode(usize),
+    /// Recompiled code and the IR evaluator disagree about the result of a
+    /// block (see [crate::Backend::Lockstep]).
+    Divergence(String),
+}
+
+/// An error associated with some guest instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    /// The address of the guest instruction.
+    pub pc: u32,
+    /// The guest instruction.
+    pub opcd: u32,
+    pub kind: ErrorKind,
+}
+impl Error {
+    pub fn new(pc: u32, opcd: u32, kind: ErrorKind) -> Self {
+        Error { pc, opcd, kind }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ErrorKind::*;
+        match self {
+            Unimplemented(inst) => write!(f, "unimplemented instruction {:?}", inst),
+            Unsupported(what) => write!(f, "unsupported {}", what),
+            InvalidCond(c) => write!(f, "invalid condition {:04b}", c),
+            InvalidMode => write!(f, "invalid mode"),
+            Fetch => write!(f, "instruction fetch failed"),
+            Codegen(msg) => write!(f, "codegen: {}", msg),
+            OutOfRegisters => write!(f, "out of host registers"),
+            InvalidIr(msg) => write!(f, "invalid IR: {}", msg),
+            InvalidExitCode(x) => write!(f, "invalid exit code {}", x),
+            Divergence(msg) => write!(f, "divergence: {}", msg),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}: {:08x}: {}", self.pc, self.opcd, self.kind)
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,245 @@
+//! Handling faults on accesses to "fast memory."
+//!
+//! Recompiled code accesses guest memory directly (relative to the base of
+//! the fast memory arena), without checking whether or not the target page
+//! is actually backed by host memory. Pages which aren't backed by host
+//! memory (i.e. memory-mapped I/O, or unmapped addresses) are reserved with
+//! `PROT_NONE`, and accesses to them cause a `SIGSEGV` on the host. Pages
+//! without guest write permission are mapped read-only, so stores to them
+//! also fault.
+//!
+//! When emitting a fast memory access, the emitter also emits an out-of-line
+//! "slow path" for the access (which calls into a runtime helper), and
+//! registers the location of both with [register].
+//!
+//! When our signal handler catches a fault inside one of these sites, it 
+//! decodes the faulting host instruction to check that it's actually an 
+//! access to fast memory. The site is "backpatched" into a jump to the slow 
+//! path, and execution resumes at the start of the site (so a site may 
+//! contain other instructions before the faulting access, as long as they 
+//! don't clobber any live values), which emulates the access on the slow 
+//! path. After this, the access always goes through the slow path without 
+//! faulting. Faults anywhere else are passed along to the previously-installed
+//! handler.
+//!
+//! The handler can't take locks or allocate, so sites are kept in a 
+//! preallocated table which the handler only reads. Sites are only added and
+//! removed outside of the handler (see [register] and [unregister]).
+
+use std::collections::BTreeMap;
+use std::sync::{ Mutex, Once };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+extern crate libc;
+use libc::{
+    c_int, c_void, siginfo_t, ucontext_t, sigaction, sigemptyset,
+    SA_SIGINFO, SIGSEGV, SIG_DFL, SIG_IGN, REG_RIP,
+};
+
+use crate::patch;
+use crate::runtime::RuntimeContext;
+
+/// A fast memory access in recompiled code.
+#[derive(Clone, Copy, Debug)]
+pub struct FastmemSite {
+    /// Host address of the access.
+    pub start: usize,
+    /// Host address after the end of the access (including any padding).
+    pub end: usize,
+    /// Host address of the slow path for this access.
+    pub stub: usize,
+}
+impl FastmemSite {
+    /// The minimum size of a site (the size of a `jmp rel32`).
+    pub const MIN_SIZE: usize = 5;
+}
+
+/// An entry in the site table (an empty entry has a null `start`).
+struct Slot {
+    start: AtomicUsize,
+    end: AtomicUsize,
+    stub: AtomicUsize,
+}
+impl Slot {
+    const fn empty() -> Slot {
+        Slot { 
+            start: AtomicUsize::new(0), 
+            end: AtomicUsize::new(0), 
+            stub: AtomicUsize::new(0),
+        }
+    }
+
+    /// Read the site in this entry, if the entry isn't empty and isn't being
+    /// changed while we read it.
+    fn load(&self) -> Option<FastmemSite> {
+        let start = self.start.load(Ordering::Acquire);
+        if start == 0 {
+            return None;
+        }
+        let end = self.end.load(Ordering::Acquire);
+        let stub = self.stub.load(Ordering::Acquire);
+        if self.start.load(Ordering::Acquire) != start {
+            return None;
+        }
+        Some(FastmemSite { start, end, stub })
+    }
+}
+
+/// The maximum number of registered sites.
+const MAX_SITES: usize = 0x10000;
+
+/// The table of all patchable fast memory accesses, which is read by the 
+/// signal handler.
+static TABLE: [Slot; MAX_SITES] = [const { Slot::empty() }; MAX_SITES];
+
+/// The number of entries in [TABLE] which have ever been used.
+static TABLE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Bookkeeping for [TABLE], which is only used outside of the signal handler.
+struct TableIndex {
+    /// The entry for each site (by host address).
+    entries: BTreeMap<usize, usize>,
+    /// Entries which can be reused.
+    free: Vec<usize>,
+}
+static INDEX: Mutex<TableIndex> = Mutex::new(TableIndex { 
+    entries: BTreeMap::new(), free: Vec::new() 
+});
+
+/// The signal handler which was installed before ours.
+static mut PREV_ACTION: Option<sigaction> = None;
+
+/// Register a fast memory access, returning false (without registering it) 
+/// if the table is full.
+pub fn register(site: FastmemSite) -> bool {
+    assert!(site.end - site.start >= FastmemSite::MIN_SIZE);
+    let mut index = INDEX.lock().unwrap();
+    let idx = match index.free.pop() {
+        Some(idx) => idx,
+        None => {
+            let idx = TABLE_LEN.load(Ordering::Relaxed);
+            if idx >= MAX_SITES {
+                return false;
+            }
+            TABLE_LEN.store(idx + 1, Ordering::Release);
+            idx
+        },
+    };
+    // The entry is only visible to the handler after `start` is written
+    let slot = &TABLE[idx];
+    slot.end.store(site.end, Ordering::Release);
+    slot.stub.store(site.stub, Ordering::Release);
+    slot.start.store(site.start, Ordering::Release);
+    index.entries.insert(site.start, idx);
+    true
+}
+
+/// Unregister all fast memory accesses in the range `[start, start + len)`,
+/// (i.e. when recompiled code is being discarded).
+pub fn unregister(start: usize, len: usize) {
+    let mut index = INDEX.lock().unwrap();
+    let doomed: Vec<usize> = index.entries.range(start..start + len)
+        .map(|(k, _)| *k).collect();
+    for k in doomed.iter() {
+        let idx = index.entries.remove(k).unwrap();
+        TABLE[idx].start.store(0, Ordering::Release);
+        index.free.push(idx);
+    }
+}
+
+/// Find the site containing the host address `rip`.
+///
+/// This is called from the signal handler, so it must not lock or allocate.
+fn lookup(rip: usize) -> Option<FastmemSite> {
+    let len = TABLE_LEN.load(Ordering::Acquire);
+    TABLE[..len].iter().filter_map(|slot| slot.load())
+        .find(|site| site.start <= rip && rip < site.end)
+}
+
+/// Returns true if the host instruction at `rip` accesses fast memory (the
+/// base of fast memory is always in [RuntimeContext::CTX_FASTMEM]).
+unsafe fn is_fastmem_access(rip: usize) -> bool {
+    use yaxpeax_arch::Decoder;
+    use yaxpeax_x86::long_mode::{ InstDecoder, Operand, RegSpec };
+
+    // NOTE: x86 instructions are at most 15 bytes long
+    let bytes = std::slice::from_raw_parts(rip as *const u8, 15);
+    let inst = match InstDecoder::default().decode(bytes.iter().cloned()) {
+        Ok(inst) => inst,
+        Err(_) => return false,
+    };
+    let base = RegSpec::q(RuntimeContext::CTX_FASTMEM as u8);
+    (0..inst.operand_count()).any(|i| match inst.operand(i) {
+        Operand::RegIndexBase(b, _) |
+        Operand::RegIndexBaseDisp(b, _, _) |
+        Operand::RegIndexBaseScale(b, _, _) |
+        Operand::RegIndexBaseScaleDisp(b, _, _, _) => b == base,
+        _ => false,
+    })
+}
+
+/// Install the fault handler (this only happens once).
+pub fn install_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        let mut action: sigaction = std::mem::zeroed();
+        let mut prev: sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_segv as *const () as usize;
+        action.sa_flags = SA_SIGINFO;
+        sigemptyset(&mut action.sa_mask);
+        if sigaction(SIGSEGV, &action, &mut prev) != 0 {
+            panic!("Couldn't install SIGSEGV handler");
+        }
+        PREV_ACTION = Some(prev);
+    });
+}
+
+/// Rewrite a site into a jump to its slow path, returning false if the site
+/// couldn't be patched.
+unsafe fn backpatch(site: &FastmemSite) -> bool {
+    // Fill the rest of the site with nops before writing the jump
+    let nops = [0x90u8; 64];
+    let len = site.end - site.start - 5;
+    len <= nops.len()
+        && patch::write_code(site.start + 5, &nops[..len])
+        && patch::write_jmp(site.start, site.stub)
+}
+
+/// Pass a fault along to the previously-installed handler.
+unsafe fn chain(sig: c_int, info: *mut siginfo_t, uctx: *mut c_void) {
+    let prev = match PREV_ACTION {
+        Some(prev) => prev,
+        None => std::mem::zeroed(),
+    };
+    if prev.sa_sigaction == SIG_DFL || prev.sa_sigaction == SIG_IGN {
+        // Restore the default action; the fault will occur again after we
+        // return from this handler.
+        sigaction(SIGSEGV, &prev, std::ptr::null_mut());
+    } else if (prev.sa_flags & SA_SIGINFO) != 0 {
+        let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+            std::mem::transmute(prev.sa_sigaction);
+        f(sig, info, uctx);
+    } else {
+        let f: extern "C" fn(c_int) = std::mem::transmute(prev.sa_sigaction);
+        f(sig);
+    }
+}
+
+extern "C" fn handle_segv(sig: c_int, info: *mut siginfo_t, uctx: *mut c_void) {
+    unsafe {
+        let ctx = &mut *(uctx as *mut ucontext_t);
+        let rip = ctx.uc_mcontext.gregs[REG_RIP as usize] as usize;
+
+        // After the site is patched, it can't fault again (and it's only
+        // removed from the table when the recompiled code is discarded).
+        // If patching fails, the fault is handled like any other (we can't 
+        // unwind out of a signal handler).
+        match lookup(rip) {
+            Some(site) if is_fastmem_access(rip) && backpatch(&site) => {
+                ctx.uc_mcontext.gregs[REG_RIP as usize] = site.start as i64;
+            },
+            _ => chain(sig, info, uctx),
+        }
+    }
+}
+
@@ -1,4 +1,11 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
 use crate::mem::*;
+use crate::mmu::{ Mmu, Access, Fault, FaultStatus };
+use crate::bus::{ 
+    MemoryBus, Region, RegionKind, WriteProtectAction, WriteProtectHandler 
+};
 
 pub type RegIdx = u32;
 
@@ -19,14 +26,24 @@ pub enum CpuMode {
 }
 impl CpuMode {
     pub fn is_privileged(self) -> bool { self != CpuMode::Usr }
+
+    /// Index of the register bank used in this mode.
+    fn bank(self) -> usize {
+        use CpuMode::*;
+        match self {
+            Usr | Sys => 0, Fiq => 1, Irq => 2, Svc => 3, Abt => 4, Und => 5,
+        }
+    }
 }
-impl From<u32> for CpuMode {
-    fn from(x: u32) -> Self {
+impl TryFrom<u32> for CpuMode {
+    type Error = u32;
+    fn try_from(x: u32) -> Result<Self, u32> {
         use CpuMode::*;
         match x {
-            0b10000 => Usr, 0b10001 => Fiq, 0b10010 => Irq, 0b10011 => Svc,
-            0b10111 => Abt, 0b11011 => Und, 0b11111 => Sys,
-            _ => panic!("Invalid mode bits {:08x}", x),
+            0b10000 => Ok(Usr), 0b10001 => Ok(Fiq), 0b10010 => Ok(Irq), 
+            0b10011 => Ok(Svc), 0b10111 => Ok(Abt), 0b11011 => Ok(Und), 
+            0b11111 => Ok(Sys),
+            _ => Err(x),
         }
     }
 }
@@ -40,7 +57,18 @@ impl Psr {
         self.0 = (self.0 & !(1 << idx)) | (val as u32) << idx
     }
 
-    pub fn mode(&self) -> CpuMode { CpuMode::from(self.0 & 0x1f) }
+    /// The CPU mode, or the mode bits if they're invalid.
+    ///
+    /// NOTE: Writes to the CPSR with an invalid mode are rejected (see
+    /// [crate::runtime::RuntimeContext::write_cpsr]), so this should only
+    /// fail if the user wrote an invalid mode directly.
+    pub fn mode(&self) -> Result<CpuMode, u32> { 
+        CpuMode::try_from(self.0 & 0x1f)
+    }
+    /// Returns true in any mode other than user mode.
+    pub fn is_privileged(&self) -> bool { 
+        (self.0 & 0x1f) != CpuMode::Usr as u32 
+    }
     pub fn thumb(&self) -> bool { (self.0 & 0x0000_0020) != 0 }
     pub fn fiq_disable(&self) -> bool { (self.0 & 0x0000_0040) != 0 }
     pub fn irq_disable(&self) -> bool { (self.0 & 0x0000_0080) != 0 }
@@ -77,10 +105,11 @@ pub enum Cond {
     GT = 0b1100, LE = 0b1101,
     AL = 0b1110,
 }
-impl From<u32> for Cond {
-    fn from(x: u32) -> Self {
+impl TryFrom<u32> for Cond {
+    type Error = u32;
+    fn try_from(x: u32) -> Result<Self, u32> {
         use Cond::*;
-        match x {
+        Ok(match x {
             0b0000 => EQ, 0b0001 => NE,
             0b0010 => CS, 0b0011 => CC,
             0b0100 => MI, 0b0101 => PL,
@@ -89,34 +118,515 @@ impl From<u32> for Cond {
             0b1010 => GE, 0b1011 => LT,
             0b1100 => GT, 0b1101 => LE,
             0b1110 => AL,
-            _ => panic!("Invalid condition bits {:08x}", x),
+            _ => return Err(x),
+        })
+    }
+}
+impl Cond {
+    /// Returns true if an instruction with this condition would execute.
+    pub fn passes(self, cpsr: Psr) -> bool {
+        use Cond::*;
+        match self {
+            EQ => cpsr.z(), NE => !cpsr.z(),
+            CS => cpsr.c(), CC => !cpsr.c(),
+            MI => cpsr.n(), PL => !cpsr.n(),
+            VS => cpsr.v(), VC => !cpsr.v(),
+            HI => cpsr.c() && !cpsr.z(), LS => !cpsr.c() || cpsr.z(),
+            GE => cpsr.n() == cpsr.v(), LT => cpsr.n() != cpsr.v(),
+            GT => !cpsr.z() && cpsr.n() == cpsr.v(), 
+            LE => cpsr.z() || cpsr.n() != cpsr.v(),
+            AL => true,
+        }
+    }
+}
+
+/// Types of exceptions (the discriminant is the offset into the vector table).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionType {
+    Reset = 0x00, Undefined = 0x04, Svc = 0x08, PrefetchAbort = 0x0c,
+    DataAbort = 0x10, Irq = 0x18, Fiq = 0x1c,
+}
+impl ExceptionType {
+    /// The mode entered when taking this exception.
+    pub fn mode(self) -> CpuMode {
+        use ExceptionType::*;
+        match self {
+            Reset | Svc => CpuMode::Svc,
+            Undefined => CpuMode::Und,
+            PrefetchAbort | DataAbort => CpuMode::Abt,
+            Irq => CpuMode::Irq,
+            Fiq => CpuMode::Fiq,
         }
     }
 }
 
+/// A region of guest physical memory backed by host memory.
+struct HostRegion {
+    kind: RegionKind,
+    /// The base address of the region which owns the backing memory
+    /// (which is different from `mem.addr` for mirrors).
+    origin: u32,
+    mem: MemRegion,
+}
+impl HostRegion {
+    fn offset(&self, addr: u32) -> Option<usize> {
+        let off = addr.wrapping_sub(self.mem.addr) as usize;
+        if off < self.mem.len { Some(off) } else { None }
+    }
+}
+
+/// Guest physical memory, and the MMU used to translate guest virtual 
+/// addresses.
 pub struct GuestMmu { 
-    mem: MemRegion 
+    /// Regions of RAM and ROM.
+    regions: Vec<HostRegion>,
+    /// The fast memory arena containing all regions of RAM and ROM.
+    /// (This must be dropped after all of the regions.)
+    arena: Arena,
+    /// User-defined memory bus, which handles all other physical accesses.
+    bus: Option<Box<dyn MemoryBus>>,
+    /// User callback for writes to write-protected regions.
+    write_protect_handler: Option<WriteProtectHandler>,
+    /// The physical address of the last access to unmapped memory.
+    unmapped: Option<u32>,
+
+    /// Pages containing recompiled guest code (by canonical address).
+    code_pages: HashSet<u32>,
+    /// Pages containing recompiled guest code which have been written.
+    dirty_pages: Vec<u32>,
+
+    /// Byte order used for all guest memory accesses.
+    pub endianness: Endianness,
+
+    /// ARMv5 address translation (disabled by default).
+    pub vmsa: Mmu,
 }
 impl GuestMmu {
+    /// Create a default memory map (1MiB of RAM at physical address zero).
     pub fn new() -> Self {
+        let mut mmu = GuestMmu::empty(None);
+        mmu.map(&Region::new("MEM", 0x0000_0000, 0x0010_0000, RegionKind::Ram));
+        mmu
+    }
+
+    /// Create a memory map described by a user-defined bus.
+    pub fn with_bus(bus: Box<dyn MemoryBus>) -> Self {
+        let regions = bus.regions();
+        let mut mmu = GuestMmu::empty(Some(bus));
+        for region in regions.iter() {
+            mmu.map(region);
+        }
+        mmu
+    }
+
+    fn empty(bus: Option<Box<dyn MemoryBus>>) -> Self {
         GuestMmu {
-            mem: MemRegion::new("MEM", 0x0000_0000, 0x0010_0000),
+            regions: Vec::new(),
+            arena: Arena::new(),
+            bus,
+            write_protect_handler: None,
+            unmapped: None,
+            code_pages: HashSet::new(),
+            dirty_pages: Vec::new(),
+            endianness: Endianness::Big,
+            vmsa: Mmu::new(),
+        }
+    }
+
+    /// Returns true if the physical range `[addr, addr + len)` overlaps an
+    /// existing region of RAM or ROM.
+    fn overlaps(&self, addr: u32, len: usize) -> bool {
+        let end = addr as usize + len;
+        self.regions.iter().any(|r| {
+            (addr as usize) < r.mem.addr as usize + r.mem.len 
+                && (r.mem.addr as usize) < end
+        })
+    }
+
+    /// Add a region to the physical memory map.
+    fn map(&mut self, region: &Region) {
+        if region.kind == RegionKind::Mmio {
+            return;
+        }
+        // Mapping the region would clobber any existing region
+        assert!(!self.overlaps(region.base, region.len), 
+            "Region {} overlaps an existing region", region.name);
+        self.regions.push(HostRegion {
+            kind: region.kind,
+            origin: region.base,
+            mem: MemRegion::new(&self.arena, &region.name, region.base, 
+                region.len, region.perm),
+        });
+    }
+
+    /// Set the callback used when the guest writes to a write-protected 
+    /// region. By default, these writes are ignored.
+    pub fn set_write_protect_handler(&mut self, handler: WriteProtectHandler) {
+        self.write_protect_handler = Some(handler);
+    }
+
+    /// Handle a write to a write-protected region.
+    fn write_protect(&mut self, addr: u32, val: u32) -> Result<(), Fault> {
+        let action = match self.write_protect_handler {
+            Some(ref mut handler) => handler(addr, val),
+            None => WriteProtectAction::Ignore,
+        };
+        match action {
+            WriteProtectAction::Ignore => Ok(()),
+            WriteProtectAction::Abort => Err(Fault {
+                status: FaultStatus::External, domain: 0, addr, 
+                access: Access::Write,
+            }),
+        }
+    }
+
+    /// Map an existing region of RAM or ROM (with base physical address 
+    /// `base`) at another physical address `addr`.
+    ///
+    /// Both addresses share the same backing memory in the fast memory arena,
+    /// so a write through either address is visible through both.
+    pub fn add_mirror(&mut self, base: u32, addr: u32) {
+        assert!(addr & 0xfff == 0, "Mirror {:08x} must be page-aligned", addr);
+        let idx = match self.regions.iter().position(|r| r.mem.addr == base) {
+            Some(idx) => idx,
+            None => panic!("No RAM/ROM region with base {:08x}", base),
+        };
+
+        // Mapping the mirror would clobber any existing region
+        let len = self.regions[idx].mem.len;
+        assert!(!self.overlaps(addr, len), 
+            "Mirror {:08x} overlaps an existing region", addr);
+
+        let kind = self.regions[idx].kind;
+        let origin = self.regions[idx].origin;
+        let mem = self.regions[idx].mem.mirror(&self.arena, addr);
+        self.regions.push(HostRegion { kind, origin, mem });
+
+        // The mirror must also be write-protected on pages which already 
+        // contain recompiled code, or writes through it would go unnoticed
+        let tracked: Vec<u32> = self.code_pages.iter().cloned()
+            .filter(|page| (page.wrapping_sub(origin) as usize) < len)
+            .collect();
+        for page in tracked {
+            self.protect_page(page, false);
+        }
+    }
+
+    /// Find the region of host-backed memory containing some address.
+    fn find(&mut self, addr: u32) -> Option<(&mut HostRegion, usize)> {
+        self.regions.iter_mut().find_map(|r| r.offset(addr).map(|off| (r, off)))
+    }
+
+    /// Return the bus, which handles accesses to an address which isn't
+    /// backed by host memory. If there's no bus, the access is recorded as
+    /// unmapped (reads return zero, and writes are ignored).
+    fn bus(&mut self, addr: u32) -> Option<&mut (dyn MemoryBus + 'static)> {
+        if self.bus.is_none() {
+            self.unmapped = Some(addr);
         }
+        self.bus.as_deref_mut()
     }
+
+    /// The physical address of the last access to unmapped memory.
+    pub fn unmapped(&self) -> Option<u32> { self.unmapped }
+    /// Take the physical address of the last access to unmapped memory.
+    pub fn take_unmapped(&mut self) -> Option<u32> { self.unmapped.take() }
+
+    /// The base address of fast memory on the host.
+    pub fn fastmem_base(&self) -> usize { self.arena.base }
+
+    /// Write a buffer into RAM or ROM (i.e. when loading a program).
     pub fn write_buf(&mut self, addr: u32, buf: &[u8]) {
-        self.mem.write_buf(addr, buf);
+        let end = addr as usize + buf.len();
+        for page in ((addr as usize & !0xfff)..end).step_by(0x1000) {
+            if let Some(page) = self.canonical_page(page as u32) {
+                self.mark_dirty(page);
+            }
+        }
+        match self.find(addr) {
+            Some((r, off)) => r.mem.write_buf(off as u32, buf),
+            None => panic!("No RAM/ROM region at {:08x}", addr),
+        }
+    }
+}
+impl Default for GuestMmu {
+    fn default() -> Self { GuestMmu::new() }
+}
+
+/// A copy of the contents of RAM and ROM (see [GuestMmu::snapshot]).
+pub struct MemorySnapshot {
+    /// The base physical address and contents of each region which owns 
+    /// its backing memory (mirrors are omitted).
+    regions: Vec<(u32, Vec<u8>)>,
+}
+
+/// Comparing the contents of guest memory before and after running some code
+/// (see [crate::Backend::Lockstep]).
+impl GuestMmu {
+    /// Copy the contents of all RAM and ROM.
+    pub fn snapshot(&self) -> MemorySnapshot {
+        MemorySnapshot {
+            regions: self.regions.iter().filter(|r| r.mem.addr == r.origin)
+                .map(|r| (r.origin, r.mem.ptr.to_vec())).collect(),
+        }
+    }
+
+    /// Find the first word of RAM or ROM which differs from `snapshot`, 
+    /// returning its physical address, the value in the snapshot, and the 
+    /// current value.
+    pub fn compare(&self, snapshot: &MemorySnapshot) -> Option<(u32, u32, u32)> {
+        let e = self.endianness;
+        let word = |buf: &[u8], off: usize| {
+            let bytes = [buf[off], buf[off + 1], buf[off + 2], buf[off + 3]];
+            match e {
+                Endianness::Little => u32::from_le_bytes(bytes),
+                Endianness::Big => u32::from_be_bytes(bytes),
+            }
+        };
+        for (base, old) in snapshot.regions.iter() {
+            let r = match self.regions.iter().find(|r| r.mem.addr == *base) {
+                Some(r) => r,
+                None => continue,
+            };
+            if r.mem.ptr[..] == old[..] {
+                continue;
+            }
+            let off = (0..old.len() & !3).step_by(4)
+                .find(|off| r.mem.ptr[*off..*off + 4] != old[*off..*off + 4]);
+            if let Some(off) = off {
+                return Some((base + off as u32, word(old, off), 
+                    word(r.mem.ptr, off)));
+            }
+        }
+        None
     }
-    pub fn read32(&self, addr: u32) -> u32 {
-        self.mem.read32(addr as usize)
+}
+
+/// Tracking writes to pages containing recompiled code.
+///
+/// Pages are identified by their "canonical" physical address (the address 
+/// in the region which owns the backing memory), so that a write through any 
+/// mirror of a page is noticed.
+impl GuestMmu {
+    /// Return the canonical address of the RAM/ROM page containing `addr`.
+    pub fn canonical_page(&mut self, addr: u32) -> Option<u32> {
+        self.find(addr).map(|(r, off)| r.origin + (off as u32 & !0xfff))
+    }
+
+    /// Change host write permissions on a page (and all of its mirrors).
+    fn protect_page(&mut self, page: u32, writable: bool) {
+        for r in self.regions.iter_mut() {
+            let off = page.wrapping_sub(r.origin) as usize;
+            if off < r.mem.len {
+                r.mem.protect(off, 0x1000, writable);
+            }
+        }
+    }
+
+    /// Start tracking writes to the page containing the physical address 
+    /// `addr` (which contains code that is about to be recompiled). 
+    ///
+    /// Returns the canonical address of the page, or `None` if the page 
+    /// isn't backed by host memory.
+    pub fn track_code(&mut self, addr: u32) -> Option<u32> {
+        let page = self.canonical_page(addr)?;
+        if self.code_pages.insert(page) {
+            self.protect_page(page, false);
+        }
+        Some(page)
+    }
+
+    /// Stop tracking writes to all pages (i.e. after discarding all 
+    /// recompiled code).
+    pub fn untrack_all(&mut self) {
+        for page in std::mem::take(&mut self.code_pages) {
+            self.protect_page(page, true);
+        }
+        self.dirty_pages.clear();
+    }
+
+    /// Record a guest write to the physical address `addr`.
+    fn note_write(&mut self, addr: u32) {
+        if self.code_pages.is_empty() {
+            return;
+        }
+        let page = match self.find(addr) {
+            Some((r, off)) if r.mem.perm.write => {
+                r.origin + (off as u32 & !0xfff)
+            },
+            _ => return,
+        };
+        self.mark_dirty(page);
+    }
+
+    /// If a page contains recompiled code, mark it as dirty and stop 
+    /// tracking it.
+    fn mark_dirty(&mut self, page: u32) {
+        if self.code_pages.remove(&page) {
+            self.protect_page(page, true);
+            self.dirty_pages.push(page);
+        }
+    }
+
+    /// Returns true if any pages with recompiled code have been written.
+    pub fn has_dirty_pages(&self) -> bool { !self.dirty_pages.is_empty() }
+
+    /// Take the set of pages with recompiled code which have been written.
+    pub fn take_dirty_pages(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.dirty_pages)
+    }
+}
+
+/// Accesses using guest physical addresses.
+impl GuestMmu {
+    pub fn read8(&mut self, addr: u32) -> u8 {
+        match self.find(addr) {
+            Some((r, off)) => r.mem.read8(off),
+            None => self.bus(addr).map_or(0, |bus| bus.read8(addr)),
+        }
+    }
+    pub fn read16(&mut self, addr: u32) -> u16 {
+        let e = self.endianness;
+        match self.find(addr) {
+            Some((r, off)) => r.mem.read16(off, e),
+            None => self.bus(addr).map_or(0, |bus| bus.read16(addr)),
+        }
+    }
+    pub fn read32(&mut self, addr: u32) -> u32 {
+        let e = self.endianness;
+        match self.find(addr) {
+            Some((r, off)) => r.mem.read32(off, e),
+            None => self.bus(addr).map_or(0, |bus| bus.read32(addr)),
+        }
+    }
+    pub fn write8(&mut self, addr: u32, val: u8) -> Result<(), Fault> {
+        self.note_write(addr);
+        match self.find(addr) {
+            Some((r, off)) if r.mem.perm.write => r.mem.write8(off, val),
+            Some(_) => return self.write_protect(addr, val as u32),
+            None => if let Some(bus) = self.bus(addr) { bus.write8(addr, val) },
+        }
+        Ok(())
+    }
+    pub fn write16(&mut self, addr: u32, val: u16) -> Result<(), Fault> {
+        self.note_write(addr);
+        let e = self.endianness;
+        match self.find(addr) {
+            Some((r, off)) if r.mem.perm.write => r.mem.write16(off, val, e),
+            Some(_) => return self.write_protect(addr, val as u32),
+            None => if let Some(bus) = self.bus(addr) { bus.write16(addr, val) },
+        }
+        Ok(())
+    }
+    pub fn write32(&mut self, addr: u32, val: u32) -> Result<(), Fault> {
+        self.note_write(addr);
+        let e = self.endianness;
+        match self.find(addr) {
+            Some((r, off)) if r.mem.perm.write => r.mem.write32(off, val, e),
+            Some(_) => return self.write_protect(addr, val),
+            None => if let Some(bus) = self.bus(addr) { bus.write32(addr, val) },
+        }
+        Ok(())
     }
 }
 
+/// Accesses using guest virtual addresses.
+impl GuestMmu {
+    /// Translate a virtual address into a physical address.
+    pub fn translate(&mut self, va: u32, access: Access, privileged: bool) 
+        -> Result<u32, Fault> 
+    {
+        // Translation table walks only read from RAM/ROM (walks from 
+        // anywhere else cause an external abort)
+        let (regions, e) = (&self.regions, self.endianness);
+        self.vmsa.translate(va, access, privileged, |pa| {
+            regions.iter().find_map(|r| r.offset(pa).map(|off| r.mem.read32(off, e)))
+        })
+    }
+    pub fn fetch32(&mut self, va: u32, privileged: bool) -> Result<u32, Fault> {
+        let pa = self.translate(va, Access::Fetch, privileged)?;
+        if let Some((r, _)) = self.find(pa) {
+            if !r.mem.perm.exec {
+                return Err(Fault { 
+                    status: FaultStatus::External, domain: 0, addr: va, 
+                    access: Access::Fetch 
+                });
+            }
+        }
+        Ok(self.read32(pa))
+    }
+    /// Read a word from RAM or ROM without any side effects, returning 
+    /// `None` if the access would fault, or if the address isn't backed by 
+    /// host memory (i.e. memory-mapped I/O).
+    pub fn peek32(&mut self, va: u32, privileged: bool) -> Option<u32> {
+        let pa = self.translate(va, Access::Read, privileged).ok()?;
+        let e = self.endianness;
+        self.find(pa).map(|(r, off)| r.mem.read32(off, e))
+    }
+    pub fn load32(&mut self, va: u32, privileged: bool) -> Result<u32, Fault> {
+        let pa = self.translate(va, Access::Read, privileged)?;
+        Ok(self.read32(pa))
+    }
+    pub fn store32(&mut self, va: u32, val: u32, privileged: bool) 
+        -> Result<(), Fault> 
+    {
+        let pa = self.translate(va, Access::Write, privileged)?;
+        self.write32(pa, val).map_err(|fault| Fault { addr: va, ..fault })
+    }
+    pub fn load16(&mut self, va: u32, privileged: bool) -> Result<u16, Fault> {
+        let pa = self.translate(va, Access::Read, privileged)?;
+        Ok(self.read16(pa))
+    }
+    pub fn store16(&mut self, va: u32, val: u16, privileged: bool) 
+        -> Result<(), Fault> 
+    {
+        let pa = self.translate(va, Access::Write, privileged)?;
+        self.write16(pa, val).map_err(|fault| Fault { addr: va, ..fault })
+    }
+    pub fn load8(&mut self, va: u32, privileged: bool) -> Result<u8, Fault> {
+        let pa = self.translate(va, Access::Read, privileged)?;
+        Ok(self.read8(pa))
+    }
+    pub fn store8(&mut self, va: u32, val: u8, privileged: bool) 
+        -> Result<(), Fault> 
+    {
+        let pa = self.translate(va, Access::Write, privileged)?;
+        self.write8(pa, val).map_err(|fault| Fault { addr: va, ..fault })
+    }
+}
+
+/// Registers which are banked between different CPU modes.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct RegisterBank {
+    /// Inactive copies of r13 and r14 for each register bank.
+    sp_lr: [[u32; 2]; 6],
+    /// Inactive copies of r8-r12 for all modes other than FIQ.
+    hi_usr: [u32; 5],
+    /// Inactive copies of r8-r12 for FIQ mode.
+    hi_fiq: [u32; 5],
+    /// Saved program status registers for each register bank.
+    spsr: [Psr; 6],
+}
+impl RegisterBank {
+    pub fn new() -> Self {
+        RegisterBank { 
+            sp_lr: [[0; 2]; 6], hi_usr: [0; 5], hi_fiq: [0; 5], 
+            spsr: [Psr(0); 6],
+        }
+    }
+}
+impl Default for RegisterBank {
+    fn default() -> Self { RegisterBank::new() }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct GuestState { 
     pub reg: [u32; 15],
     pub pc: ProgramCounter, 
     pub cpsr: Psr,
+    pub bank: RegisterBank,
 }
 impl GuestState {
     pub fn new(pc: u32, cpsr: u32) -> Self {
@@ -124,7 +634,56 @@ impl GuestState {
             reg: [0; 15], 
             pc: ProgramCounter(pc), 
             cpsr: Psr(cpsr),
+            bank: RegisterBank::new(),
+        }
+    }
+
+    /// Return the SPSR for the current mode.
+    pub fn spsr(&self) -> Psr { self.bank.spsr[self.bank_index()] }
+    /// Write the SPSR for the current mode.
+    pub fn set_spsr(&mut self, val: Psr) { 
+        let bank = self.bank_index();
+        self.bank.spsr[bank] = val; 
+    }
+
+    /// Index of the register bank used in the current mode (invalid modes
+    /// use the same registers as user mode).
+    fn bank_index(&self) -> usize { self.cpsr.mode().map_or(0, CpuMode::bank) }
+
+    /// Change the CPU mode, swapping out banked registers.
+    pub fn switch_mode(&mut self, new_mode: CpuMode) {
+        let old_mode = self.cpsr.mode();
+        let (old, new) = (self.bank_index(), new_mode.bank());
+        if old != new {
+            self.bank.sp_lr[old] = [self.reg[13], self.reg[14]];
+            if old_mode == Ok(CpuMode::Fiq) {
+                self.bank.hi_fiq.copy_from_slice(&self.reg[8..13]);
+                self.reg[8..13].copy_from_slice(&self.bank.hi_usr);
+            } else if new_mode == CpuMode::Fiq {
+                self.bank.hi_usr.copy_from_slice(&self.reg[8..13]);
+                self.reg[8..13].copy_from_slice(&self.bank.hi_fiq);
+            }
+            self.reg[13] = self.bank.sp_lr[new][0];
+            self.reg[14] = self.bank.sp_lr[new][1];
+        }
+        self.cpsr.set_mode(new_mode);
+    }
+
+    /// Take an exception, where `lr` is the value of the link register in
+    /// the new mode, and `vector_base` is the base of the vector table.
+    pub fn enter_exception(&mut self, kind: ExceptionType, lr: u32, 
+        vector_base: u32) 
+    {
+        let old_cpsr = self.cpsr;
+        self.switch_mode(kind.mode());
+        self.set_spsr(old_cpsr);
+        self.reg[14] = lr;
+        self.cpsr.set_thumb(false);
+        self.cpsr.set_irq_disable(true);
+        if kind == ExceptionType::Reset || kind == ExceptionType::Fiq {
+            self.cpsr.set_fiq_disable(true);
         }
+        self.pc = ProgramCounter(vector_base.wrapping_add(kind as u32));
     }
 
     pub fn dump(&self) {
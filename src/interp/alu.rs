@@ -0,0 +1,66 @@
+//! Barrel shifter and ALU operations.
+//!
+//! All of these take the input carry flag, and return a result along with
+//! the output carry (and overflow) flags.
+
+use crate::lift::alu::ShiftType;
+
+/// Rotate an 8-bit immediate (the `imm12` field of a data-processing
+/// instruction).
+pub fn rot_imm(imm12: u32, c: bool) -> (u32, bool) {
+    let (simm, imm8) = ((imm12 & 0xf00) >> 8, imm12 & 0xff);
+    let val = imm8.rotate_right(simm * 2);
+    if simm == 0 { (val, c) } else { (val, (val & 0x8000_0000) != 0) }
+}
+
+/// Shift by an immediate (where a shift of zero encodes `lsr #32`,
+/// `asr #32`, or `rrx`).
+pub fn shift_imm(x: u32, stype: u32, imm5: u32, c: bool) -> (u32, bool) {
+    match (ShiftType::from(stype), imm5) {
+        (ShiftType::Lsl, 0) => (x, c),
+        (ShiftType::Lsl, n) => shift_reg(x, stype, n, c),
+        (ShiftType::Lsr, 0) | (ShiftType::Asr, 0) => shift_reg(x, stype, 32, c),
+        (ShiftType::Ror, 0) => (((c as u32) << 31) | (x >> 1), (x & 1) != 0),
+        (_, n) => shift_reg(x, stype, n, c),
+    }
+}
+
+/// Shift by the bottom byte of a register.
+pub fn shift_reg(x: u32, stype: u32, amount: u32, c: bool) -> (u32, bool) {
+    let n = amount & 0xff;
+    if n == 0 {
+        return (x, c);
+    }
+    match ShiftType::from(stype) {
+        ShiftType::Lsl => match n {
+            1..=31 => (x << n, ((x >> (32 - n)) & 1) != 0),
+            32 => (0, (x & 1) != 0),
+            _ => (0, false),
+        },
+        ShiftType::Lsr => match n {
+            1..=31 => (x >> n, ((x >> (n - 1)) & 1) != 0),
+            32 => (0, (x & 0x8000_0000) != 0),
+            _ => (0, false),
+        },
+        ShiftType::Asr => match n {
+            1..=31 => (((x as i32) >> n) as u32, ((x >> (n - 1)) & 1) != 0),
+            _ => {
+                let sign = (x & 0x8000_0000) != 0;
+                (if sign { 0xffff_ffff } else { 0 }, sign)
+            },
+        },
+        ShiftType::Ror => match n & 0x1f {
+            0 => (x, (x & 0x8000_0000) != 0),
+            r => (x.rotate_right(r), ((x >> (r - 1)) & 1) != 0),
+        },
+    }
+}
+
+/// Compute `x + y + carry`, returning the carry and overflow flags.
+pub fn add_with_carry(x: u32, y: u32, carry: bool) -> (u32, bool, bool) {
+    let wide = x as u64 + y as u64 + carry as u64;
+    let res = wide as u32;
+    let c = wide > 0xffff_ffff;
+    let v = ((x ^ res) & (y ^ res) & 0x8000_0000) != 0;
+    (res, c, v)
+}
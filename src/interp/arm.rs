@@ -0,0 +1,595 @@
+//! Implementations of ARM instructions.
+
+use crate::lift::decode::ArmInst;
+use crate::lift::arm::bits::*;
+use crate::lift::arm::branch::sign_extend;
+use crate::lift::arm::status::field_mask;
+use crate::guest::{ CpuMode, Psr };
+use crate::runtime::RuntimeExitCode;
+use crate::interp::{ Cpu, Exec, Trap };
+use crate::interp::alu::*;
+
+/// Execute an instruction whose condition has already passed.
+pub fn execute(cpu: &mut Cpu, inst: ArmInst) -> Exec<()> {
+    use ArmInst::*;
+    let opcd = cpu.opcd;
+    match inst {
+        AndImm | EorImm | SubImm | RsbImm | AddImm | AdcImm | SbcImm |
+        RscImm | TstImm | TeqImm | CmpImm | CmnImm | OrrImm | MovImm |
+        BicImm | MvnImm => dp_imm(cpu, DpImmBits(opcd)),
+
+        AndReg | EorReg | SubReg | RsbReg | AddReg | AdcReg | SbcReg |
+        RscReg | TstReg | TeqReg | CmpReg | CmnReg | OrrReg | MovReg |
+        BicReg | MvnReg => dp_reg(cpu, DpRegBits(opcd)),
+
+        AndRegShiftReg | EorRegShiftReg | SubRegShiftReg | RsbRegShiftReg |
+        AddRegShiftReg | AdcRegShiftReg | SbcRegShiftReg | RscRegShiftReg |
+        TstRegShiftReg | TeqRegShiftReg | CmpRegShiftReg | CmnRegShiftReg |
+        OrrRegShiftReg | MovRegShiftReg | BicRegShiftReg |
+        MvnRegShiftReg => dp_rsr(cpu, DpRsrBits(opcd)),
+
+        Mul | Mla => mul(cpu, MlaBits(opcd), inst == Mla),
+        Umull | Umlal | Smull | Smlal => mull(cpu, SignedMlBits(opcd), inst),
+        Qadd | Qsub | Qdadd | Qdsub => qarith(cpu, QBits(opcd), inst),
+        Smulbb => smulxy(cpu, SmulbbBits(opcd)),
+        Smlabb => smlaxy(cpu, SmlabbBits(opcd)),
+        Smulwb => smulwy(cpu, SmulwbBits(opcd)),
+        Smlawb => smlawy(cpu, SmlawbBits(opcd)),
+        Smlalbb => smlalxy(cpu, SmalbbBits(opcd)),
+        Clz => {
+            let op = ClzBits(opcd);
+            let res = cpu.reg(op.rm()).leading_zeros();
+            cpu.set_reg(op.rd(), res);
+            Ok(())
+        },
+
+        LdrImm | StrImm | LdrbImm | StrbImm => {
+            let op = LsImmBits(opcd);
+            ls_word(cpu, inst, op.p(), op.u(), op.w(), op.rn(), op.rt(),
+                op.imm12())
+        },
+        LdrReg | StrReg | LdrbReg | StrbReg => {
+            let op = LsRegBits(opcd);
+            let c = cpu.state.cpsr.c();
+            let (off, _) = shift_imm(cpu.reg(op.rm()), op.stype(), op.imm5(), c);
+            ls_word(cpu, inst, op.p(), op.u(), op.w(), op.rn(), op.rt(), off)
+        },
+        Ldrt | Strt | Ldrbt | Strbt => {
+            let op = LsTransBits(opcd);
+            ls_word(cpu, inst, false, op.u(), true, op.rn(), op.rt(),
+                op.imm12())
+        },
+        LdrtAlt | StrtAlt | LdrbtAlt | StrbtAlt => {
+            let op = LsTransAltBits(opcd);
+            let c = cpu.state.cpsr.c();
+            let (off, _) = shift_imm(cpu.reg(op.rm()), op.stype(), op.imm5(), c);
+            ls_word(cpu, inst, false, op.u(), true, op.rn(), op.rt(), off)
+        },
+
+        LdrhImm | StrhImm | LdrsbImm | LdrshImm | LdrdImm | StrdImm => {
+            let op = LsSignedImmBits(opcd);
+            let off = (op.imm4h() << 4) | op.imm4l();
+            ls_misc(cpu, inst, op.p(), op.u(), op.w(), op.rn(), op.rt(), off)
+        },
+        LdrhReg | StrhReg | LdrsbReg | LdrshReg | LdrdReg | StrdReg => {
+            let op = LsSignedRegBits(opcd);
+            let off = cpu.reg(op.rm());
+            ls_misc(cpu, inst, op.p(), op.u(), op.w(), op.rn(), op.rt(), off)
+        },
+
+        Stm | Stmib | Stmda | Stmdb | Ldm | Ldmib | Ldmda | Ldmdb => {
+            let op = LsMultiBits(opcd);
+            let load = matches!(inst, Ldm | Ldmib | Ldmda | Ldmdb);
+            ls_multi(cpu, load, false, op.w(), op.rn(), op.register_list())
+        },
+        LdmRegUser => {
+            let op = LdmRegUserBits(opcd);
+            ls_multi(cpu, true, true, op.w(), op.rn(), opcd & 0xffff)
+        },
+        StmRegUser => {
+            let op = StmRegUserBits(opcd);
+            ls_multi(cpu, false, true, false, op.rn(), op.register_list())
+        },
+
+        B | BlImm => {
+            let op = BranchBits(opcd);
+            let offset = sign_extend(op.imm24(), 24) * 4;
+            if inst == BlImm {
+                cpu.set_reg(14, cpu.pc.wrapping_add(4));
+            }
+            cpu.set_reg(15, cpu.reg(15).wrapping_add(offset as u32));
+            Ok(())
+        },
+        // NOTE: There's no Jazelle, so `bxj` is just `bx`.
+        Bx | Bxj | BlxReg => {
+            let op = BxBits(opcd);
+            let target = cpu.reg(op.rm());
+            if inst == BlxReg {
+                cpu.set_reg(14, cpu.pc.wrapping_add(4));
+            }
+            cpu.interwork(target)
+        },
+
+        Mrs => {
+            let op = MrsBits(opcd);
+            let val = if op.r() {
+                if !cpu.has_spsr() {
+                    return Err(cpu.unsupported("mrs from SPSR without SPSR"));
+                }
+                cpu.state.spsr().0
+            } else {
+                cpu.state.cpsr.0
+            };
+            cpu.set_reg(op.rd(), val);
+            Ok(())
+        },
+        MsrImm => {
+            let op = MsrImmBits(opcd);
+            let (val, _) = rot_imm(op.imm12(), false);
+            msr(cpu, op.r(), op.mask(), val)
+        },
+        MsrReg => {
+            let op = MsrRegBits(opcd);
+            let val = cpu.reg(op.rn());
+            msr(cpu, op.r(), op.mask(), val)
+        },
+
+        Mrc | Mcr => cp15(cpu, MoveCoprocBits(opcd), inst == Mrc),
+        // There are no other coprocessors
+        Stc | LdcImm | Mcrr | Mrrc => {
+            Err(Trap::Exit(RuntimeExitCode::Undefined))
+        },
+
+        PldImm | PldReg => Ok(()),
+
+        Svc => Err(Trap::Exit(RuntimeExitCode::Svc)),
+        Bkpt => Err(Trap::Exit(RuntimeExitCode::Breakpoint)),
+        // `movw` doesn't exist on ARMv5
+        MovImmAlt | Undefined => Err(Trap::Exit(RuntimeExitCode::Undefined)),
+    }
+}
+
+/// Execute an instruction from the unconditional space (where the
+/// condition field is `0b1111`).
+pub fn execute_unconditional(cpu: &mut Cpu) -> Exec<()> {
+    let opcd = cpu.opcd;
+    if (opcd & 0x0e00_0000) == 0x0a00_0000 {
+        // `blx <imm>` always switches to Thumb
+        Err(cpu.unsupported("Thumb"))
+    } else if (opcd & 0x0d70_f000) == 0x0550_f000 {
+        // `pld` does nothing
+        Ok(())
+    } else {
+        Err(Trap::Exit(RuntimeExitCode::Undefined))
+    }
+}
+
+
+/// Data-processing instructions.
+///
+/// The opcode is always in bits `[24:21]`, and the test instructions (`tst`,
+/// `teq`, `cmp`, and `cmn`) always have the S bit set.
+fn dataproc(cpu: &mut Cpu, s: bool, rn: u32, rd: u32, op2: u32, shc: bool)
+    -> Exec<()>
+{
+    let opc = (cpu.opcd >> 21) & 0xf;
+    let x = cpu.reg(rn);
+    let (c, v) = (cpu.state.cpsr.c(), cpu.state.cpsr.v());
+    let (res, c, v, logical) = match opc {
+        0b0000 | 0b1000 => (x & op2, shc, v, true),
+        0b0001 | 0b1001 => (x ^ op2, shc, v, true),
+        0b1100 => (x | op2, shc, v, true),
+        0b1101 => (op2, shc, v, true),
+        0b1110 => (x & !op2, shc, v, true),
+        0b1111 => (!op2, shc, v, true),
+        _ => {
+            let (res, c, v) = match opc {
+                0b0010 | 0b1010 => add_with_carry(x, !op2, true),
+                0b0011 => add_with_carry(op2, !x, true),
+                0b0100 | 0b1011 => add_with_carry(x, op2, false),
+                0b0101 => add_with_carry(x, op2, c),
+                0b0110 => add_with_carry(x, !op2, c),
+                0b0111 => add_with_carry(op2, !x, c),
+                _ => unreachable!(),
+            };
+            (res, c, v, false)
+        },
+    };
+
+    let test = (0b1000..=0b1011).contains(&opc);
+    if !test && rd == 15 {
+        // Writing the program counter with the S bit set is an exception
+        // return
+        if s { cpu.restore_spsr()?; }
+        cpu.set_reg(15, res & !3);
+        return Ok(());
+    }
+    if !test {
+        cpu.set_reg(rd, res);
+    }
+    if s {
+        cpu.set_nz(res);
+        cpu.state.cpsr.set_c(c);
+        if !logical { cpu.state.cpsr.set_v(v); }
+    }
+    Ok(())
+}
+
+fn dp_imm(cpu: &mut Cpu, op: DpImmBits) -> Exec<()> {
+    let (op2, c) = rot_imm(op.imm12(), cpu.state.cpsr.c());
+    dataproc(cpu, op.s(), op.rn(), op.rd(), op2, c)
+}
+
+fn dp_reg(cpu: &mut Cpu, op: DpRegBits) -> Exec<()> {
+    let rm = cpu.reg(op.rm());
+    let (op2, c) = shift_imm(rm, op.stype(), op.imm5(), cpu.state.cpsr.c());
+    dataproc(cpu, op.s(), op.rn(), op.rd(), op2, c)
+}
+
+fn dp_rsr(cpu: &mut Cpu, op: DpRsrBits) -> Exec<()> {
+    if op.rd() == 15 || op.rn() == 15 || op.rm() == 15 || op.rs() == 15 {
+        return Err(cpu.unsupported("register-shifted register with pc"));
+    }
+    let (rm, rs) = (cpu.reg(op.rm()), cpu.reg(op.rs()));
+    let (op2, c) = shift_reg(rm, op.stype(), rs, cpu.state.cpsr.c());
+    dataproc(cpu, op.s(), op.rn(), op.rd(), op2, c)
+}
+
+
+/// Multiply instructions.
+fn mul(cpu: &mut Cpu, op: MlaBits, acc: bool) -> Exec<()> {
+    let mut res = cpu.reg(op.rn()).wrapping_mul(cpu.reg(op.rm()));
+    if acc {
+        res = res.wrapping_add(cpu.reg(op.ra()));
+    }
+    cpu.set_reg(op.rd(), res);
+    if op.s() { cpu.set_nz(res); }
+    Ok(())
+}
+
+fn mull(cpu: &mut Cpu, op: SignedMlBits, inst: ArmInst) -> Exec<()> {
+    use ArmInst::*;
+    let (rn, rm) = (cpu.reg(op.rn()), cpu.reg(op.rm()));
+    let mut res = match inst {
+        Smull | Smlal => (rn as i32 as i64).wrapping_mul(rm as i32 as i64) as u64,
+        _ => (rn as u64).wrapping_mul(rm as u64),
+    };
+    if inst == Umlal || inst == Smlal {
+        let acc = (cpu.reg(op.rdhi()) as u64) << 32 | cpu.reg(op.rdlo()) as u64;
+        res = res.wrapping_add(acc);
+    }
+    cpu.set_reg(op.rdlo(), res as u32);
+    cpu.set_reg(op.rdhi(), (res >> 32) as u32);
+    if op.s() {
+        cpu.state.cpsr.set_n((res >> 63) != 0);
+        cpu.state.cpsr.set_z(res == 0);
+    }
+    Ok(())
+}
+
+/// Saturate a signed 64-bit value to 32 bits, setting the Q flag if it
+/// saturated.
+fn saturate(cpu: &mut Cpu, x: i64) -> i32 {
+    if x > i32::MAX as i64 {
+        cpu.state.cpsr.set_q(true);
+        i32::MAX
+    } else if x < i32::MIN as i64 {
+        cpu.state.cpsr.set_q(true);
+        i32::MIN
+    } else {
+        x as i32
+    }
+}
+
+fn qarith(cpu: &mut Cpu, op: QBits, inst: ArmInst) -> Exec<()> {
+    use ArmInst::*;
+    let (rm, rn) = (cpu.reg(op.rm()) as i32 as i64, cpu.reg(op.rn()) as i32 as i64);
+    let rn = match inst {
+        Qdadd | Qdsub => saturate(cpu, rn * 2) as i64,
+        _ => rn,
+    };
+    let res = match inst {
+        Qadd | Qdadd => saturate(cpu, rm + rn),
+        _ => saturate(cpu, rm - rn),
+    };
+    cpu.set_reg(op.rd(), res as u32);
+    Ok(())
+}
+
+/// Select the top or bottom (signed) halfword of a register.
+fn half(x: u32, top: bool) -> i32 {
+    if top { (x >> 16) as i16 as i32 } else { x as i16 as i32 }
+}
+
+fn smulxy(cpu: &mut Cpu, op: SmulbbBits) -> Exec<()> {
+    let x = half(cpu.reg(op.rn()), op.n());
+    let y = half(cpu.reg(op.rm()), op.m());
+    cpu.set_reg(op.rd(), x.wrapping_mul(y) as u32);
+    Ok(())
+}
+
+fn smlaxy(cpu: &mut Cpu, op: SmlabbBits) -> Exec<()> {
+    let x = half(cpu.reg(op.rn()), op.n());
+    let y = half(cpu.reg(op.rm()), op.m());
+    let acc = cpu.reg(op.ra()) as i32;
+    let (res, overflow) = x.wrapping_mul(y).overflowing_add(acc);
+    if overflow { cpu.state.cpsr.set_q(true); }
+    cpu.set_reg(op.rd(), res as u32);
+    Ok(())
+}
+
+fn smulwy(cpu: &mut Cpu, op: SmulwbBits) -> Exec<()> {
+    let x = cpu.reg(op.rn()) as i32 as i64;
+    let y = half(cpu.reg(op.rm()), op.m()) as i64;
+    cpu.set_reg(op.rd(), ((x * y) >> 16) as u32);
+    Ok(())
+}
+
+fn smlawy(cpu: &mut Cpu, op: SmlawbBits) -> Exec<()> {
+    let x = cpu.reg(op.rn()) as i32 as i64;
+    let y = half(cpu.reg(op.rm()), op.m()) as i64;
+    let acc = cpu.reg(op.ra()) as i32;
+    let (res, overflow) = (((x * y) >> 16) as i32).overflowing_add(acc);
+    if overflow { cpu.state.cpsr.set_q(true); }
+    cpu.set_reg(op.rd(), res as u32);
+    Ok(())
+}
+
+fn smlalxy(cpu: &mut Cpu, op: SmalbbBits) -> Exec<()> {
+    let x = half(cpu.reg(op.rn()), op.n()) as i64;
+    let y = half(cpu.reg(op.rm()), op.m()) as i64;
+    let acc = (cpu.reg(op.rdhi()) as u64) << 32 | cpu.reg(op.rdlo()) as u64;
+    let res = acc.wrapping_add((x * y) as u64);
+    cpu.set_reg(op.rdlo(), res as u32);
+    cpu.set_reg(op.rdhi(), (res >> 32) as u32);
+    Ok(())
+}
+
+
+/// Compute the address for a load or store, returning the address of the
+/// access and the value written back to the base register (if any).
+fn address(cpu: &Cpu, p: bool, u: bool, w: bool, rn: u32, off: u32)
+    -> Exec<(u32, Option<u32>)>
+{
+    let base = cpu.reg(rn);
+    let offset_addr = if u {
+        base.wrapping_add(off)
+    } else {
+        base.wrapping_sub(off)
+    };
+    let addr = if p { offset_addr } else { base };
+    let wb = if !p || w { Some(offset_addr) } else { None };
+    if wb.is_some() && rn == 15 {
+        return Err(cpu.unsupported("writeback to pc"));
+    }
+    Ok((addr, wb))
+}
+
+/// Word and unsigned byte loads and stores.
+#[allow(clippy::too_many_arguments)]
+fn ls_word(cpu: &mut Cpu, inst: ArmInst,
+    p: bool, u: bool, w: bool, rn: u32, rt: u32, off: u32) -> Exec<()>
+{
+    use ArmInst::*;
+    let (addr, wb) = address(cpu, p, u, w, rn, off)?;
+    let byte = matches!(inst,
+        LdrbImm | StrbImm | LdrbReg | StrbReg |
+        Ldrbt | Strbt | LdrbtAlt | StrbtAlt
+    );
+    let load = matches!(inst,
+        LdrImm | LdrbImm | LdrReg | LdrbReg |
+        Ldrt | Ldrbt | LdrtAlt | LdrbtAlt
+    );
+    // The "T" variants always access memory as if in user mode
+    let privileged = cpu.privileged() && !matches!(inst,
+        Ldrt | Strt | Ldrbt | Strbt | LdrtAlt | StrtAlt | LdrbtAlt | StrbtAlt
+    );
+
+    if load {
+        let val = if byte {
+            cpu.load8(addr, privileged)? as u32
+        } else {
+            // Unaligned loads are rotated
+            let word = cpu.load32(addr & !3, privileged)?;
+            word.rotate_right((addr & 3) * 8)
+        };
+        if let Some(wb) = wb { cpu.set_reg(rn, wb); }
+        if rt == 15 {
+            cpu.interwork(val)?;
+        } else {
+            cpu.set_reg(rt, val);
+        }
+    } else {
+        let val = cpu.reg(rt);
+        if byte {
+            cpu.store8(addr, val as u8, privileged)?;
+        } else {
+            cpu.store32(addr & !3, val, privileged)?;
+        }
+        if let Some(wb) = wb { cpu.set_reg(rn, wb); }
+    }
+    Ok(())
+}
+
+/// Halfword, signed byte, and doubleword loads and stores.
+#[allow(clippy::too_many_arguments)]
+fn ls_misc(cpu: &mut Cpu, inst: ArmInst,
+    p: bool, u: bool, w: bool, rn: u32, rt: u32, off: u32) -> Exec<()>
+{
+    use ArmInst::*;
+    if !p && w {
+        return Err(cpu.unsupported("addressing mode"));
+    }
+    if rt == 15 {
+        return Err(cpu.unsupported("load/store to pc"));
+    }
+    let (addr, wb) = address(cpu, p, u, w, rn, off)?;
+    let privileged = cpu.privileged();
+
+    match inst {
+        StrhImm | StrhReg => {
+            let val = cpu.reg(rt) as u16;
+            cpu.store16(addr, val, privileged)?;
+        },
+        StrdImm | StrdReg => {
+            if (rt & 1) != 0 || rt == 14 {
+                return Err(cpu.unsupported("strd register"));
+            }
+            let (lo, hi) = (cpu.reg(rt), cpu.reg(rt + 1));
+            cpu.store32(addr, lo, privileged)?;
+            cpu.store32(addr.wrapping_add(4), hi, privileged)?;
+        },
+        LdrdImm | LdrdReg => {
+            if (rt & 1) != 0 || rt == 14 {
+                return Err(cpu.unsupported("ldrd register"));
+            }
+            let lo = cpu.load32(addr, privileged)?;
+            let hi = cpu.load32(addr.wrapping_add(4), privileged)?;
+            if let Some(wb) = wb { cpu.set_reg(rn, wb); }
+            cpu.set_reg(rt, lo);
+            cpu.set_reg(rt + 1, hi);
+            return Ok(());
+        },
+        _ => {
+            let val = match inst {
+                LdrhImm | LdrhReg => cpu.load16(addr, privileged)? as u32,
+                LdrshImm | LdrshReg => {
+                    cpu.load16(addr, privileged)? as i16 as i32 as u32
+                },
+                _ => cpu.load8(addr, privileged)? as i8 as i32 as u32,
+            };
+            if let Some(wb) = wb { cpu.set_reg(rn, wb); }
+            cpu.set_reg(rt, val);
+            return Ok(());
+        },
+    }
+    if let Some(wb) = wb { cpu.set_reg(rn, wb); }
+    Ok(())
+}
+
+/// Read or write the user mode copies of some registers.
+fn with_user_regs<T>(cpu: &mut Cpu, f: impl FnOnce(&mut Cpu) -> T) -> Exec<T> {
+    let mode = cpu.state.cpsr.mode()
+        .map_err(|_| Trap::Exit(RuntimeExitCode::InvalidMode))?;
+    cpu.state.switch_mode(CpuMode::Sys);
+    let res = f(cpu);
+    cpu.state.switch_mode(mode);
+    Ok(res)
+}
+
+/// Load and store multiple.
+///
+/// When `s` is set, these transfer user mode registers (or when loading the
+/// program counter, return from an exception).
+fn ls_multi(cpu: &mut Cpu, load: bool, s: bool, w: bool, rn: u32, list: u32)
+    -> Exec<()>
+{
+    // Bits 24 and 23 select before/after and increment/decrement
+    let (p, u) = ((cpu.opcd & (1 << 24)) != 0, (cpu.opcd & (1 << 23)) != 0);
+    let n = list.count_ones();
+    if n == 0 {
+        return Err(cpu.unsupported("empty register list"));
+    }
+    if rn == 15 {
+        return Err(cpu.unsupported("ldm/stm with rn=pc"));
+    }
+    let base = cpu.reg(rn);
+    let size = n * 4;
+    let start = match (p, u) {
+        (false, true) => base,
+        (true, true) => base.wrapping_add(4),
+        (false, false) => base.wrapping_sub(size).wrapping_add(4),
+        (true, false) => base.wrapping_sub(size),
+    };
+    let wb = if u { base.wrapping_add(size) } else { base.wrapping_sub(size) };
+    let regs: Vec<u32> = (0..16).filter(|i| (list & (1 << i)) != 0).collect();
+    let user = s && !(load && (list & (1 << 15)) != 0);
+    let privileged = cpu.privileged();
+
+    if load {
+        let mut vals = Vec::with_capacity(regs.len());
+        for i in 0..regs.len() {
+            let addr = start.wrapping_add(i as u32 * 4);
+            vals.push(cpu.load32(addr, privileged)?);
+        }
+        if w { cpu.set_reg(rn, wb); }
+        if user {
+            with_user_regs(cpu, |cpu| {
+                for (reg, val) in regs.iter().zip(vals.iter()) {
+                    cpu.set_reg(*reg, *val);
+                }
+            })?;
+        } else {
+            for (reg, val) in regs.iter().zip(vals.iter()) {
+                if *reg == 15 {
+                    if s {
+                        cpu.restore_spsr()?;
+                        cpu.set_reg(15, *val & !3);
+                    } else {
+                        cpu.interwork(*val)?;
+                    }
+                } else {
+                    cpu.set_reg(*reg, *val);
+                }
+            }
+        }
+    } else {
+        let vals: Vec<u32> = if user {
+            with_user_regs(cpu, |cpu| regs.iter().map(|r| cpu.reg(*r)).collect())?
+        } else {
+            regs.iter().map(|r| cpu.reg(*r)).collect()
+        };
+        for (i, val) in vals.iter().enumerate() {
+            let addr = start.wrapping_add(i as u32 * 4);
+            cpu.store32(addr, *val, privileged)?;
+        }
+        if w { cpu.set_reg(rn, wb); }
+    }
+    Ok(())
+}
+
+
+/// Write to the CPSR or SPSR.
+fn msr(cpu: &mut Cpu, r: bool, mask: u32, val: u32) -> Exec<()> {
+    let mask = field_mask(mask);
+    if r {
+        if !cpu.has_spsr() {
+            return Err(cpu.unsupported("msr to SPSR without SPSR"));
+        }
+        let spsr = cpu.state.spsr();
+        cpu.state.set_spsr(Psr((spsr.0 & !mask) | (val & mask)));
+        Ok(())
+    } else {
+        // Only the flags can be written in user mode
+        let mask = if cpu.privileged() { mask } else { mask & 0xf000_0000 };
+        let cpsr = cpu.state.cpsr;
+        cpu.write_cpsr(Psr((cpsr.0 & !mask) | (val & mask)))
+    }
+}
+
+/// Moves to and from the system control coprocessor.
+fn cp15(cpu: &mut Cpu, op: MoveCoprocBits, read: bool) -> Exec<()> {
+    if op.coproc() != 15 || !cpu.privileged() {
+        return Err(Trap::Exit(RuntimeExitCode::Undefined));
+    }
+    let (crn, opc1, crm, opc2) = (op.crn(), op.opc1(), op.crm(), op.opc2());
+    if read {
+        let val = match cpu.mmu.vmsa.read_cp15(crn, opc1, crm, opc2) {
+            Some(val) => val,
+            None => return Err(cpu.unsupported("CP15 register")),
+        };
+        // Reads into the program counter set the flags
+        if op.rt() == 15 {
+            let cpsr = cpu.state.cpsr.0;
+            cpu.state.cpsr = Psr((cpsr & 0x0fff_ffff) | (val & 0xf000_0000));
+        } else {
+            cpu.set_reg(op.rt(), val);
+        }
+    } else {
+        let val = cpu.reg(op.rt());
+        if !cpu.mmu.vmsa.write_cp15(crn, opc1, crm, opc2, val) {
+            return Err(cpu.unsupported("CP15 register"));
+        }
+    }
+    Ok(())
+}
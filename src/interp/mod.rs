@@ -0,0 +1,196 @@
+//! An interpreter for single ARM instructions.
+//!
+//! This is used for instructions that we can't recompile (yet). It operates
+//! directly on a [GuestState] and [GuestMmu], and follows the same
+//! conventions as recompiled code: when an instruction needs the dispatcher
+//! to do something, it returns a [RuntimeExitCode] and leaves the program
+//! counter at the instruction.
+
+pub mod alu;
+pub mod arm;
+
+use std::convert::TryFrom;
+
+use crate::guest::{ GuestState, GuestMmu, Cond, CpuMode, Psr };
+use crate::runtime::RuntimeExitCode;
+use crate::error::{ Error, ErrorKind, Result };
+use crate::lift::decode::ArmInst;
+
+/// Something that stops an instruction from completing.
+pub enum Trap {
+    /// Return to the dispatcher with an exit code.
+    Exit(RuntimeExitCode),
+    /// The interpreter doesn't support this instruction.
+    Error(Error),
+}
+impl From<Error> for Trap {
+    fn from(e: Error) -> Self { Trap::Error(e) }
+}
+
+/// The result of executing an instruction.
+pub type Exec<T> = std::result::Result<T, Trap>;
+
+/// The state of the machine while executing a single instruction.
+pub struct Cpu<'a> {
+    pub state: &'a mut GuestState,
+    pub mmu: &'a mut GuestMmu,
+    /// The address of the current instruction.
+    pub pc: u32,
+    /// The current instruction.
+    pub opcd: u32,
+    /// The target address, if this instruction writes the program counter.
+    branch: Option<u32>,
+}
+impl <'a> Cpu<'a> {
+    /// Return an error associated with the current instruction.
+    pub fn error(&self, kind: ErrorKind) -> Trap {
+        Trap::Error(Error::new(self.pc, self.opcd, kind))
+    }
+    pub fn unsupported(&self, what: &'static str) -> Trap {
+        self.error(ErrorKind::Unsupported(what))
+    }
+
+    pub fn privileged(&self) -> bool {
+        self.state.cpsr.is_privileged()
+    }
+
+    /// Read a register (where the program counter reads as the address of
+    /// the current instruction plus 8).
+    pub fn reg(&self, idx: u32) -> u32 {
+        if idx == 15 {
+            self.pc.wrapping_add(8)
+        } else {
+            self.state.reg[idx as usize]
+        }
+    }
+    /// Write a register (where writes to the program counter are branches).
+    pub fn set_reg(&mut self, idx: u32, val: u32) {
+        if idx == 15 {
+            self.branch = Some(val);
+        } else {
+            self.state.reg[idx as usize] = val;
+        }
+    }
+    /// Branch to an address loaded from memory (or written with `bx`),
+    /// where bit 0 selects Thumb state.
+    pub fn interwork(&mut self, val: u32) -> Exec<()> {
+        if (val & 1) != 0 {
+            return Err(self.unsupported("Thumb"));
+        }
+        self.branch = Some(val & !3);
+        Ok(())
+    }
+
+    /// Write the CPSR. A change to an invalid mode is an exit.
+    pub fn write_cpsr(&mut self, val: Psr) -> Exec<()> {
+        let mode = match CpuMode::try_from(val.0 & 0x1f) {
+            Ok(mode) => mode,
+            Err(_) => return Err(Trap::Exit(RuntimeExitCode::InvalidMode)),
+        };
+        if val.thumb() {
+            return Err(self.unsupported("Thumb"));
+        }
+        self.state.switch_mode(mode);
+        self.state.cpsr = val;
+        Ok(())
+    }
+    /// Returns true if the current mode has an SPSR.
+    pub fn has_spsr(&self) -> bool {
+        !matches!(self.state.cpsr.mode(), Ok(CpuMode::Usr | CpuMode::Sys))
+    }
+    /// Return from an exception by restoring the CPSR from the SPSR.
+    pub fn restore_spsr(&mut self) -> Exec<()> {
+        if !self.has_spsr() {
+            return Err(self.unsupported("exception return without SPSR"));
+        }
+        let spsr = self.state.spsr();
+        self.write_cpsr(spsr)
+    }
+
+    /// Set the N and Z flags for some result.
+    pub fn set_nz(&mut self, res: u32) {
+        self.state.cpsr.set_n((res & 0x8000_0000) != 0);
+        self.state.cpsr.set_z(res == 0);
+    }
+}
+
+/// Memory accesses.
+///
+/// Aborts record the fault and exit with [RuntimeExitCode::DataAbort], and
+/// accesses to unmapped memory complete before exiting with
+/// [RuntimeExitCode::MemoryFault] (just like the runtime helpers).
+impl <'a> Cpu<'a> {
+    fn check<T>(&mut self, res: std::result::Result<T, crate::mmu::Fault>)
+        -> Exec<T>
+    {
+        match res {
+            Ok(val) => {
+                if self.mmu.unmapped().is_some() {
+                    return Err(Trap::Exit(RuntimeExitCode::MemoryFault));
+                }
+                Ok(val)
+            },
+            Err(fault) => {
+                self.mmu.vmsa.record_fault(fault);
+                Err(Trap::Exit(RuntimeExitCode::DataAbort))
+            },
+        }
+    }
+
+    pub fn load32(&mut self, addr: u32, privileged: bool) -> Exec<u32> {
+        let res = self.mmu.load32(addr, privileged);
+        self.check(res)
+    }
+    pub fn load16(&mut self, addr: u32, privileged: bool) -> Exec<u16> {
+        let res = self.mmu.load16(addr, privileged);
+        self.check(res)
+    }
+    pub fn load8(&mut self, addr: u32, privileged: bool) -> Exec<u8> {
+        let res = self.mmu.load8(addr, privileged);
+        self.check(res)
+    }
+    pub fn store32(&mut self, addr: u32, val: u32, privileged: bool)
+        -> Exec<()>
+    {
+        let res = self.mmu.store32(addr, val, privileged);
+        self.check(res)
+    }
+    pub fn store16(&mut self, addr: u32, val: u16, privileged: bool)
+        -> Exec<()>
+    {
+        let res = self.mmu.store16(addr, val, privileged);
+        self.check(res)
+    }
+    pub fn store8(&mut self, addr: u32, val: u8, privileged: bool)
+        -> Exec<()>
+    {
+        let res = self.mmu.store8(addr, val, privileged);
+        self.check(res)
+    }
+}
+
+/// Interpret the ARM instruction `opcd` at the program counter.
+///
+/// If the instruction completes, this returns [RuntimeExitCode::NextBlock]
+/// with the program counter at the next instruction.
+pub fn step(state: &mut GuestState, mmu: &mut GuestMmu, opcd: u32)
+    -> Result<RuntimeExitCode>
+{
+    let pc = state.pc.fetch();
+    let mut cpu = Cpu { state, mmu, pc, opcd, branch: None };
+    cpu.mmu.take_unmapped();
+
+    let res = match Cond::try_from(opcd >> 28) {
+        Ok(cond) if !cond.passes(cpu.state.cpsr) => Ok(()),
+        Ok(_) => arm::execute(&mut cpu, ArmInst::decode(opcd)),
+        Err(_) => arm::execute_unconditional(&mut cpu),
+    };
+    match res {
+        Ok(()) => {
+            cpu.state.pc.0 = cpu.branch.unwrap_or_else(|| pc.wrapping_add(4));
+            Ok(RuntimeExitCode::NextBlock)
+        },
+        Err(Trap::Exit(code)) => Ok(code),
+        Err(Trap::Error(e)) => Err(e),
+    }
+}
@@ -21,8 +21,8 @@ impl fmt::Display for Constant {
 
 impl fmt::Display for Flag {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.value.is_some() {
-            write!(f, "Flag({:?}, {}", self.kind, self.value.unwrap())
+        if let Some(value) = self.value {
+            write!(f, "Flag({:?}, {}", self.kind, value)
         } else {
             write!(f, "Flag({:?})", self.kind)
         }
@@ -31,10 +31,7 @@ impl fmt::Display for Flag {
 
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.lh.is_none() {
-            write!(f, "{}", self.rh)
-        } else {
-            let lh = self.lh.unwrap();
+        if let Some(lh) = self.lh {
             match (self.lh_c, self.lh_v) {
                 (None, None) => {
                     write!(f, "%{} := {}", lh.id, self.rh)
@@ -49,6 +46,8 @@ impl fmt::Display for Instruction {
                     write!(f, "%{}, c{}, v{} := {}", lh.id, c, v, self.rh)
                 },
             }
+        } else {
+            write!(f, "{}", self.rh)
         }
     }
 }
@@ -102,6 +101,10 @@ impl fmt::Display for BindOp {
             }
             BindOp::ReadFlag(fl) => write!(f, "ReadFlag({:?})", fl),
             BindOp::WriteFlag(fl, v) => write!(f, "WriteFlag({:?}, {})", fl, v),
+            BindOp::WriteCpsr(v, mask) => 
+                write!(f, "WriteCpsr({}, {:08x})", v, mask),
+            BindOp::SetFlags(op, x, y) => 
+                write!(f, "SetFlags({:?}, {}, {})", op, x, y),
         }
     }
 }
@@ -57,8 +57,59 @@ impl Constant {
     }
 }
 
+/// An operation whose operands determine the guest flags (see 
+/// [BindOp::SetFlags]).
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagOp {
+    /// NZCV from the subtraction `x - y`.
+    Sub = 1,
+    /// NZ from the result `x`, and C from the shifter carry `y` (V is 
+    /// unchanged).
+    Logical = 2,
+}
+impl FlagOp {
+    /// Convert from the value stored in [crate::runtime::LazyFlags] (zero 
+    /// when there's no pending operation).
+    pub fn from_usize(x: usize) -> Option<Self> {
+        match x {
+            1 => Some(FlagOp::Sub),
+            2 => Some(FlagOp::Logical),
+            _ => None,
+        }
+    }
+
+    /// Return the CPSR after applying this operation to the flags in `cpsr`.
+    pub fn apply(&self, cpsr: u32, x: u32, y: u32) -> u32 {
+        let (mask, nzcv) = match self {
+            FlagOp::Sub => {
+                let res = x.wrapping_sub(y);
+                let (_, v) = (x as i32).overflowing_sub(y as i32);
+                (0xf000_0000, (res & 0x8000_0000) | ((res == 0) as u32) << 30
+                    | ((x >= y) as u32) << 29 | (v as u32) << 28)
+            },
+            FlagOp::Logical => {
+                (0xe000_0000, (x & 0x8000_0000) | ((x == 0) as u32) << 30
+                    | (y & 1) << 29)
+            },
+        };
+        (cpsr & !mask) | nzcv
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum FlagKind { Negative, Zero, Carry, Overflow }
+impl FlagKind {
+    /// The bit in the CPSR for this flag.
+    pub fn bit(&self) -> u32 {
+        match self {
+            FlagKind::Negative => 31,
+            FlagKind::Zero => 30,
+            FlagKind::Carry => 29,
+            FlagKind::Overflow => 28,
+        }
+    }
+}
 pub struct Flag { 
     pub kind: FlagKind, 
     pub value: Option<bool> 
@@ -88,6 +139,11 @@ pub enum BindOp {
     WriteGuestReg(guest::RegIdx, Var),
     ReadFlag(FlagKind),
     WriteFlag(FlagKind, Var),
+    /// Write the bits selected by a mask into the CPSR.
+    WriteCpsr(Var, u32),
+    /// Set the guest flags from some operation, which may be deferred until
+    /// the flags are actually used.
+    SetFlags(FlagOp, Var, Var),
 }
 #[derive(Clone, Debug)]
 pub enum BranchOp { 
@@ -105,6 +161,8 @@ pub enum Operation {
 #[derive(Clone)]
 pub struct Instruction {
     pub guest_op: u32,
+    /// Address of the guest instruction this was lifted from.
+    pub guest_pc: u32,
     pub lh: Option<Var>,
     pub lh_c: Option<Var>,
     pub lh_v: Option<Var>,
@@ -116,7 +174,12 @@ impl Instruction {
         match self.rh {
             Operation::Bind(ref op) => match op {
                 BindOp::WriteGuestReg(_, v) |
-                BindOp::WriteFlag(_, v) => vars.push(*v),
+                BindOp::WriteFlag(_, v) |
+                BindOp::WriteCpsr(v, _) => vars.push(*v),
+                BindOp::SetFlags(_, x, y) => {
+                    vars.push(*x);
+                    vars.push(*y);
+                },
                 _ => {},
             },
             Operation::Memory(ref op) => match op {
@@ -152,35 +215,50 @@ impl Instruction {
         Instruction { 
             lh: Some(v), lh_c: None, lh_v: None,
             rh: Operation::Bind(BindOp::Const(c)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn read_reg(opcd: u32, v: Var, reg: guest::RegIdx) -> Self {
         Instruction { 
             lh: Some(v), lh_c: None, lh_v: None,
             rh: Operation::Bind(BindOp::ReadGuestReg(reg)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn write_reg(opcd: u32, reg: guest::RegIdx, val: Var) -> Self {
         Instruction { 
             lh: None, lh_c: None, lh_v: None,
             rh: Operation::Bind(BindOp::WriteGuestReg(reg, val)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn read_flag(opcd: u32, v: Var, kind: FlagKind) -> Self {
         Instruction { 
             lh: Some(v), lh_c: None, lh_v: None,
             rh: Operation::Bind(BindOp::ReadFlag(kind)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn write_flag(opcd: u32, kind: FlagKind, val: Var) -> Self {
         Instruction { 
             lh: None, lh_c: None, lh_v: None,
             rh: Operation::Bind(BindOp::WriteFlag(kind, val)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
+        }
+    }
+    pub fn write_cpsr(opcd: u32, val: Var, mask: u32) -> Self {
+        Instruction { 
+            lh: None, lh_c: None, lh_v: None,
+            rh: Operation::Bind(BindOp::WriteCpsr(val, mask)),
+            guest_op: opcd, guest_pc: 0,
+        }
+    }
+
+    pub fn set_flags(opcd: u32, op: FlagOp, x: Var, y: Var) -> Self {
+        Instruction { 
+            lh: None, lh_c: None, lh_v: None,
+            rh: Operation::Bind(BindOp::SetFlags(op, x, y)),
+            guest_op: opcd, guest_pc: 0,
         }
     }
 
@@ -188,14 +266,14 @@ impl Instruction {
         Instruction {
             lh: Some(v), lh_c: None, lh_v: None,
             rh: Operation::Memory(MemoryOp::Load32(addr)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn store32(opcd: u32, addr: Var, val: Var) -> Self {
         Instruction {
             lh: None, lh_c: None, lh_v: None,
             rh: Operation::Memory(MemoryOp::Store32(addr, val)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
 
@@ -203,21 +281,21 @@ impl Instruction {
         Instruction {
             lh: Some(dst), lh_c: None, lh_v: None,
             rh: Operation::Arith(ArithOp::Add32(x, y)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn sub32(opcd: u32, dst: Var, x: Var, y: Var) -> Self {
         Instruction {
             lh: Some(dst), lh_c: None, lh_v: None,
             rh: Operation::Arith(ArithOp::Sub32(x, y)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn sub32f(opcd: u32, dst: Var, c: Var, v: Var, x: Var, y: Var) -> Self {
         Instruction {
             lh: Some(dst), lh_c: Some(c), lh_v: Some(v),
             rh: Operation::Arith(ArithOp::Sub32(x, y)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
 
@@ -226,14 +304,14 @@ impl Instruction {
         Instruction {
             lh: Some(dst), lh_c: None, lh_v: None,
             rh: Operation::Arith(ArithOp::Lsl32(x, y)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn lsl32f(opcd: u32, dst: Var, c: Var, v: Var, x: Var, y: Var) -> Self {
         Instruction {
             lh: Some(dst), lh_c: Some(c), lh_v: Some(v),
             rh: Operation::Arith(ArithOp::Lsl32(x, y)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
 
@@ -241,14 +319,14 @@ impl Instruction {
         Instruction {
             lh: Some(dst), lh_c: None, lh_v: None,
             rh: Operation::Arith(ArithOp::IsZero(x)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
     pub fn is_negative(opcd: u32, dst: Var, x: Var) -> Self {
         Instruction {
             lh: Some(dst), lh_c: None, lh_v: None,
             rh: Operation::Arith(ArithOp::IsNegative(x)),
-            guest_op: opcd,
+            guest_op: opcd, guest_pc: 0,
         }
     }
 }
@@ -1,7 +1,10 @@
-#![feature(const_fn_transmute, const_fn_fn_ptr_basics, const_mut_refs, asm)]
-
 pub mod mem;
+pub mod mmu;
+pub mod bus;
+pub mod fastmem;
+pub mod patch;
 pub mod runtime;
+pub mod timing;
 
 pub mod lift;
 pub mod opt;
@@ -10,12 +13,83 @@ pub mod regalloc;
 pub mod ir;
 pub mod block;
 pub mod guest;
+pub mod error;
+pub mod interp;
+
+use std::collections::{ HashMap, HashSet };
+use std::convert::TryFrom;
 
-use std::collections::HashMap;
+use crate::runtime::{ 
+    RuntimeContext, RuntimeExitCode, BlockFunc, BranchCache, Interrupts
+};
+use crate::guest::{ GuestState, GuestMmu, Psr, CpuMode, ExceptionType, Cond };
+use crate::bus::MemoryBus;
+use crate::mem::Endianness;
+use crate::timing::CycleTable;
+use crate::lift::decode::ArmInst;
+use crate::block::{ BasicBlock, BlockExit };
+use crate::error::{ Error, ErrorKind, Result };
+
+/// Print a message if debugging output is enabled (see [Jit::set_debug]).
+macro_rules! debug {
+    ($jit:expr, $($t:tt)*) => { if $jit.debug { println!($($t)*); } }
+}
 
-use crate::runtime::{ RuntimeContext, RuntimeExitCode, BlockFunc };
-use crate::guest::{ GuestState, GuestMmu, Psr };
-use crate::block::BasicBlock;
+/// The reason that [Jit::run_for] returned.
+///
+/// Unless otherwise noted, the program counter is left at the guest 
+/// instruction which caused execution to stop, and the instruction hasn't 
+/// been executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The cycle budget was exhausted.
+    Budget,
+    /// The guest halted.
+    Halt,
+    /// Execution reached a breakpoint (see [Jit::add_breakpoint]).
+    /// Running again resumes from the breakpoint.
+    Breakpoint(u32),
+    /// A `bkpt` instruction.
+    Bkpt { pc: u32, imm: u16 },
+    /// An `svc` instruction which needs to be handled by the host 
+    /// (see [Jit::raise_exception] for passing it to the guest instead).
+    Svc { pc: u32, imm: u32 },
+    /// An undefined instruction.
+    Undefined { pc: u32, opcd: u32 },
+    /// A fetch, load, or store touched an unmapped physical address.
+    /// Loads from unmapped memory have already completed (returning zero),
+    /// and stores have been ignored.
+    MemoryFault { pc: u32, addr: u32 },
+}
+
+/// The result of [Jit::run_for].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunResult {
+    /// The number of cycles which actually ran (this may exceed the budget,
+    /// since blocks always run to completion).
+    pub cycles: usize,
+    /// The reason that execution stopped.
+    pub reason: StopReason,
+}
+
+/// How lifted blocks are executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Recompile blocks into host code.
+    Recompile,
+    /// Evaluate the IR for each block without recompiling it (see 
+    /// [BasicBlock::eval]). This is much slower, but useful for checking 
+    /// whether a bug is in the emitter.
+    Eval,
+    /// Run each block both in recompiled code and in the IR evaluator (from
+    /// the same state), and return an error at the first block where the 
+    /// results differ. Stores to memory-mapped I/O can't be undone, so they
+    /// happen twice (and they aren't compared).
+    ///
+    /// Memory is compared by taking a copy of all RAM and ROM after each 
+    /// block, so this is even slower than [Backend::Eval].
+    Lockstep,
+}
 
 /// Top-level emulator state.
 #[repr(C)]
@@ -26,61 +100,545 @@ pub struct Jit {
     pub mmu: GuestMmu,
     /// A cache of previously-visited basic blocks.
     pub cache: HashMap<u32, BasicBlock>,
+    /// The set of cached blocks containing code from each physical page.
+    pages: HashMap<u32, Vec<u32>>,
+    /// The set of exits (and the blocks they belong to) which branch to each
+    /// guest address, whether or not they're currently linked.
+    links: HashMap<u32, Vec<(u32, BlockExit)>>,
+    /// Lookup tables used by recompiled code for indirect branches.
+    branch_cache: Box<BranchCache>,
+    /// The cost of each instruction.
+    timing: CycleTable,
+    /// The state of the guest's interrupt lines.
+    interrupts: Interrupts,
+    /// The set of guest addresses where execution stops.
+    breakpoints: HashSet<u32>,
+    /// A breakpoint which was just reported (and which shouldn't be reported
+    /// again until it's been executed).
+    resume_breakpoint: Option<u32>,
+    /// The runtime context (and dispatcher) used to enter recompiled code.
+    ctx: RuntimeContext,
+    /// Whether or not debugging output is printed.
+    debug: bool,
+    /// The set of guest addresses with instructions that can't be 
+    /// recompiled, which are run in the interpreter instead.
+    interp_pcs: HashSet<u32>,
+    /// How lifted blocks are executed.
+    backend: Backend,
 }
 
 impl Jit {
     pub fn new() -> Self {
+        Jit::with_mmu(GuestMmu::new())
+    }
+
+    /// Create a new guest machine with a user-defined physical memory map.
+    pub fn with_bus(bus: Box<dyn MemoryBus>) -> Self {
+        Jit::with_mmu(GuestMmu::with_bus(bus))
+    }
+
+    /// Set the byte order of the guest (big-endian by default).
+    ///
+    /// Since recompiled code depends on the byte order, this discards all 
+    /// previously-recompiled blocks.
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.mmu.endianness = endianness;
+        self.flush();
+    }
+
+    /// Print debugging output while running: disassembly of the guest code,
+    /// IR, and host code for each new block, and a message for each block 
+    /// that's executed or discarded.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Discard all cached blocks containing code from the guest physical
+    /// address range `[addr, addr + len)`.
+    ///
+    /// This is only necessary when guest memory is modified without going
+    /// through [GuestMmu] (writes through [GuestMmu] are already tracked).
+    /// Blocks are tracked by page, so this may also discard blocks which are
+    /// near (but outside of) the range.
+    pub fn invalidate_range(&mut self, addr: u32, len: usize) {
+        let end = addr as usize + len;
+        for page in ((addr as usize & !0xfff)..end).step_by(0x1000) {
+            if let Some(page) = self.mmu.canonical_page(page as u32) {
+                self.invalidate_page(page);
+            }
+        }
+    }
+
+    /// Discard all cached blocks.
+    pub fn flush(&mut self) {
+        let pcs: Vec<u32> = self.cache.keys().cloned().collect();
+        for pc in pcs {
+            self.invalidate_block(pc);
+        }
+        self.pages.clear();
+        self.mmu.untrack_all();
+        self.links.clear();
+        self.branch_cache.clear();
+        self.interp_pcs.clear();
+    }
+
+    fn with_mmu(mmu: GuestMmu) -> Self {
+        // Start in supervisor mode with interrupts disabled (as after reset)
+        let mut cpsr = Psr(0x0000_00c0);
+        cpsr.set_mode(CpuMode::Svc);
+        let mut branch_cache = BranchCache::new();
+
+        // Pointers into the Jit itself are filled in before each run
+        let ctx = RuntimeContext::new(0, mmu.fastmem_base(), 0, 0,
+            branch_cache.as_mut() as *mut BranchCache as usize);
         Jit { 
-            state: GuestState::new(0x0000_0000, 0x0000_0000), 
-            mmu: GuestMmu::new(),
+            state: GuestState::new(0x0000_0000, cpsr.0), 
+            mmu,
             cache: HashMap::new(),
+            pages: HashMap::new(),
+            links: HashMap::new(),
+            branch_cache,
+            timing: CycleTable::new(),
+            interrupts: Interrupts::default(),
+            breakpoints: HashSet::new(),
+            resume_breakpoint: None,
+            ctx,
+            debug: false,
+            interp_pcs: HashSet::new(),
+            backend: Backend::Recompile,
         }
     }
 
-    pub fn run(&mut self) {
+    /// Stop execution before the instruction at the guest virtual address
+    /// `pc` is executed.
+    pub fn add_breakpoint(&mut self, pc: u32) {
+        // Blocks must be split at the new breakpoint
+        if self.breakpoints.insert(pc) {
+            self.flush();
+        }
+    }
+    /// Remove a breakpoint.
+    pub fn remove_breakpoint(&mut self, pc: u32) {
+        if self.breakpoints.remove(&pc) {
+            self.flush();
+        }
+    }
+
+    /// Assert the IRQ line. The interrupt is taken at the next block 
+    /// boundary where IRQs are enabled, until the line is deasserted.
+    pub fn assert_irq(&mut self) { self.interrupts.irq = true; }
+    /// Deassert the IRQ line.
+    pub fn deassert_irq(&mut self) { self.interrupts.irq = false; }
+    /// Assert the FIQ line. The interrupt is taken at the next block 
+    /// boundary where FIQs are enabled, until the line is deasserted.
+    pub fn assert_fiq(&mut self) { self.interrupts.fiq = true; }
+    /// Deassert the FIQ line.
+    pub fn deassert_fiq(&mut self) { self.interrupts.fiq = false; }
 
-        // Instantiate the runtime context/dispatcher
-        let mut ctx = RuntimeContext::new(
-            unsafe { self.state.reg.as_ptr() as usize },
-            mem::ARENA_BASE,
-            unsafe { std::mem::transmute(&self.state.cpsr) },
-        );
+    /// Select how lifted blocks are executed.
+    ///
+    /// This discards all previously-lifted blocks.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.flush();
+    }
+
+    /// Prepare the runtime context to enter recompiled code with some cycle
+    /// budget.
+    ///
+    /// The Jit may have moved since the last call, so pointers into it are 
+    /// updated here (the dispatcher and [BranchCache] don't move).
+    fn reset_context(&mut self, cycles: isize) {
+        self.ctx.register_ptr = self.state.reg.as_ptr() as usize;
+        self.ctx.cpsr_ptr = &self.state.cpsr as *const Psr as usize;
+        self.ctx.mmu_ptr = &mut self.mmu as *mut GuestMmu as usize;
+        self.ctx.cycles = cycles;
+        self.ctx.stop = 0;
+        self.ctx.interrupts = self.interrupts;
+    }
 
+    /// Override the cost (in cycles) of some kind of instruction.
+    ///
+    /// Since costs are compiled into blocks, this discards all 
+    /// previously-recompiled blocks.
+    pub fn set_cycle_cost(&mut self, inst: ArmInst, cycles: usize) {
+        self.timing.set(inst, cycles);
+        self.flush();
+    }
+
+    /// Run until the guest halts (or until execution stops for some other
+    /// reason, other than running out of cycles).
+    pub fn run(&mut self) -> Result<StopReason> {
         loop {
+            match self.run_for(isize::MAX as usize)?.reason {
+                StopReason::Budget => {},
+                reason => break Ok(reason),
+            }
+        }
+    }
+
+    /// Run a single block.
+    pub fn step(&mut self) -> Result<StopReason> {
+        Ok(self.run_for(1)?.reason)
+    }
+
+    /// Run until the guest halts, or until at least `cycles` cycles have 
+    /// elapsed (or until execution stops for some other reason).
+    ///
+    /// If some guest code can't be recompiled, this returns an error, and 
+    /// the program counter is left at the guest instruction which caused it.
+    pub fn run_for(&mut self, cycles: usize) -> Result<RunResult> {
+        let budget = cycles.min(isize::MAX as usize) as isize;
+
+        self.reset_context(budget);
+
+        let reason = loop {
+            // Take any pending interrupt before the next block
+            if let Some(kind) = self.interrupts.pending(self.state.cpsr) {
+                let pc = self.state.pc.fetch();
+                self.take_exception(kind, pc.wrapping_add(4));
+            }
+
+            if self.ctx.cycles <= 0 {
+                break StopReason::Budget;
+            }
+
+            // Discard any blocks whose code was overwritten
+            self.invalidate_dirty_pages();
+
+            // Thumb isn't supported (the program counter is left at the 
+            // target of the branch)
             let pc = self.state.pc.fetch();
+            if pc & 3 != 0 {
+                return Err(Error::new(pc, 0, ErrorKind::Unsupported("thumb")));
+            }
+            // The user can write an invalid mode into the CPSR directly
+            if self.state.cpsr.mode().is_err() {
+                return Err(Error::new(pc, 0, ErrorKind::InvalidMode));
+            }
+
+            if self.breakpoints.contains(&pc) 
+                && self.resume_breakpoint.take() != Some(pc) 
+            {
+                self.resume_breakpoint = Some(pc);
+                break StopReason::Breakpoint(pc);
+            }
+            self.resume_breakpoint = None;
+
+            // Take a prefetch abort if we can't fetch the next block
+            let privileged = self.state.cpsr.is_privileged();
+            self.mmu.take_unmapped();
+            if let Err(fault) = self.mmu.fetch32(pc, privileged) {
+                self.mmu.vmsa.record_fault(fault);
+                self.take_exception(ExceptionType::PrefetchAbort, 
+                    pc.wrapping_add(4));
+                continue;
+            }
+            if let Some(addr) = self.mmu.take_unmapped() {
+                break StopReason::MemoryFault { pc, addr };
+            }
+
+            // Instructions we can't recompile are interpreted one at a time
+            if self.interp_pcs.contains(&pc) {
+                let res = self.interpret(pc)?;
+                match self.handle_exit(res)? {
+                    Some(reason) => break reason,
+                    None => continue,
+                }
+            }
+
             let bb = match self.cache.get(&pc) {
                 // Lift, compile, and cache a block if we haven't seen it
                 None => {
-                    let mut new_block = BasicBlock::lift(&self.state, &self.mmu);
-                    println!("[*] Lifted new block {:08x}", pc);
-                    new_block.disas_guest();
-
+                    // Blocks end before breakpoints and interpreted 
+                    // instructions
+                    let stops: HashSet<u32> = self.breakpoints
+                        .union(&self.interp_pcs).cloned().collect();
+                    let mut new_block = match BasicBlock::lift(&self.state, 
+                        &mut self.mmu, &stops) 
+                    {
+                        Ok(bb) => bb,
+                        Err(e) => {
+                            debug!(self, "[*] Interpreting {:08x} ({})", pc, e);
+                            self.interp_pcs.insert(pc);
+                            continue;
+                        },
+                    };
                     new_block.prune_dead_vars();
-                    new_block.disas_ir();
+                    new_block.cycles = self.timing.cost_all(&new_block.guest_ops);
 
-                    new_block.recompile();
-                    new_block.disas_host();
-                    new_block.storage.print();
-                    new_block.intervals.print();
-                    println!("");
+                    // If some instruction can't be compiled, lift the block 
+                    // again so that it ends before the instruction
+                    if self.backend != Backend::Eval {
+                        if let Err(e) = new_block.recompile(&self.mmu) {
+                            debug!(self, "[*] Interpreting {:08x} ({})", e.pc, e);
+                            self.interp_pcs.insert(e.pc);
+                            continue;
+                        }
+                    }
+                    if self.debug {
+                        println!("[*] Lifted new block {:08x}", pc);
+                        new_block.disas_guest();
+                        new_block.disas_ir();
+                        if self.backend == Backend::Recompile {
+                            new_block.disas_host();
+                            new_block.storage.print();
+                            new_block.intervals.print();
+                        }
+                        println!();
+                    }
 
+                    for page in new_block.code_pages.iter() {
+                        self.pages.entry(*page).or_default()
+                            .push(pc);
+                    }
                     self.cache.insert(pc, new_block);
+                    // Blocks are only linked to each other (or entered from 
+                    // the branch cache) when recompiled code runs on its own
+                    if self.backend == Backend::Recompile {
+                        self.link_block(pc);
+                    }
                     self.cache.get(&pc).unwrap()
                 },
                 // Otherwise, retrieve the block from the cache
                 Some(block) => block,
             };
 
-            // Enter the dispatcher at the current block
-            println!("[*] Executing block {:08x}", pc);
-            let res = runtime::trampoline(&mut ctx, BlockFunc::from_block(&bb));
-            match RuntimeExitCode::from(res) {
-                RuntimeExitCode::NextBlock => {}, 
-                RuntimeExitCode::Halt => break,
+            let res = match self.backend {
+                // Enter the dispatcher at the current block
+                Backend::Recompile => {
+                    debug!(self, "[*] Executing block {:08x}", pc);
+                    runtime::trampoline(&mut self.ctx, BlockFunc::from_block(bb))
+                        .map_err(|x| Error::new(pc, bb.guest_ops[0], 
+                            ErrorKind::InvalidExitCode(x)))?
+                },
+                Backend::Eval => {
+                    debug!(self, "[*] Evaluating block {:08x}", pc);
+                    self.ctx.cycles -= bb.cycles as isize;
+                    bb.eval(&mut self.state, &mut self.mmu)?
+                },
+                Backend::Lockstep => {
+                    debug!(self, "[*] Executing block {:08x} (lockstep)", pc);
+                    let before = self.state;
+                    let mut stores = Vec::new();
+                    let eval_res = bb.eval_logged(&mut self.state, 
+                        &mut self.mmu, Some(&mut stores))?;
+                    let after = self.state;
+                    let expected = self.mmu.snapshot();
+
+                    // Undo the evaluator's stores (other than stores to 
+                    // memory-mapped I/O), and run the recompiled code from 
+                    // the same state
+                    for s in stores.iter().rev() {
+                        if let Some(old) = s.old {
+                            let _ = self.mmu.store32(s.addr, old, s.privileged);
+                        }
+                    }
+                    self.state = before;
+                    let res = runtime::trampoline(&mut self.ctx, 
+                        BlockFunc::from_block(bb))
+                        .map_err(|x| Error::new(pc, bb.guest_ops[0], 
+                            ErrorKind::InvalidExitCode(x)))?;
+
+                    if let Some(msg) = block::eval::divergence(
+                        (&after, eval_res), (&self.state, res), &expected, 
+                        &self.mmu)
+                    {
+                        if self.debug {
+                            println!("[!] Divergence in block {:08x}: {}", pc, msg);
+                            bb.disas_guest();
+                            bb.disas_ir();
+                            bb.disas_host();
+                        }
+                        return Err(Error::new(pc, bb.guest_ops[0], 
+                            ErrorKind::Divergence(msg)));
+                    }
+                    res
+                },
+            };
+            if let Some(reason) = self.handle_exit(res)? {
+                break reason;
             }
+        };
+
+        Ok(RunResult { cycles: (budget - self.ctx.cycles) as usize, reason })
+    }
+
+    /// Deal with the exit code from a block (or an interpreted instruction),
+    /// returning a reason if execution should stop.
+    fn handle_exit(&mut self, res: RuntimeExitCode) 
+        -> Result<Option<StopReason>> 
+    {
+        // For all other exits, the program counter points at the 
+        // instruction which caused the exit
+        let pc = self.state.pc.fetch();
+        let reason = match res {
+            RuntimeExitCode::NextBlock => return Ok(None),
+            RuntimeExitCode::Halt => StopReason::Halt,
+            RuntimeExitCode::DataAbort => {
+                self.raise_exception(ExceptionType::DataAbort);
+                return Ok(None);
+            },
+            // Recompiled code only returns this after an access to unmapped
+            // memory
+            RuntimeExitCode::MemoryFault => match self.mmu.take_unmapped() {
+                Some(addr) => StopReason::MemoryFault { pc, addr },
+                None => return Err(Error::new(pc, self.opcd_at(pc), 
+                    ErrorKind::InvalidExitCode(res as usize))),
+            },
+            RuntimeExitCode::Breakpoint => {
+                let opcd = self.opcd_at(pc);
+                let imm = ((opcd & 0x000f_ff00) >> 4) | (opcd & 0xf);
+                StopReason::Bkpt { pc, imm: imm as u16 }
+            },
+            RuntimeExitCode::Svc => {
+                let opcd = self.opcd_at(pc);
+                if self.skip_if_failed(opcd) { return Ok(None); }
+                StopReason::Svc { pc, imm: opcd & 0x00ff_ffff }
+            },
+            RuntimeExitCode::Undefined => {
+                let opcd = self.opcd_at(pc);
+                if self.skip_if_failed(opcd) { return Ok(None); }
+                StopReason::Undefined { pc, opcd }
+            },
+            RuntimeExitCode::InvalidMode => {
+                let opcd = self.opcd_at(pc);
+                return Err(Error::new(pc, opcd, ErrorKind::InvalidMode));
+            },
+        };
+        Ok(Some(reason))
+    }
+
+    /// Interpret the instruction at the program counter.
+    fn interpret(&mut self, pc: u32) -> Result<RuntimeExitCode> {
+        let opcd = self.opcd_at(pc);
+        debug!(self, "[*] Interpreting {:08x}: {:08x}", pc, opcd);
+        let res = interp::step(&mut self.state, &mut self.mmu, opcd)?;
+        self.ctx.cycles -= self.timing.cost(opcd) as isize;
+
+        // Writes to CP15 may change address translation
+        if res == RuntimeExitCode::NextBlock 
+            && ArmInst::decode(opcd) == ArmInst::Mcr 
+        {
+            self.flush();
         }
+        Ok(res)
+    }
+
+    /// Discard all cached blocks containing code from pages which have been 
+    /// written since they were recompiled.
+    fn invalidate_dirty_pages(&mut self) {
+        for page in self.mmu.take_dirty_pages() {
+            self.invalidate_page(page);
+        }
+    }
 
+    /// Discard all cached blocks containing code from a physical page.
+    fn invalidate_page(&mut self, page: u32) {
+        if let Some(pcs) = self.pages.remove(&page) {
+            for pc in pcs {
+                self.invalidate_block(pc);
+            }
+        }
     }
+
+    /// Link a newly-cached block to its successors, and link any exits from
+    /// other blocks which branch to it.
+    ///
+    /// Blocks at a breakpoint are never entered from recompiled code, since 
+    /// the dispatcher needs to stop there.
+    fn link_block(&mut self, pc: u32) {
+        let bb = self.cache.get(&pc).unwrap();
+        for exit in bb.exits.iter() {
+            if let Some(target) = self.cache.get(&exit.target) {
+                if !self.breakpoints.contains(&exit.target) {
+                    exit.link(target.entrypoint());
+                }
+            }
+            self.links.entry(exit.target).or_default()
+                .push((pc, *exit));
+        }
+        if self.breakpoints.contains(&pc) {
+            return;
+        }
+        self.branch_cache.insert(pc, bb.entrypoint());
+        if let Some(exits) = self.links.get(&pc) {
+            for (_, exit) in exits.iter() {
+                exit.link(bb.entrypoint());
+            }
+        }
+    }
+
+    /// Discard a cached block.
+    ///
+    /// All invalidation goes through here, so that nothing is left with a 
+    /// reference to the discarded block.
+    fn invalidate_block(&mut self, pc: u32) {
+        let bb = match self.cache.remove(&pc) {
+            Some(bb) => bb,
+            None => return,
+        };
+        self.branch_cache.remove(pc);
+        // Exits from other blocks must return to the dispatcher instead
+        if let Some(exits) = self.links.get(&pc) {
+            for (_, exit) in exits.iter() {
+                exit.unlink();
+            }
+        }
+        // Forget about exits from this block
+        for exit in bb.exits.iter() {
+            if let Some(exits) = self.links.get_mut(&exit.target) {
+                exits.retain(|(src, _)| *src != pc);
+            }
+        }
+        // Forget about this block on any other pages
+        for page in bb.code_pages.iter() {
+            if let Some(pcs) = self.pages.get_mut(page) {
+                pcs.retain(|x| *x != pc);
+            }
+        }
+        debug!(self, "[*] Invalidated block {:08x}", pc);
+    }
+
+    /// Take an exception caused by the instruction at the program counter
+    /// (for instance, after [StopReason::Svc] or [StopReason::Undefined]).
+    pub fn raise_exception(&mut self, kind: ExceptionType) {
+        let pc = self.state.pc.fetch();
+        let lr = match kind {
+            ExceptionType::DataAbort => pc.wrapping_add(8),
+            _ => pc.wrapping_add(4),
+        };
+        self.take_exception(kind, lr);
+    }
+
+    /// Read the opcode of a guest instruction which has already been 
+    /// fetched by the lifter.
+    fn opcd_at(&mut self, pc: u32) -> u32 {
+        let privileged = self.state.cpsr.is_privileged();
+        self.mmu.fetch32(pc, privileged).unwrap_or(0)
+    }
+
+    /// If the condition for an instruction fails, skip over it and return 
+    /// true.
+    fn skip_if_failed(&mut self, opcd: u32) -> bool {
+        // Instructions in the unconditional space always execute
+        match Cond::try_from(opcd >> 28) {
+            Ok(cond) if !cond.passes(self.state.cpsr) => {},
+            _ => return false,
+        }
+        self.state.pc.increment();
+        true
+    }
+
+    /// Take an exception, where `lr` is the value written to the link 
+    /// register in the new mode.
+    fn take_exception(&mut self, kind: ExceptionType, lr: u32) {
+        let vector_base = self.mmu.vmsa.vector_base();
+        self.state.enter_exception(kind, lr, vector_base);
+    }
+}
+impl Default for Jit {
+    fn default() -> Self { Jit::new() }
 }
 
 
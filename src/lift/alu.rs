@@ -2,6 +2,7 @@
 
 use crate::block::*;
 use crate::ir::*;
+use crate::error::{ ErrorKind, Result };
 
 pub enum ShiftType { Lsl = 0b00, Lsr = 0b01, Asr = 0b10, Ror = 0b11 }
 impl From<u32> for ShiftType {
@@ -26,15 +27,16 @@ pub enum ShiftArgs {
 /// Perform some barrel-shifter operation.
 ///
 /// Returns a tuple containing the output value and output carry flag.
-pub fn barrel_shift(bb: &mut BasicBlock, args: ShiftArgs) -> (Var, Var) {
+pub fn barrel_shift(bb: &mut BasicBlock, args: ShiftArgs) 
+    -> Result<(Var, Var)> {
     match args {
         ShiftArgs::Imm { imm12 } => {
-            rot_by_imm(bb, imm12)
+            Ok(rot_by_imm(bb, imm12))
         },
         ShiftArgs::Reg { rm, stype, imm5 } => {
             shift_by_imm(bb, rm, stype, imm5)
         },
-        _ => unimplemented!(),
+        _ => Err(bb.error(ErrorKind::Unsupported("register-shifted register"))),
     }
 }
 
@@ -52,12 +54,11 @@ pub fn rot_by_imm(bb: &mut BasicBlock, imm12: u32) -> (Var, Var) {
 }
 
 pub fn shift_by_imm(bb: &mut BasicBlock, rm: Var, stype: u32, simm: u32) 
-    -> (Var, Var) {
+    -> Result<(Var, Var)> {
     match ShiftType::from(stype) {
-        ShiftType::Lsl => do_lsl(bb, rm, simm),
-        _ => unimplemented!(),
+        ShiftType::Lsl => Ok(do_lsl(bb, rm, simm)),
+        _ => Err(bb.error(ErrorKind::Unsupported("shift type"))),
     }
-
 }
 
 pub fn do_lsl(bb: &mut BasicBlock, rm: Var, simm: u32) -> (Var, Var) {
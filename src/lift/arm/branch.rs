@@ -2,6 +2,7 @@
 use crate::guest::Cond;
 use crate::lift::arm::bits::*;
 use crate::block::*;
+use crate::error::{ ErrorKind, Result };
 
 pub fn sign_extend(x: u32, bits: i32) -> i32 {
     if ((x as i32 >> (bits - 1)) & 1) != 0 { 
@@ -11,12 +12,12 @@ pub fn sign_extend(x: u32, bits: i32) -> i32 {
     }
 }
 
-pub fn b(bb: &mut BasicBlock, op: BranchBits) {
+pub fn b(bb: &mut BasicBlock, op: BranchBits) -> Result<()> {
     let offset = sign_extend(op.imm24(), 24) * 4;
     let target_val = (bb.read_exec_pc() as i32).wrapping_add(offset) as u32;
     let target = bb.constant(32, target_val as usize);
 
-    let cond = Cond::from(op.cond());
+    let cond = bb.cond(op.cond())?;
     if cond == Cond::AL {
         bb.terminate(BlockLink::Branch(target));
     } else {
@@ -26,10 +27,29 @@ pub fn b(bb: &mut BasicBlock, op: BranchBits) {
         );
         bb.terminate(BlockLink::BranchCond(cond, target, target_false));
     }
+    Ok(())
 }
 
-pub fn bl_imm(bb: &mut BasicBlock, op: BranchBits) {
-    assert_eq!(Cond::from(op.cond()), Cond::AL);
+/// Branch to a register.
+///
+/// NOTE: We don't support Thumb, so the target isn't checked for interworking
+/// here (the runtime refuses to execute a block at an odd address).
+pub fn bx(bb: &mut BasicBlock, op: BxBits) -> Result<()> {
+    bb.require_al(op.cond())?;
+    if op.rm() == 15 {
+        return Err(bb.error(ErrorKind::Unsupported("bx pc")));
+    }
+    let target = bb.read_reg(op.rm());
+    if op.rm() == 14 {
+        bb.terminate(BlockLink::Return(target));
+    } else {
+        bb.terminate(BlockLink::Branch(target));
+    }
+    Ok(())
+}
+
+pub fn bl_imm(bb: &mut BasicBlock, op: BranchBits) -> Result<()> {
+    bb.require_al(op.cond())?;
     let offset = sign_extend(op.imm24(), 24) * 4;
 
     let lr_val = bb.read_fetch_pc().wrapping_add(4);
@@ -40,4 +60,5 @@ pub fn bl_imm(bb: &mut BasicBlock, op: BranchBits) {
 
     //bb.write_reg(14, new_lr);
     bb.terminate(BlockLink::BranchAndLink(target, new_lr));
+    Ok(())
 }
@@ -3,11 +3,14 @@ use crate::lift::arm::bits::*;
 use crate::lift::alu::*;
 use crate::ir::*;
 use crate::block::*;
-use crate::guest::Cond;
+use crate::error::{ ErrorKind, Result };
 
-pub fn sub_imm(bb: &mut BasicBlock, op: DpImmBits) {
-    assert_eq!(Cond::from(op.cond()), Cond::AL);
-    let (imm, _) = barrel_shift(bb, ShiftArgs::Imm { imm12: op.imm12() });
+pub fn sub_imm(bb: &mut BasicBlock, op: DpImmBits) -> Result<()> {
+    bb.require_al(op.cond())?;
+    if op.rd() == 15 {
+        return Err(bb.error(ErrorKind::Unsupported("sub to pc")));
+    }
+    let (imm, _) = barrel_shift(bb, ShiftArgs::Imm { imm12: op.imm12() })?;
 
     let rn = if op.rn() == 15 {
         bb.constant(32, bb.read_exec_pc() as usize)
@@ -15,43 +18,33 @@ pub fn sub_imm(bb: &mut BasicBlock, op: DpImmBits) {
         bb.read_reg(op.rn())
     };
 
-    let (res, c, v) = bb.sub32f(rn, imm);
-
-    if op.rd() == 15 {
-        unimplemented!();
-    } else {
-        if op.s() {
-            let n = bb.is_negative(res);
-            let z = bb.is_zero(res);
-            bb.write_flag(FlagKind::Negative, n);
-            bb.write_flag(FlagKind::Zero, z);
-            bb.write_flag(FlagKind::Carry, c);
-            bb.write_flag(FlagKind::Overflow, v);
-        }
-        bb.write_reg(op.rd(), res);
+    let res = bb.sub32(rn, imm);
+    if op.s() {
+        bb.set_flags(FlagOp::Sub, rn, imm);
     }
+    bb.write_reg(op.rd(), res);
+    Ok(())
 }
 
-pub fn mov_imm(bb: &mut BasicBlock, op: MovImmBits) {
-    assert_eq!(Cond::from(op.cond()), Cond::AL);
-    let (imm, c_out) = barrel_shift(bb, ShiftArgs::Imm { imm12: op.imm12() });
-
+pub fn mov_imm(bb: &mut BasicBlock, op: MovImmBits) -> Result<()> {
+    bb.require_al(op.cond())?;
     if op.rd() == 15 {
-        unimplemented!();
-    } else {
-        bb.write_reg(op.rd(), imm);
-        if op.s() {
-            let n = bb.is_negative(imm);
-            let z = bb.is_zero(imm);
-            bb.write_flag(FlagKind::Negative, n);
-            bb.write_flag(FlagKind::Zero, z);
-            bb.write_flag(FlagKind::Carry, c_out);
-        }
+        return Err(bb.error(ErrorKind::Unsupported("mov to pc")));
     }
+    let (imm, c_out) = barrel_shift(bb, ShiftArgs::Imm { imm12: op.imm12() })?;
+
+    bb.write_reg(op.rd(), imm);
+    if op.s() {
+        bb.set_flags(FlagOp::Logical, imm, c_out);
+    }
+    Ok(())
 }
 
-pub fn mov_reg(bb: &mut BasicBlock, op: MovRegBits) {
-    assert_eq!(Cond::from(op.cond()), Cond::AL);
+pub fn mov_reg(bb: &mut BasicBlock, op: MovRegBits) -> Result<()> {
+    bb.require_al(op.cond())?;
+    if op.rd() == 15 && op.s() {
+        return Err(bb.error(ErrorKind::Unsupported("movs to pc")));
+    }
     let rm = if op.rm() == 15 {
         bb.constant(32, bb.read_exec_pc() as usize)
     } else {
@@ -59,34 +52,31 @@ pub fn mov_reg(bb: &mut BasicBlock, op: MovRegBits) {
     };
     let (res, c) = barrel_shift(bb, ShiftArgs::Reg {
         rm, stype: op.stype(), imm5: op.imm5()
-    });
+    })?;
 
     if op.rd() == 15 {
-        unimplemented!();
+        // "mov pc, lr" is the usual return from a subroutine
+        if op.rm() == 14 {
+            bb.terminate(BlockLink::Return(res));
+        } else {
+            bb.terminate(BlockLink::Branch(res));
+        }
     } else {
         bb.write_reg(op.rd(), res);
         if op.s() {
-            let n = bb.is_negative(res);
-            let z = bb.is_zero(res);
-            bb.write_flag(FlagKind::Negative, n);
-            bb.write_flag(FlagKind::Zero, z);
-            bb.write_flag(FlagKind::Carry, c);
+            bb.set_flags(FlagOp::Logical, res, c);
         }
     }
+    Ok(())
 }
 
-pub fn cmp_imm(bb: &mut BasicBlock, op: DpTestImmBits) {
-    assert_eq!(Cond::from(op.cond()), Cond::AL);
+pub fn cmp_imm(bb: &mut BasicBlock, op: DpTestImmBits) -> Result<()> {
+    bb.require_al(op.cond())?;
     let rn = bb.read_reg(op.rn());
-    let (imm, _) = barrel_shift(bb, ShiftArgs::Imm { imm12: op.imm12() });
+    let (imm, _) = barrel_shift(bb, ShiftArgs::Imm { imm12: op.imm12() })?;
 
-    let (res, c, v) = bb.sub32f(rn, imm);
-    let n = bb.is_negative(res);
-    let z = bb.is_zero(res);
-    bb.write_flag(FlagKind::Negative, n);
-    bb.write_flag(FlagKind::Zero, z);
-    bb.write_flag(FlagKind::Carry, c);
-    bb.write_flag(FlagKind::Overflow, v);
+    bb.set_flags(FlagOp::Sub, rn, imm);
+    Ok(())
 }
 
 
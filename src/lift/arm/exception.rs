@@ -0,0 +1,26 @@
+
+use crate::lift::arm::bits::*;
+use crate::block::*;
+use crate::runtime::RuntimeExitCode;
+use crate::error::Result;
+
+// NOTE: These instructions always end a block and return to the dispatcher,
+// which decides how to handle them (the condition is checked there).
+
+pub fn svc(bb: &mut BasicBlock, _op: BranchBits) -> Result<()> {
+    let pc = bb.read_fetch_pc();
+    bb.terminate(BlockLink::Exit(RuntimeExitCode::Svc, pc));
+    Ok(())
+}
+
+pub fn bkpt(bb: &mut BasicBlock, _op: BkptBits) -> Result<()> {
+    let pc = bb.read_fetch_pc();
+    bb.terminate(BlockLink::Exit(RuntimeExitCode::Breakpoint, pc));
+    Ok(())
+}
+
+pub fn undefined(bb: &mut BasicBlock, _op: u32) -> Result<()> {
+    let pc = bb.read_fetch_pc();
+    bb.terminate(BlockLink::Exit(RuntimeExitCode::Undefined, pc));
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use crate::lift::arm::bits::*;
 use crate::ir::*;
 use crate::block::*;
-use crate::guest::Cond;
+use crate::error::{ ErrorKind, Result };
 
 /// Compute an address (literal addressing mode).
 pub fn amode_lit(pc: u32, imm: u32, p: bool, u: bool) -> u32 {
@@ -13,18 +13,23 @@ pub fn amode_lit(pc: u32, imm: u32, p: bool, u: bool) -> u32 {
 }
 
 pub fn amode(bb: &mut BasicBlock, 
-    rn: Var, imm: Var, u: bool, p: bool, w: bool) -> (Var, Var) {
+    rn: Var, imm: Var, u: bool, p: bool, w: bool) -> Result<(Var, Var)> {
+    if !p && w {
+        return Err(bb.error(ErrorKind::Unsupported("addressing mode")));
+    }
     let res = if u { bb.add32(rn, imm) } else { bb.sub32(rn, imm) };
-    match (p, w) {
+    Ok(match (p, w) {
         (false, false) => (rn, res),
         (true, false) => (res, rn),
-        (true, true) => (res, res),
-        (false, true) => panic!("Unsupported addressing mode"),
-    }
+        _ => (res, res),
+    })
 }
 
-pub fn ldr_imm(bb: &mut BasicBlock, op: LsImmBits) {
-    assert_eq!(Cond::from(op.cond()), Cond::AL);
+pub fn ldr_imm(bb: &mut BasicBlock, op: LsImmBits) -> Result<()> {
+    bb.require_al(op.cond())?;
+    if op.rt() == 15 {
+        return Err(bb.error(ErrorKind::Unsupported("load to pc")));
+    }
     let res = if op.rn() == 15 {
         let addr_val = amode_lit(
             bb.read_exec_pc(), op.imm12(), op.p(), op.u()
@@ -34,32 +39,31 @@ pub fn ldr_imm(bb: &mut BasicBlock, op: LsImmBits) {
     } else {
         let rn = bb.read_reg(op.rn());
         let imm = bb.constant(32, op.imm12() as usize);
-        let (addr, wb_addr) = amode(bb, rn, imm, op.u(), op.p(), op.w());
+        let (addr, wb_addr) = amode(bb, rn, imm, op.u(), op.p(), op.w())?;
         bb.write_reg(op.rn(), wb_addr);
         bb.load32(addr)
     };
-        
-    if op.rt() == 15 {
-        panic!("");
-    } else {
-        bb.write_reg(op.rt(), res);
-    }
+    bb.write_reg(op.rt(), res);
+    Ok(())
 }
 
-pub fn str_imm(bb: &mut BasicBlock, op: LsImmBits) {
-    assert_eq!(Cond::from(op.cond()), Cond::AL);
+pub fn str_imm(bb: &mut BasicBlock, op: LsImmBits) -> Result<()> {
+    bb.require_al(op.cond())?;
     let rt = bb.read_reg(op.rt());
     let rn = bb.read_reg(op.rn());
     let imm = bb.constant(32, op.imm12() as usize);
 
-    let (addr, wb_addr) = amode(bb, rn, imm, op.u(), op.p(), op.w());
+    let (addr, wb_addr) = amode(bb, rn, imm, op.u(), op.p(), op.w())?;
     bb.write_reg(op.rn(), wb_addr);
     bb.store32(addr, rt);
+    Ok(())
 }
 
-pub fn stmdb(bb: &mut BasicBlock, op: LsMultiBits) {
-    assert_eq!(Cond::from(op.cond()), Cond::AL);
-    assert_ne!(op.rn(), 15);
+pub fn stmdb(bb: &mut BasicBlock, op: LsMultiBits) -> Result<()> {
+    bb.require_al(op.cond())?;
+    if op.rn() == 15 {
+        return Err(bb.error(ErrorKind::Unsupported("stm with rn=pc")));
+    }
 
     let reglist = op.register_list();
     let num_regs = reglist.count_ones() as usize;
@@ -69,6 +73,7 @@ pub fn stmdb(bb: &mut BasicBlock, op: LsMultiBits) {
     let wb_addr = base_addr;
 
     stm_common(bb, reglist, op.rn(), base_addr, wb_addr, op.w());
+    Ok(())
 }
 
 pub fn stm_common(bb: &mut BasicBlock, 
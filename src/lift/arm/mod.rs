@@ -5,3 +5,5 @@ pub mod bits;
 pub mod loadstore;
 pub mod branch;
 pub mod dataproc;
+pub mod status;
+pub mod exception;
@@ -0,0 +1,38 @@
+use crate::lift::arm::bits::*;
+use crate::block::*;
+use crate::error::{ ErrorKind, Result };
+
+/// Convert the field mask in an MSR instruction into a mask of PSR bits.
+pub fn field_mask(mask: u32) -> u32 {
+    let mut res = 0;
+    for i in 0..4 {
+        if (mask & (1 << i)) != 0 {
+            res |= 0xff << (i * 8);
+        }
+    }
+    res
+}
+
+pub fn msr_imm(bb: &mut BasicBlock, op: MsrImmBits) -> Result<()> {
+    bb.require_al(op.cond())?;
+    if op.r() {
+        return Err(bb.error(ErrorKind::Unsupported("msr to spsr")));
+    }
+    let (simm, imm8) = ((op.imm12() & 0xf00) >> 8, op.imm12() & 0xff);
+    let val = bb.constant(32, imm8.rotate_right(simm * 2) as usize);
+    bb.write_cpsr(val, field_mask(op.mask()));
+    Ok(())
+}
+
+pub fn msr_reg(bb: &mut BasicBlock, op: MsrRegBits) -> Result<()> {
+    bb.require_al(op.cond())?;
+    if op.r() {
+        return Err(bb.error(ErrorKind::Unsupported("msr to spsr")));
+    }
+    if op.rn() == 15 {
+        return Err(bb.error(ErrorKind::Unsupported("msr from pc")));
+    }
+    let val = bb.read_reg(op.rn());
+    bb.write_cpsr(val, field_mask(op.mask()));
+    Ok(())
+}
@@ -1,6 +1,6 @@
 
 /// Enumerated type describing different kinds of ARM instruction encodings.
-#[derive(Clone,Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ArmInst {
     AndRegShiftReg, AdcRegShiftReg, MovRegShiftReg, OrrRegShiftReg,
     EorRegShiftReg, RscRegShiftReg, MvnRegShiftReg, SbcRegShiftReg,
@@ -177,9 +177,8 @@ impl ArmInst {
             0x09800000 => return Stmib,
             _ => {},
         }
-        match opcd & 0x0fb00000 {
-            0x03200000 => return MsrImm,
-            _ => {},
+        if opcd & 0x0fb00000 == 0x03200000 {
+            return MsrImm;
         }
         match opcd & 0x0e500010 {
             0x06100000 => return LdrReg,
@@ -315,13 +314,11 @@ impl ThumbInst {
             0x4800 => return LdrLit,
             _ => {},
         }
-        match opcd & 0xf000 {
-            0xd000 => return B,
-            _ => {},
+        if opcd & 0xf000 == 0xd000 {
+            return B;
         }
-        match opcd & 0xe000 {
-            0x0000 => return MovRegAlt,
-            _ => {},
+        if opcd & 0xe000 == 0x0000 {
+            return MovRegAlt;
         }
         Undefined
     }
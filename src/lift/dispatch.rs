@@ -1,28 +1,27 @@
 
 use crate::lift::decode::*;
-use crate::ir::*;
 use crate::block::*;
+use crate::error::{ ErrorKind, Result };
 
 use crate::lift::arm;
 //use crate::lift::thumb;
 
 /// A function pointer to an ARM instruction implementation.
 #[derive(Clone, Copy)]
-pub struct ArmFn(pub fn(&mut BasicBlock, u32));
+pub struct ArmFn(pub fn(&mut BasicBlock, u32) -> Result<()>);
 
 /// A function pointer to a Thumb instruction implementation.
 #[derive(Clone, Copy)]
-pub struct ThumbFn(pub fn(&mut BasicBlock, u16));
+pub struct ThumbFn(pub fn(&mut BasicBlock, u16) -> Result<()>);
 
 /// Handler for unimplemented ARM instructions.
-pub fn arm_unimpl_instr(_bb: &mut BasicBlock, op: u32) {
-    println!("Unimpl {:08x} {:?}", op, ArmInst::decode(op));
-    panic!("Unimplemented ARM instruction");
+pub fn arm_unimpl_instr(bb: &mut BasicBlock, op: u32) -> Result<()> {
+    Err(bb.error(ErrorKind::Unimplemented(ArmInst::decode(op))))
 }
 
 /// Handler for unimplemented Thumb instructions.
-pub fn thumb_unimpl_instr(_bb: &mut BasicBlock, _op: u16) {
-    panic!("Unimplemented Thumb instruction");
+pub fn thumb_unimpl_instr(bb: &mut BasicBlock, _op: u16) -> Result<()> {
+    Err(bb.error(ErrorKind::Unsupported("Thumb instruction")))
 }
 
 
@@ -31,14 +30,14 @@ impl ArmFn {
     pub const fn from_inst(inst: ArmInst) -> Self {
 
         macro_rules! afn { ($func:expr) => { unsafe {
-            std::mem::transmute::<*const fn(), fn(&mut BasicBlock, u32)>
+            std::mem::transmute::<*const fn(), fn(&mut BasicBlock, u32) -> Result<()>>
                 ($func as *const fn())
         }}}
 
         use ArmInst::*;
         match inst {
-            MsrImm          => ArmFn(afn!(arm_unimpl_instr)),
-            MsrReg          => ArmFn(afn!(arm_unimpl_instr)),
+            MsrImm          => ArmFn(afn!(arm::status::msr_imm)),
+            MsrReg          => ArmFn(afn!(arm::status::msr_reg)),
             Mrs             => ArmFn(afn!(arm_unimpl_instr)),
             Umull           => ArmFn(afn!(arm_unimpl_instr)),
             Mul             => ArmFn(afn!(arm_unimpl_instr)),
@@ -65,8 +64,12 @@ impl ArmFn {
             Mcr             => ArmFn(afn!(arm_unimpl_instr)),
             Mrc             => ArmFn(afn!(arm_unimpl_instr)),
 
+            Svc             => ArmFn(afn!(arm::exception::svc)),
+            Bkpt            => ArmFn(afn!(arm::exception::bkpt)),
+            Undefined       => ArmFn(afn!(arm::exception::undefined)),
+
             B               => ArmFn(afn!(arm::branch::b)),
-            Bx              => ArmFn(afn!(arm_unimpl_instr)),
+            Bx              => ArmFn(afn!(arm::branch::bx)),
             BlImm           => ArmFn(afn!(arm::branch::bl_imm)),
 
             RsbImm          => ArmFn(afn!(arm_unimpl_instr)),
@@ -104,7 +107,7 @@ impl ThumbFn {
     pub const fn from_inst(inst: ThumbInst) -> Self {
 
         macro_rules! tfn { ($func:expr) => { unsafe {
-            std::mem::transmute::<*const fn(), fn(&mut BasicBlock, u16)>
+            std::mem::transmute::<*const fn(), fn(&mut BasicBlock, u16) -> Result<()>>
                 ($func as *const fn())
         }}}
 
@@ -2,18 +2,18 @@
 
 use std::ffi::CString;
 use std::convert::TryInto;
+use std::sync::atomic::{ AtomicUsize, Ordering };
 
 extern crate libc;
 use libc::{
-    shm_open, shm_unlink, mmap, ftruncate,
+    shm_open, shm_unlink, mmap, munmap, mprotect, ftruncate, close, dup,
     O_CREAT, O_RDWR, O_EXCL,
-    MAP_SHARED, MAP_FIXED, MAP_FAILED,
-    PROT_READ, PROT_WRITE,PROT_EXEC,
+    MAP_SHARED, MAP_FIXED, MAP_FAILED, MAP_PRIVATE, MAP_ANONYMOUS, MAP_NORESERVE,
+    PROT_READ, PROT_WRITE, PROT_NONE,
     c_void, c_char,
 };
 
 
-#[allow(dead_code)]
 pub struct MemRegion {
     /// Pointer to this memory region.
     pub ptr: &'static mut [u8],
@@ -21,26 +21,123 @@ pub struct MemRegion {
     pub addr: u32,
     /// Length of the memory region.
     pub len: usize,
+    /// Guest access permissions for this memory region.
+    pub perm: Permissions,
     /// Associated file descriptor.
     fd: i32,
 }
 
-pub const ARENA_BASE: usize = 0x0000_1337_0000_0000;
+/// Guest access permissions for a memory region (all regions are readable).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Permissions {
+    /// The guest may write to this region.
+    pub write: bool,
+    /// The guest may execute code from this region.
+    pub exec: bool,
+}
+impl Permissions {
+    pub const RWX: Permissions = Permissions { write: true, exec: true };
+    pub const RW: Permissions = Permissions { write: true, exec: false };
+    pub const RX: Permissions = Permissions { write: false, exec: true };
+    pub const R: Permissions = Permissions { write: false, exec: false };
+
+    /// Protection bits for the host mapping of a region.
+    ///
+    /// Read-only regions are mapped read-only on the host, so that guest 
+    /// stores from recompiled code fault (see [crate::fastmem]).
+    fn prot(self) -> i32 {
+        if self.write { PROT_READ | PROT_WRITE } else { PROT_READ }
+    }
+}
+
+/// Byte order of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness { Little, Big }
+
+/// The size of the fast memory arena (the 32-bit guest address space, plus a
+/// guard page for accesses which straddle the end of the address space).
+pub const ARENA_SIZE: usize = 0x1_0000_0000 + 0x1000;
+
+/// Counter used to give each shared memory object a unique name.
+static SHM_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The "fast memory" arena, embedding a guest's 32-bit address space in the 
+/// virtual address space of the host.
+///
+/// The arena is reserved at whatever address the host kernel gives us, and
+/// the whole arena is initially mapped as inaccessible; a [MemRegion] 
+/// replaces part of this mapping. Any access to a page which isn't backed by
+/// a [MemRegion] causes a fault (see [crate::fastmem]).
+pub struct Arena {
+    /// Base address of the arena on the host.
+    pub base: usize,
+}
+impl Arena {
+    pub fn new() -> Self {
+        let res = unsafe {
+            mmap(std::ptr::null_mut(), ARENA_SIZE, PROT_NONE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0
+            )
+        };
+        if res == MAP_FAILED { panic!("Couldn't reserve fast memory arena"); }
+        Arena { base: res as usize }
+    }
+
+    /// Check that the guest physical range `[addr, addr + len)` is inside 
+    /// the 32-bit address space (so that mapping it can't clobber host 
+    /// memory outside of the arena).
+    fn check_bounds(addr: u32, len: usize) {
+        assert!(addr as usize + len <= 0x1_0000_0000, 
+            "Region {:08x} ({:x} bytes) is out of bounds", addr, len);
+    }
+}
+impl Default for Arena {
+    fn default() -> Self { Arena::new() }
+}
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { munmap(self.base as *mut c_void, ARENA_SIZE); }
+    }
+}
+
 impl MemRegion {
-    /// Create a new memory region.
-    pub fn new(name: &str, addr: u32, len: usize) -> Self {
-        let address = ARENA_BASE + addr as usize;
-        let name = CString::new(name).unwrap();
+    /// Create a new memory region in some arena.
+    ///
+    /// The region is mapped over whatever was at the same address in the 
+    /// arena, so callers are responsible for checking that regions don't 
+    /// overlap.
+    pub fn new(arena: &Arena, name: &str, addr: u32, len: usize, 
+        perm: Permissions) -> Self {
+        Arena::check_bounds(addr, len);
+        let address = arena.base + addr as usize;
+        let name = CString::new(format!("/nil-{}-{}-{}", 
+            std::process::id(), SHM_ID.fetch_add(1, Ordering::Relaxed), name
+        )).unwrap();
         let fd = unsafe { MemRegion::create_shm(name.as_ptr(), len) };
-        let ptr = unsafe { MemRegion::mmap(fd, address, len) };
+        let ptr = unsafe { MemRegion::mmap(fd, address, len, perm) };
         MemRegion {
-            ptr, addr, len, fd
+            ptr, addr, len, perm, fd
+        }
+    }
+
+    /// Create a mirror of this memory region at a different guest address.
+    ///
+    /// Both regions share the same backing memory, so writes through one are
+    /// visible through the other.
+    pub fn mirror(&self, arena: &Arena, addr: u32) -> Self {
+        Arena::check_bounds(addr, self.len);
+        let address = arena.base + addr as usize;
+        let fd = unsafe { dup(self.fd) };
+        if fd < 0 { panic!("dup() failed for region at {:08x}", self.addr); }
+        let ptr = unsafe { MemRegion::mmap(fd, address, self.len, self.perm) };
+        MemRegion {
+            ptr, addr, len: self.len, perm: self.perm, fd
         }
     }
 
     unsafe fn create_shm(name: *const c_char, len: usize) -> i32 {
         let fd = shm_open(name, O_RDWR | O_CREAT | O_EXCL, 0o600);
-        if fd == 1 {
+        if fd < 0 {
             panic!("shm_open for object {:?} failed", name);
         } else {
             shm_unlink(name);
@@ -52,30 +149,80 @@ impl MemRegion {
         }
     }
 
-    unsafe fn mmap(shm_fd: i32, vaddr: usize, len: usize) -> &'static mut [u8] {
+    unsafe fn mmap(shm_fd: i32, vaddr: usize, len: usize, perm: Permissions) 
+        -> &'static mut [u8] 
+    {
         let addr = vaddr as *mut c_void;
-        let res = mmap(addr, len, 
-            PROT_READ | PROT_WRITE | PROT_EXEC, 
-            MAP_FIXED | MAP_SHARED, shm_fd, 0
-        );
+        let res = mmap(addr, len, perm.prot(), MAP_FIXED | MAP_SHARED, shm_fd, 0);
         if res == MAP_FAILED { panic!("mmap() failed {:?}", addr); }
         std::slice::from_raw_parts_mut(res as *mut u8, len)
     }
 
 }
 
+impl Drop for MemRegion {
+    fn drop(&mut self) {
+        unsafe { close(self.fd); }
+    }
+}
+
 impl MemRegion {
+    /// Change whether or not the host can write to the pages in the range
+    /// `[off, off + len)` (i.e. when tracking pages containing guest code). 
+    pub fn protect(&mut self, off: usize, len: usize, writable: bool) {
+        assert!(off & 0xfff == 0 && off + len <= self.len);
+        let ptr = unsafe { self.ptr.as_mut_ptr().add(off) as *mut c_void };
+        let prot = if writable { self.perm.prot() } else { PROT_READ };
+        if unsafe { mprotect(ptr, len, prot) } != 0 {
+            panic!("mprotect() failed for {:08x}", self.addr as usize + off);
+        }
+    }
+
+    /// Write a buffer into this region (ignoring guest permissions).
     pub fn write_buf(&mut self, off: u32, buf: &[u8]) {
         let off = off as usize;
+        let ptr = self.ptr.as_mut_ptr() as *mut c_void;
+        if !self.perm.write {
+            unsafe { mprotect(ptr, self.len, PROT_READ | PROT_WRITE); }
+        }
         self.ptr[off..off + buf.len()].copy_from_slice(buf);
+        if !self.perm.write {
+            unsafe { mprotect(ptr, self.len, self.perm.prot()); }
+        }
+    }
+    pub fn write8(&mut self, off: usize, val: u8) {
+        self.ptr[off] = val;
+    }
+    pub fn write16(&mut self, off: usize, val: u16, e: Endianness) {
+        let bytes = match e {
+            Endianness::Little => val.to_le_bytes(),
+            Endianness::Big => val.to_be_bytes(),
+        };
+        self.ptr[off..off + 2].copy_from_slice(&bytes);
     }
-    pub fn read32(&self, off: usize) -> u32 {
-        u32::from_be_bytes(self.ptr[off..off + 4].try_into().unwrap())
+    pub fn write32(&mut self, off: usize, val: u32, e: Endianness) {
+        let bytes = match e {
+            Endianness::Little => val.to_le_bytes(),
+            Endianness::Big => val.to_be_bytes(),
+        };
+        self.ptr[off..off + 4].copy_from_slice(&bytes);
     }
-    pub fn read16(&self, off: usize) -> u16 {
-        u16::from_be_bytes(self.ptr[off..off + 2].try_into().unwrap())
+    pub fn read32(&self, off: usize, e: Endianness) -> u32 {
+        let bytes = self.ptr[off..off + 4].try_into().unwrap();
+        match e {
+            Endianness::Little => u32::from_le_bytes(bytes),
+            Endianness::Big => u32::from_be_bytes(bytes),
+        }
+    }
+    pub fn read16(&self, off: usize, e: Endianness) -> u16 {
+        let bytes = self.ptr[off..off + 2].try_into().unwrap();
+        match e {
+            Endianness::Little => u16::from_le_bytes(bytes),
+            Endianness::Big => u16::from_be_bytes(bytes),
+        }
     }
     pub fn read8(&self, off: usize) -> u8 {
         self.ptr[off]
     }
 }
+
@@ -0,0 +1,371 @@
+//! ARMv5 virtual memory system architecture (address translation).
+//!
+//! # Translation Tables
+//! When the MMU is enabled, a virtual address is translated by walking a
+//! two-level set of tables in guest physical memory:
+//!
+//! - The first-level table (pointed to by the TTBR) has 4096 entries, each
+//!   describing 1MiB of the virtual address space. An entry is either a
+//!   fault, a *section* (mapping 1MiB directly), or a pointer to a *coarse*
+//!   or *fine* second-level table.
+//! - A coarse table has 256 entries (4KiB granularity), and a fine table has
+//!   1024 entries (1KiB granularity). Each entry is either a fault, a *large*
+//!   page (64KiB), a *small* page (4KiB), or a *tiny* page (1KiB, only valid
+//!   in fine tables).
+//!
+//! # Permissions
+//! Every section and page is associated with one of sixteen domains. The
+//! domain access control register (DACR) decides whether accesses to a
+//! domain are checked against the access permission (AP) bits in the
+//! descriptor ("client"), or are always allowed ("manager").
+//!
+//! # TLB
+//! Walking the tables on every access is expensive, so successful walks are
+//! cached in a small direct-mapped software TLB. Entries only cache the
+//! result of the walk; domain and permission checks are still performed on
+//! every hit (they are cheap, and this means that we don't need to flush the
+//! TLB when the DACR changes).
+
+/// The kind of memory access being translated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access { Read, Write, Fetch }
+
+/// Fault status codes (the low four bits of the FSR).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultStatus {
+    Alignment           = 0b0001,
+    TranslationSection  = 0b0101,
+    TranslationPage     = 0b0111,
+    DomainSection       = 0b1001,
+    DomainPage          = 0b1011,
+    PermissionSection   = 0b1101,
+    PermissionPage      = 0b1111,
+    /// An abort from outside of the MMU (i.e. a write to ROM, or an 
+    /// instruction fetch from a region without execute permission).
+    External            = 0b1000,
+    /// An external abort while reading a first-level descriptor (i.e. when
+    /// the table is in unmapped memory).
+    ExternalSection     = 0b1100,
+    /// An external abort while reading a second-level descriptor.
+    ExternalPage        = 0b1110,
+}
+
+/// A fault raised during address translation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fault {
+    /// The kind of fault.
+    pub status: FaultStatus,
+    /// The domain associated with the faulting descriptor.
+    pub domain: u32,
+    /// The faulting virtual address.
+    pub addr: u32,
+    /// The kind of access which caused this fault.
+    pub access: Access,
+}
+impl Fault {
+    /// The value of the fault status register associated with this fault.
+    pub fn fsr(&self) -> u32 { (self.domain << 4) | self.status as u32 }
+}
+
+/// The granularity of a mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MapKind { Section, Page }
+
+/// A cached translation.
+#[derive(Clone, Copy, Debug)]
+struct TlbEntry {
+    /// The base virtual address of this mapping.
+    vbase: u32,
+    /// Mask selecting the virtual page number bits for this mapping.
+    mask: u32,
+    /// The base physical address of this mapping.
+    pbase: u32,
+    /// The access permission bits for this mapping.
+    ap: u32,
+    /// The domain associated with this mapping.
+    domain: u32,
+    /// Whether this mapping came from a section or a page descriptor.
+    kind: MapKind,
+    valid: bool,
+}
+impl TlbEntry {
+    const INVALID: TlbEntry = TlbEntry {
+        vbase: 0, mask: 0, pbase: 0, ap: 0, domain: 0,
+        kind: MapKind::Page, valid: false,
+    };
+    fn hit(&self, va: u32) -> bool { self.valid && (va & self.mask) == self.vbase }
+    fn output(&self, va: u32) -> u32 { self.pbase | (va & !self.mask) }
+}
+
+/// A direct-mapped software TLB.
+pub struct Tlb {
+    data: [TlbEntry; Tlb::NUM_ENTRIES],
+}
+impl Tlb {
+    const NUM_ENTRIES: usize = 256;
+    pub fn new() -> Self { Tlb { data: [TlbEntry::INVALID; Tlb::NUM_ENTRIES] } }
+
+    fn index(va: u32) -> usize { ((va >> 12) as usize) % Self::NUM_ENTRIES }
+
+    fn lookup(&self, va: u32) -> Option<&TlbEntry> {
+        let e = &self.data[Self::index(va)];
+        if e.hit(va) { Some(e) } else { None }
+    }
+    fn insert(&mut self, va: u32, entry: TlbEntry) {
+        self.data[Self::index(va)] = entry;
+    }
+
+    /// Invalidate all entries.
+    pub fn flush(&mut self) {
+        for e in self.data.iter_mut() { e.valid = false; }
+    }
+
+    /// Invalidate any entry which maps the provided virtual address.
+    pub fn invalidate(&mut self, va: u32) {
+        for e in self.data.iter_mut() {
+            if e.hit(va) { e.valid = false; }
+        }
+    }
+}
+impl Default for Tlb {
+    fn default() -> Self { Tlb::new() }
+}
+
+/// State associated with the ARMv5 MMU (mostly system control coprocessor
+/// registers).
+pub struct Mmu {
+    /// Translation is enabled (SCTLR.M).
+    pub enabled: bool,
+    /// System protection bit (SCTLR.S).
+    pub system: bool,
+    /// ROM protection bit (SCTLR.R).
+    pub rom: bool,
+    /// Exception vectors are located at 0xffff_0000 (SCTLR.V).
+    pub high_vectors: bool,
+
+    /// Translation table base register.
+    pub ttbr: u32,
+    /// Domain access control register.
+    pub dacr: u32,
+    /// Data fault status register.
+    pub dfsr: u32,
+    /// Instruction fault status register.
+    pub ifsr: u32,
+    /// Fault address register.
+    pub far: u32,
+
+    pub tlb: Tlb,
+}
+impl Mmu {
+    pub fn new() -> Self {
+        Mmu {
+            enabled: false, system: false, rom: false, high_vectors: false,
+            ttbr: 0, dacr: 0, dfsr: 0, ifsr: 0, far: 0,
+            tlb: Tlb::new(),
+        }
+    }
+
+    /// The base address of the exception vector table.
+    pub fn vector_base(&self) -> u32 {
+        if self.high_vectors { 0xffff_0000 } else { 0x0000_0000 }
+    }
+
+    /// Write the translation table base register.
+    ///
+    /// The TLB is flushed, since cached walks are only valid for the tables
+    /// they were read from.
+    pub fn set_ttbr(&mut self, val: u32) {
+        self.ttbr = val;
+        self.tlb.flush();
+    }
+
+    /// Write the domain access control register.
+    ///
+    /// Domains are checked on every TLB hit, so this takes effect for cached
+    /// translations without flushing the TLB.
+    pub fn set_dacr(&mut self, val: u32) {
+        self.dacr = val;
+    }
+
+    /// Latch the fault status registers for a fault.
+    ///
+    /// Data aborts update the FSR and FAR, and prefetch aborts only update
+    /// the IFSR.
+    pub fn record_fault(&mut self, fault: Fault) {
+        match fault.access {
+            Access::Fetch => { self.ifsr = fault.fsr(); },
+            Access::Read | Access::Write => {
+                self.dfsr = fault.fsr();
+                self.far = fault.addr;
+            },
+        }
+    }
+}
+impl Default for Mmu {
+    fn default() -> Self { Mmu::new() }
+}
+
+/// Accesses to system control coprocessor (CP15) registers with `mrc` and
+/// `mcr`, where registers are numbered `(crn, opc1, crm, opc2)`.
+impl Mmu {
+    /// The value of the main ID register (an ARM926EJ-S).
+    pub const MIDR: u32 = 0x4106_9265;
+
+    /// Read a register, returning `None` if it isn't implemented.
+    pub fn read_cp15(&self, crn: u32, opc1: u32, crm: u32, opc2: u32) 
+        -> Option<u32> 
+    {
+        Some(match (crn, opc1, crm, opc2) {
+            (0, 0, 0, 0) => Mmu::MIDR,
+            (1, 0, 0, 0) => {
+                (self.enabled as u32) | (self.system as u32) << 8
+                    | (self.rom as u32) << 9 | (self.high_vectors as u32) << 13
+            },
+            (2, 0, 0, 0) => self.ttbr,
+            (3, 0, 0, 0) => self.dacr,
+            (5, 0, 0, 0) => self.dfsr,
+            (5, 0, 0, 1) => self.ifsr,
+            (6, 0, 0, 0) => self.far,
+            _ => return None,
+        })
+    }
+
+    /// Write a register, returning `false` if it isn't implemented.
+    ///
+    /// There are no caches, so cache maintenance operations do nothing.
+    pub fn write_cp15(&mut self, crn: u32, opc1: u32, crm: u32, opc2: u32, 
+        val: u32) -> bool 
+    {
+        match (crn, opc1, crm, opc2) {
+            (1, 0, 0, 0) => {
+                self.enabled = (val & (1 << 0)) != 0;
+                self.system = (val & (1 << 8)) != 0;
+                self.rom = (val & (1 << 9)) != 0;
+                self.high_vectors = (val & (1 << 13)) != 0;
+            },
+            (2, 0, 0, 0) => self.set_ttbr(val),
+            (3, 0, 0, 0) => self.set_dacr(val),
+            (5, 0, 0, 0) => self.dfsr = val,
+            (5, 0, 0, 1) => self.ifsr = val,
+            (6, 0, 0, 0) => self.far = val,
+            (7, 0, _, _) => {},
+            (8, 0, _, 1) => self.tlb.invalidate(val),
+            (8, 0, _, _) => self.tlb.flush(),
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl Mmu {
+    /// Translate a virtual address into a physical address.
+    ///
+    /// The closure `read32` is used to read descriptors from guest physical
+    /// memory while walking the translation tables, and returns `None` if 
+    /// there's no memory at some address (which causes an external abort).
+    pub fn translate<F>(&mut self, va: u32, access: Access, privileged: bool,
+        read32: F) -> Result<u32, Fault> where F: Fn(u32) -> Option<u32>
+    {
+        if !self.enabled {
+            return Ok(va);
+        }
+        let entry = match self.tlb.lookup(va) {
+            Some(e) => *e,
+            None => {
+                let e = self.walk(va, access, read32)?;
+                self.tlb.insert(va, e);
+                e
+            },
+        };
+        self.check(&entry, va, access, privileged)?;
+        Ok(entry.output(va))
+    }
+
+    /// Walk the translation tables for some virtual address.
+    fn walk<F>(&self, va: u32, access: Access, read32: F)
+        -> Result<TlbEntry, Fault> where F: Fn(u32) -> Option<u32>
+    {
+        let fault = |status, domain| Fault { status, domain, addr: va, access };
+
+        let l1_addr = (self.ttbr & 0xffff_c000) | ((va >> 20) << 2);
+        let l1 = read32(l1_addr)
+            .ok_or_else(|| fault(FaultStatus::ExternalSection, 0))?;
+        let domain = (l1 >> 5) & 0xf;
+
+        let (l2_addr, fine) = match l1 & 0b11 {
+            0b00 => return Err(fault(FaultStatus::TranslationSection, 0)),
+
+            // Section descriptor
+            0b10 => return Ok(TlbEntry {
+                vbase: va & 0xfff0_0000, mask: 0xfff0_0000,
+                pbase: l1 & 0xfff0_0000, ap: (l1 >> 10) & 0b11,
+                domain, kind: MapKind::Section, valid: true,
+            }),
+
+            // Coarse page table descriptor
+            0b01 => ((l1 & 0xffff_fc00) | (((va >> 12) & 0xff) << 2), false),
+
+            // Fine page table descriptor
+            0b11 => ((l1 & 0xffff_f000) | (((va >> 10) & 0x3ff) << 2), true),
+            _ => unreachable!(),
+        };
+
+        let l2 = read32(l2_addr)
+            .ok_or_else(|| fault(FaultStatus::ExternalPage, domain))?;
+        let (mask, subpage) = match l2 & 0b11 {
+            0b00 => return Err(fault(FaultStatus::TranslationPage, domain)),
+            // Large page (64KiB)
+            0b01 => (0xffff_0000, (va >> 14) & 0b11),
+            // Small page (4KiB)
+            0b10 => (0xffff_f000, (va >> 10) & 0b11),
+            // Tiny page (1KiB)
+            0b11 => {
+                if !fine {
+                    return Err(fault(FaultStatus::TranslationPage, domain));
+                }
+                (0xffff_fc00, 0)
+            },
+            _ => unreachable!(),
+        };
+
+        Ok(TlbEntry {
+            vbase: va & mask, mask, pbase: l2 & mask,
+            ap: (l2 >> (4 + subpage * 2)) & 0b11,
+            domain, kind: MapKind::Page, valid: true,
+        })
+    }
+
+    /// Perform domain and access permission checks on a translation.
+    fn check(&self, e: &TlbEntry, va: u32, access: Access, privileged: bool)
+        -> Result<(), Fault>
+    {
+        let (domain_fault, perm_fault) = match e.kind {
+            MapKind::Section =>
+                (FaultStatus::DomainSection, FaultStatus::PermissionSection),
+            MapKind::Page =>
+                (FaultStatus::DomainPage, FaultStatus::PermissionPage),
+        };
+        let fault = |status| Fault { status, domain: e.domain, addr: va, access };
+
+        match (self.dacr >> (e.domain * 2)) & 0b11 {
+            // Manager: accesses are never checked
+            0b11 => return Ok(()),
+            // Client: accesses are checked against the AP bits
+            0b01 => {},
+            // No access, or reserved
+            _ => return Err(fault(domain_fault)),
+        }
+
+        let write = access == Access::Write;
+        let allowed = match (e.ap, privileged) {
+            (0b00, true)  => !write && (self.system != self.rom),
+            (0b00, false) => !write && (self.rom && !self.system),
+            (0b01, priv_) => priv_,
+            (0b10, priv_) => priv_ || !write,
+            (0b11, _)     => true,
+            _ => unreachable!(),
+        };
+        if allowed { Ok(()) } else { Err(fault(perm_fault)) }
+    }
+}
+
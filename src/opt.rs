@@ -1,12 +1,104 @@
 
 use std::collections::HashMap;
-use crate::block::BasicBlock;
+use crate::block::{ BasicBlock, BlockLink };
 use crate::ir::*;
-use crate::regalloc::{ HostRegister, IntervalMap };
-use crate::regalloc;
+use crate::regalloc::IntervalMap;
 
 impl BasicBlock {
+    /// Remove flag-setting operations whose flags are overwritten before 
+    /// anything can observe them.
+    ///
+    /// Flags are observed by reading or writing individual flags (which 
+    /// computes any pending flags first), and by anything that might exit 
+    /// the block (memory accesses and writes to the CPSR).
+    pub fn prune_dead_flags(&mut self) {
+        // The flags are always live at the end of a block
+        let mut live = true;
+        let mut dead = Vec::new();
+        for (idx, inst) in self.data.iter().enumerate().rev() {
+            match inst.rh {
+                Operation::Bind(BindOp::SetFlags(..)) => {
+                    if !live { dead.push(idx); }
+                    live = false;
+                },
+                Operation::Bind(BindOp::ReadFlag(_)) |
+                Operation::Bind(BindOp::WriteFlag(..)) |
+                Operation::Bind(BindOp::WriteCpsr(..)) |
+                Operation::Memory(_) => live = true,
+                _ => {},
+            }
+        }
+        for idx in dead.iter() {
+            self.data.remove(*idx);
+        }
+    }
+
+    /// Compute the flags for a flag-setting operation right away (with the 
+    /// host flags, see [crate::block::emitter]) when they're used later in 
+    /// the same block.
+    ///
+    /// Flags are used within a block by reading individual flags, or by a 
+    /// conditional branch at the end of the block.
+    pub fn expand_used_flags(&mut self) {
+        let mut used = matches!(self.link, Some(BlockLink::BranchCond(..)));
+        let mut expand = Vec::new();
+        for (idx, inst) in self.data.iter().enumerate().rev() {
+            match inst.rh {
+                Operation::Bind(BindOp::SetFlags(..)) => {
+                    if used { expand.push(idx); }
+                    used = false;
+                },
+                Operation::Bind(BindOp::ReadFlag(_)) => used = true,
+                Operation::Bind(BindOp::WriteFlag(..)) |
+                Operation::Bind(BindOp::WriteCpsr(..)) |
+                Operation::Memory(_) => used = false,
+                _ => {},
+            }
+        }
+        for idx in expand.iter() {
+            let inst = self.data.remove(*idx);
+            let insts = self.expand_flags(&inst);
+            self.data.splice(*idx..*idx, insts);
+        }
+    }
+
+    /// Expand [BindOp::SetFlags] into instructions which compute the flags.
+    fn expand_flags(&mut self, inst: &Instruction) -> Vec<Instruction> {
+        let (op, x, y) = match inst.rh {
+            Operation::Bind(BindOp::SetFlags(op, x, y)) => (op, x, y),
+            _ => unreachable!(),
+        };
+        let opcd = inst.guest_op;
+        let mut res = Vec::new();
+        let (val, c, v) = match op {
+            FlagOp::Sub => {
+                let val = self.lb.alloca_local(32);
+                let c = self.lb.alloca_local(1);
+                let v = self.lb.alloca_local(1);
+                res.push(Instruction::sub32f(opcd, val, c, v, x, y));
+                (val, c, Some(v))
+            },
+            FlagOp::Logical => (x, y, None),
+        };
+        let n = self.lb.alloca_local(1);
+        let z = self.lb.alloca_local(1);
+        res.push(Instruction::is_negative(opcd, n, val));
+        res.push(Instruction::is_zero(opcd, z, val));
+        res.push(Instruction::write_flag(opcd, FlagKind::Negative, n));
+        res.push(Instruction::write_flag(opcd, FlagKind::Zero, z));
+        res.push(Instruction::write_flag(opcd, FlagKind::Carry, c));
+        if let Some(v) = v {
+            res.push(Instruction::write_flag(opcd, FlagKind::Overflow, v));
+        }
+        for i in res.iter_mut() {
+            i.guest_pc = inst.guest_pc;
+        }
+        res
+    }
+
     pub fn prune_dead_vars(&mut self) {
+        self.prune_dead_flags();
+        self.expand_used_flags();
         loop {
             self.intervals = IntervalMap::from_block(self);
             let deadlist = self.intervals.get_dead_vars();
@@ -25,14 +117,8 @@ impl BasicBlock {
                 }
                 // Unused variables bound to flags can be removed
                 for inst in self.data.iter_mut() {
-                    match inst.lh_c {
-                        Some(c) => if c == *dvar { inst.lh_c = None; }
-                        None => {},
-                    }
-                    match inst.lh_v {
-                        Some(v) => if v == *dvar { inst.lh_v = None; }
-                        None => {},
-                    }
+                    if inst.lh_c == Some(*dvar) { inst.lh_c = None; }
+                    if inst.lh_v == Some(*dvar) { inst.lh_v = None; }
                 }
             }
 
@@ -67,6 +153,9 @@ impl GuestRegGraph {
         for reg in self.data.iter() { println!("{:?}", reg); }
     }
 }
+impl Default for GuestRegGraph {
+    fn default() -> Self { GuestRegGraph::new() }
+}
 
 impl GuestRegGraph {
     fn read(&mut self, idx: usize, r: u32, v: Var) { 
@@ -79,9 +168,7 @@ impl GuestRegGraph {
 
             // The first ref to this register is a read
             None => {
-                let mut list = Vec::new();
-                list.push(GuestRegInterval(0, idx, v));
-                self.data.insert(r, list);
+                self.data.insert(r, vec![GuestRegInterval(0, idx, v)]);
             },
         }
     }
@@ -90,21 +177,18 @@ impl GuestRegGraph {
         match self.data.get_mut(&r) {
 
             Some(vec) => {
-                let latest = vec.last_mut().unwrap();
                 vec.push(GuestRegInterval(idx, idx, v))
             },
 
             // The first ref to this register is a write
             None => {
-                let mut list = Vec::new();
-                list.push(GuestRegInterval(idx, idx, v));
-                self.data.insert(r, list);
+                self.data.insert(r, vec![GuestRegInterval(idx, idx, v)]);
             },
         }
     }
 
     // Build some map of the intervals of *guest registers* in this block.
-    pub fn build(&mut self, data: &Vec<Instruction>) {
+    pub fn build(&mut self, data: &[Instruction]) {
         for (idx, inst) in data.iter().enumerate() {
             if let Operation::Bind(ref op) = inst.rh {
                 match op {
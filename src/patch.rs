@@ -0,0 +1,38 @@
+//! Patching recompiled code in place.
+
+extern crate libc;
+use libc::{ c_void, mprotect, PROT_READ, PROT_WRITE, PROT_EXEC };
+
+/// Overwrite some recompiled code at the host address `addr`, returning 
+/// false if the code couldn't be made writable (or executable again).
+///
+/// This is also called from our `SIGSEGV` handler, so it must not allocate 
+/// or panic.
+///
+/// # Safety
+///
+/// `[addr, addr + code.len())` must be inside recompiled code, and no other
+/// thread may be executing the instructions being overwritten.
+pub unsafe fn write_code(addr: usize, code: &[u8]) -> bool {
+    let page = addr & !0xfff;
+    let len = addr + code.len() - page;
+    if mprotect(page as *mut c_void, len, PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return false;
+    }
+    std::ptr::copy_nonoverlapping(code.as_ptr(), addr as *mut u8, code.len());
+    mprotect(page as *mut c_void, len, PROT_READ | PROT_EXEC) == 0
+}
+
+/// Overwrite the `jmp rel32` at `site` so that it branches to `target`, 
+/// returning false on failure (see [write_code]).
+///
+/// # Safety
+///
+/// `site` must be the address of a `jmp rel32` in recompiled code (with the 
+/// same requirements as [write_code]).
+pub unsafe fn write_jmp(site: usize, target: usize) -> bool {
+    let rel = (target as isize - (site + 5) as isize) as i32;
+    let mut code = [0xe9u8; 5];
+    code[1..5].copy_from_slice(&rel.to_le_bytes());
+    write_code(site, &code)
+}
@@ -23,6 +23,18 @@
 //! registers based on a simple rule: if the intervals associated with two 
 //! variables overlap, they cannot be allocated to the same register.
 //!
+//! Intervals are closed: a variable which is last used by some instruction 
+//! is still live while that instruction defines its results, so they never 
+//! share a register (the emitter may write a result before it has read all
+//! of the operands).
+//!
+//! When we run out of registers, the live value whose next use is furthest 
+//! away is spilled to a stack slot, splitting its interval: the rest of the 
+//! interval (from the next use onwards) is allocated again later, and the 
+//! value is reloaded into a register if one is available by then. This means
+//! that the storage location for a variable depends on the position in the 
+//! block (see [StorageMap::seek]).
+//!
 //! [^1]: See <https://dl.acm.org/doi/10.1145/330249.330250> and
 //! <https://dl.acm.org/doi/10.5555/647478.727924>
 //!
@@ -34,16 +46,25 @@
 //! - Some target instructions may have fixed operands
 //! - Some registers are reserved for interfaces with global state
 //!
+//! Each IR instruction may have some [Constraints] on the registers used by
+//! the code emitted for it. Fixed registers are never allocated to a value 
+//! which is live at that instruction (the emitter moves operands and results
+//! in and out of them). Calls to runtime helpers clobber the caller-save 
+//! registers, and any values in them which are live across the call (or used
+//! by the calling instruction) are saved and restored by the emitter (see 
+//! [StorageMap::saved]).
+//!
 
 use std::fmt;
-use std::collections::{BTreeMap, HashMap};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 
 use crate::runtime::RuntimeContext;
 use crate::ir::*;
 use crate::block::*;
 
 /// Representing a physical register on the target machine.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum HostRegister { 
     RAX = 0x0, RCX = 0x1, RDX = 0x2, RBX = 0x3,
     RSP = 0x4, RBP = 0x5, RSI = 0x6, RDI = 0x7,
@@ -73,12 +94,17 @@ pub enum StorageLoc {
 
     /// A constant value.
     Const(usize),
+
+    /// A spilled value, stored at some offset from the stack pointer in the 
+    /// block's stack frame (see [StorageMap::frame_size]).
+    Spill(i32),
 }
 impl fmt::Display for StorageLoc {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Gpr(x) => write!(f, "{:?}", HostRegister::from(*x)),
             Self::Const(_) => Ok(()),
+            Self::Spill(off) => write!(f, "[rsp+{:#x}]", off),
         }
     }
 }
@@ -89,32 +115,112 @@ impl fmt::Display for StorageLoc {
 pub struct LiveInterval(pub usize, pub usize);
 
 
+/// A move between two storage locations.
+pub type Move = (StorageLoc, StorageLoc);
+
 /// A map from variable IDs to storage locations on the target machine.
 ///
 /// When a basic block is being lowered into the target instruction set, this
 /// map is used to translate from variables to actual storage locations.
 pub struct StorageMap {
-    /// The set of bindings from variables to storage locations.
-    data: HashMap<Var, StorageLoc>,
+    /// The set of bindings from variables to storage locations, each starting
+    /// at some position in the block.
+    data: HashMap<Var, Vec<(usize, StorageLoc)>>,
+    /// Moves (from, to) which must be performed before the instruction at 
+    /// some position, when a value is spilled or reloaded.
+    moves: BTreeMap<usize, Vec<Move>>,
+    /// Caller-save registers holding live values which must be preserved
+    /// around a call to a runtime helper at some position.
+    saved: BTreeMap<usize, Vec<HostRegister>>,
+    /// The number of stack slots used for spilled values.
+    spill_slots: usize,
+    /// The current position in the block.
+    pos: usize,
 }
 impl StorageMap {
-    pub fn new() -> Self { StorageMap { data: HashMap::new() } }
+    /// The size (in bytes) of a stack slot for a spilled value.
+    pub const SLOT_SIZE: usize = 8;
+
+    pub fn new() -> Self { 
+        StorageMap { 
+            data: HashMap::new(), 
+            moves: BTreeMap::new(), 
+            saved: BTreeMap::new(), 
+            spill_slots: 0, 
+            pos: 0 
+        } 
+    }
 
-    /// Bind variable v to a storage location.
-    pub fn bind(&mut self, v: Var, s: StorageLoc) { self.data.insert(v, s); }
-    /// Get the storage location assigned to variable v.
-    pub fn get(&self, v: &Var) -> Option<&StorageLoc> { self.data.get(v) }
+    /// Allocate a new stack slot for a spilled value.
+    pub fn alloc_spill(&mut self) -> StorageLoc {
+        let off = self.spill_slots * Self::SLOT_SIZE;
+        self.spill_slots += 1;
+        StorageLoc::Spill(off as i32)
+    }
+
+    /// The size (in bytes) of the stack frame needed for spilled values.
+    ///
+    /// This is always a multiple of 16, so that the stack pointer stays
+    /// aligned for calls to runtime helpers.
+    pub fn frame_size(&self) -> usize {
+        (self.spill_slots * Self::SLOT_SIZE + 0xf) & !0xf
+    }
+
+    /// Bind variable v to a storage location, starting at position `pos`.
+    pub fn bind(&mut self, v: Var, pos: usize, s: StorageLoc) { 
+        let locs = self.data.entry(v).or_default();
+        match locs.last_mut() {
+            Some(last) if last.0 == pos => last.1 = s,
+            _ => locs.push((pos, s)),
+        }
+    }
+    /// Record a move which must be performed before the instruction at `pos`.
+    pub fn add_move(&mut self, pos: usize, from: StorageLoc, to: StorageLoc) {
+        self.moves.entry(pos).or_default().push((from, to));
+    }
+
+    /// Set the current position in the block.
+    pub fn seek(&mut self, pos: usize) { self.pos = pos; }
+    /// Get the storage location assigned to variable v at the current 
+    /// position.
+    pub fn get(&self, v: &Var) -> Option<&StorageLoc> { 
+        self.get_at(v, self.pos)
+    }
+    /// Get the storage location assigned to variable v at some position.
+    pub fn get_at(&self, v: &Var, pos: usize) -> Option<&StorageLoc> { 
+        self.data.get(v)?.iter().rev()
+            .find(|(start, _)| *start <= pos)
+            .map(|(_, loc)| loc)
+    }
+    /// Get the registers which must be preserved around a call to a runtime
+    /// helper at the current position.
+    pub fn saved(&self) -> Vec<HostRegister> {
+        self.saved.get(&self.pos).cloned().unwrap_or_default()
+    }
+    /// Get the moves to be performed before the instruction at the current
+    /// position. Stores to stack slots always come before reloads.
+    pub fn moves(&self) -> Vec<Move> {
+        let mut moves = self.moves.get(&self.pos).cloned().unwrap_or_default();
+        moves.sort_by_key(|(_, to)| !matches!(to, StorageLoc::Spill(_)));
+        moves
+    }
 
     pub fn print(&self) { 
-        for e in self.data.iter() { 
-            match e.0.kind {
-                VarKind::Local | 
-                VarKind::GuestReg(_) => println!("  {}\t {}", e.0, e.1),
+        for (var, locs) in self.data.iter() { 
+            match var.kind {
+                VarKind::Local | VarKind::GuestReg(_) => {
+                    print!("  {}\t", var);
+                    for (pos, loc) in locs.iter() { print!(" {}@{}", loc, pos); }
+                    println!();
+                },
                 _ => {},
             }
         } 
     }
 }
+impl Default for StorageMap {
+    fn default() -> Self { StorageMap::new() }
+}
 
 /// A pool of physical registers available to an allocator.
 pub struct RegisterPool {
@@ -134,6 +240,14 @@ impl RegisterPool {
         self.data.remove(self.data.len() - 1) 
     }
 
+    /// Remove (use) the most recently freed register which satisfies `f`.
+    pub fn take_if(&mut self, f: impl Fn(HostRegister) -> bool) 
+        -> Option<HostRegister> 
+    { 
+        let idx = self.data.iter().rposition(|r| f(*r))?;
+        Some(self.data.remove(idx))
+    }
+
     /// Restore (free) a register back to the pool.
     pub fn put_back(&mut self, r: HostRegister) { 
         self.data.push(r); 
@@ -142,16 +256,64 @@ impl RegisterPool {
     /// Returns true if the pool of available registers is empty.
     pub fn is_empty(&self) -> bool { self.data.is_empty() }
 }
+impl Default for RegisterPool {
+    fn default() -> Self { RegisterPool::new() }
+}
+
+/// Constraints on the registers used by the code emitted for an IR 
+/// instruction.
+#[derive(Clone, Debug, Default)]
+pub struct Constraints {
+    /// Registers used for fixed operands or results (i.e. the shift count in
+    /// CL), which can't hold any other value that's live at the instruction.
+    pub fixed: Vec<HostRegister>,
+    /// True if the instruction may call a runtime helper (clobbering the 
+    /// caller-save registers).
+    pub call: bool,
+}
+impl Constraints {
+    pub fn from_inst(inst: &Instruction) -> Self {
+        use HostRegister::*;
+        let variable = |v: &Var| !matches!(v.kind, VarKind::Constant(_));
+        match inst.rh {
+            // Slow paths for memory accesses call into the runtime, and 
+            // lazy flags are computed by the runtime before they're used
+            Operation::Memory(_) | 
+            Operation::Bind(BindOp::WriteCpsr(..)) |
+            Operation::Bind(BindOp::ReadFlag(_)) |
+            Operation::Bind(BindOp::WriteFlag(..)) => {
+                Constraints { fixed: vec![], call: true }
+            },
+            Operation::Arith(ArithOp::Shl32(_, y)) |
+            Operation::Arith(ArithOp::Shr32(_, y)) |
+            Operation::Arith(ArithOp::Lsl32(_, y)) if variable(&y) => {
+                Constraints { fixed: vec![RCX], call: false }
+            },
+            _ => Constraints::default(),
+        }
+    }
+    fn is_empty(&self) -> bool { self.fixed.is_empty() && !self.call }
+}
 
 /// A map from variables to live intervals.
 ///
 /// This uses [LiveInterval] to represent the lifetimes of variables within a 
 /// basic block.
 pub struct IntervalMap {
-    data: BTreeMap<Var, LiveInterval>
+    data: BTreeMap<Var, LiveInterval>,
+    /// The positions where each variable is used (in order).
+    uses: HashMap<Var, Vec<usize>>,
+    /// Register constraints for each instruction (if any).
+    constraints: BTreeMap<usize, Constraints>,
 }
 impl IntervalMap {
-    pub fn new() -> Self { IntervalMap { data: BTreeMap::new() } }
+    pub fn new() -> Self { 
+        IntervalMap { 
+            data: BTreeMap::new(), 
+            uses: HashMap::new(), 
+            constraints: BTreeMap::new() 
+        } 
+    }
 
     /// Iterate through the instructions in a basic block and compute the map 
     /// of live intervals for all associated variables within the block.
@@ -160,14 +322,14 @@ impl IntervalMap {
         for (pos, inst) in bb.data.iter().enumerate() {
 
             // Variables are defined on the left-hand side
-            if inst.lh.is_some() { 
-                map.define_var(inst.lh.unwrap(), pos); 
+            if let Some(v) = inst.lh { 
+                map.define_var(v, pos); 
             }
-            if inst.lh_c.is_some() { 
-                map.define_var(inst.lh_c.unwrap(), pos); 
+            if let Some(v) = inst.lh_c { 
+                map.define_var(v, pos); 
             }
-            if inst.lh_v.is_some() { 
-                map.define_var(inst.lh_v.unwrap(), pos); 
+            if let Some(v) = inst.lh_v { 
+                map.define_var(v, pos); 
             }
 
             // Variables are used on the right hand side
@@ -175,11 +337,16 @@ impl IntervalMap {
             for var in used_vars.iter() {
                 map.use_var(*var, pos); 
             }
+
+            let constraints = Constraints::from_inst(inst);
+            if !constraints.is_empty() {
+                map.constraints.insert(pos, constraints);
+            }
         }
 
         assert!(bb.link.is_some());
         match bb.link.unwrap() {
-            BlockLink::Branch(addr) => 
+            BlockLink::Branch(addr) | BlockLink::Return(addr) => 
                 map.use_var(addr, bb.data.len()),
             BlockLink::BranchAndLink(addr, lr) => {
                 map.use_var(addr, bb.data.len());
@@ -189,13 +356,17 @@ impl IntervalMap {
                 map.use_var(t, bb.data.len());
                 map.use_var(f, bb.data.len());
             },
-            _ => panic!(""),
+            BlockLink::Exit(..) => {},
         }
         map
     }
 
 
-    pub fn clear(&mut self) { self.data.clear(); }
+    pub fn clear(&mut self) { 
+        self.data.clear(); 
+        self.uses.clear(); 
+        self.constraints.clear(); 
+    }
 
     pub fn print(&self) { 
         for e in self.data.iter() { 
@@ -203,18 +374,36 @@ impl IntervalMap {
     }
 
     /// Insert a new entry for the provided variable.
+    ///
+    /// Until the variable is used, its interval ends where it's defined.
     pub fn define_var(&mut self, v: Var, def_idx: usize) {
-        self.data.insert(v, LiveInterval(def_idx, 0));
+        self.data.insert(v, LiveInterval(def_idx, def_idx));
     }
 
     /// Update the entry for the provided variable.
     pub fn use_var(&mut self, v: Var, use_idx: usize) {
         self.data.get_mut(&v).unwrap().1 = use_idx;
+        self.uses.entry(v).or_default().push(use_idx);
     }
     /// Return a list of variables in the map which are currently unused.
     pub fn get_dead_vars(&self) -> Vec<Var> {
-        self.data.iter().filter(|(_, v)| v.1 == 0).map(|(&k, _)| k).collect()
+        self.data.keys().filter(|v| !self.uses.contains_key(v)).cloned().collect()
     }
+
+    /// Returns true if `reg` is a fixed register for some instruction 
+    /// between `start` and `end` (inclusive).
+    pub fn is_fixed(&self, reg: HostRegister, start: usize, end: usize) -> bool {
+        self.constraints.range(start..=end).any(|(_, c)| c.fixed.contains(&reg))
+    }
+
+    /// Return the first position (at or after `pos`) where a variable is 
+    /// used.
+    pub fn next_use(&self, v: &Var, pos: usize) -> Option<usize> {
+        self.uses.get(v)?.iter().find(|&&idx| idx >= pos).cloned()
+    }
+}
+impl Default for IntervalMap {
+    fn default() -> Self { IntervalMap::new() }
 }
 
 /// Representing an allocated register and the associated live value.
@@ -232,50 +421,171 @@ pub struct ActiveEntry {
 }
 
 
+/// Stack slots for spilled values.
+///
+/// Each variable is only spilled to a single slot (since values never change
+/// after they're defined, a value only needs to be stored once), and slots 
+/// are reused after the interval for a variable ends.
+struct SpillSlots {
+    /// The slot assigned to each spilled variable.
+    slot: HashMap<Var, StorageLoc>,
+    /// Variables whose value has already been stored in their slot.
+    stored: HashSet<Var>,
+    /// Slots in use, along with the end of the associated interval.
+    live: Vec<(usize, StorageLoc)>,
+    /// Slots which can be reused.
+    free: Vec<StorageLoc>,
+}
+impl SpillSlots {
+    fn new() -> Self {
+        SpillSlots { 
+            slot: HashMap::new(), stored: HashSet::new(), 
+            live: Vec::new(), free: Vec::new() 
+        }
+    }
+
+    /// Get the slot for a variable (whose interval ends at `end`), 
+    /// allocating a new one if necessary.
+    fn get(&mut self, storage: &mut StorageMap, v: Var, end: usize) 
+        -> StorageLoc 
+    {
+        if let Some(slot) = self.slot.get(&v) {
+            return *slot;
+        }
+        let slot = self.free.pop().unwrap_or_else(|| storage.alloc_spill());
+        self.slot.insert(v, slot);
+        self.live.push((end, slot));
+        slot
+    }
+
+    /// Release slots for intervals which ended before `pos`.
+    fn expire(&mut self, pos: usize) {
+        let free = &mut self.free;
+        self.live.retain(|(end, slot)| {
+            if *end < pos { free.push(*slot); false } else { true }
+        });
+    }
+}
+
 /// Given an [IntervalMap] for some basic block, color all variables and
 /// return a map from variables to storage locations.
 ///
-/// NOTE: Spilling values is currently unimplemented.
+/// Intervals are visited in order of their start position. When there are 
+/// no free registers, the value (either the new one, or one that's currently
+/// in a register and not used by the current instruction) whose next use is 
+/// furthest away is spilled, and the rest of its interval is queued again 
+/// from its next use.
 ///
-/// Simple "linear-scan" allocator.
-/// Not much more to be done here until we can test it on actual code.
+/// Registers which are fixed for some instruction in an interval are never
+/// allocated to it (instead of splitting the interval around them).
 pub fn allocate_registers(intervals: &IntervalMap) -> StorageMap {
     let mut active: Vec<ActiveEntry> = Vec::new();
     let mut pool = RegisterPool::new();
     let mut storage  = StorageMap::new();
+    let mut slots = SpillSlots::new();
 
-    // Iterate over all bound values/live intervals in this block
+    // Intervals (or the remaining parts of split intervals) which haven't
+    // been allocated yet, ordered by their start position
+    let mut unhandled: BinaryHeap<Reverse<(usize, Var)>> = BinaryHeap::new();
     for (var, interval) in intervals.data.iter() {
-
         // If this is a constant, just add it to the storage map
         if let VarKind::Constant(c) = var.kind {
-            storage.bind(*var, StorageLoc::Const(c));
+            storage.bind(*var, 0, StorageLoc::Const(c));
             continue;
         }
+        unhandled.push(Reverse((interval.0, *var)));
+    }
 
-        // Expire any values which are not still live
-        active.retain(|active_var| {
-            if active_var.interval.1 <= interval.0 {
-                pool.put_back(active_var.reg);
+    while let Some(Reverse((start, var))) = unhandled.pop() {
+        let LiveInterval(def, end) = intervals.data[&var];
+
+        // Expire any values which are not still live. Values used at this
+        // position are still live while it defines new values.
+        active.retain(|entry| {
+            if entry.interval.1 < start {
+                pool.put_back(entry.reg);
                 false
             } else { true }
         });
+        slots.expire(start);
+
+        // If there are no available registers, evict the value whose next 
+        // use is furthest away (values defined or used at this position 
+        // can't be evicted).
+        let usable = |reg| !intervals.is_fixed(reg, start, end);
+        let mut reg = pool.take_if(usable);
+        if reg.is_none() {
+            let victim = active.iter().enumerate()
+                .filter(|(_, e)| e.interval.0 < start && usable(e.reg))
+                .filter_map(|(idx, e)| {
+                    intervals.next_use(&e.var, start).map(|u| (idx, u))
+                })
+                .filter(|(_, u)| *u > start)
+                .max_by_key(|(_, u)| *u);
+            let next = intervals.next_use(&var, start).unwrap_or(usize::MAX);
+
+            match victim {
+                Some((idx, victim_next)) if victim_next > next => {
+                    let entry = active.remove(idx);
+                    let slot = slots.get(&mut storage, entry.var, 
+                        entry.interval.1);
+                    if slots.stored.insert(entry.var) {
+                        storage.add_move(start, 
+                            StorageLoc::Gpr(entry.reg as u8), slot);
+                    }
+                    storage.bind(entry.var, start, slot);
+                    unhandled.push(Reverse((victim_next, entry.var)));
+                    reg = Some(entry.reg);
+                },
+
+                // Otherwise, this value lives in memory until its next use
+                _ => {
+                    let slot = slots.get(&mut storage, var, end);
+                    slots.stored.insert(var);
+                    storage.bind(var, start, slot);
+                    if let Some(u) = intervals.next_use(&var, start + 1) {
+                        unhandled.push(Reverse((u, var)));
+                    }
+                    continue;
+                },
+            }
+        }
+
+        // Allocate a register and mark the variable as active (reloading 
+        // the value if this is the rest of a split interval)
+        let reg = reg.unwrap();
+        if start != def {
+            let slot = slots.get(&mut storage, var, end);
+            storage.add_move(start, slot, StorageLoc::Gpr(reg as u8));
+        }
+        storage.bind(var, start, StorageLoc::Gpr(reg as u8));
+        active.push(ActiveEntry {
+            interval: LiveInterval(start, end),
+            var,
+            reg
+        });
+    }
 
-        // If there are no available registers, spill this value.
-        // Otherwise, allocate a register and mark the variable as active.
-        if pool.is_empty() {
-            panic!("Spilling is unimplemented");
-        } else {
-            let reg = pool.take();
-            storage.bind(*var, StorageLoc::Gpr(reg as u8));
-            active.push(ActiveEntry {
-                interval: *interval,
-                var: *var,
-                reg
-            });
+    // Find the values in caller-save registers which are live across each
+    // call to a runtime helper. Operands of the calling instruction are 
+    // included, since they may still be read after the call.
+    for (pos, _) in intervals.constraints.iter().filter(|(_, c)| c.call) {
+        let mut saved = Vec::new();
+        for (var, interval) in intervals.data.iter() {
+            if !(interval.0 < *pos && *pos <= interval.1) {
+                continue;
+            }
+            if let Some(StorageLoc::Gpr(r)) = storage.get_at(var, *pos) {
+                let r = HostRegister::from(*r);
+                if RuntimeContext::CALLER_SAVE_REGS.iter()
+                    .any(|x| *x as u8 == r as u8) && !saved.contains(&r) 
+                {
+                    saved.push(r);
+                }
+            }
         }
+        storage.saved.insert(*pos, saved);
     }
 
     storage
 }
-
@@ -1,8 +1,13 @@
 
+use std::convert::TryFrom;
+
 use dynasmrt::x64::{ Assembler, Rq };
 use dynasmrt::{ dynasm, DynasmApi, ExecutableBuffer, AssemblyOffset };
 
 use crate::block::BasicBlock;
+use crate::guest::{ GuestMmu, GuestState, Psr, CpuMode, ExceptionType };
+use crate::fastmem;
+use crate::ir::FlagOp;
 
 /// Function pointer to a block of recompiled code.
 #[repr(transparent)]
@@ -16,15 +21,26 @@ impl BlockFunc {
 
 /// Function pointer to a dispatcher block.
 #[repr(transparent)]
-pub struct DispatcherFunc(pub extern "C" fn(block_func: usize) -> RuntimeExitCode);
+pub struct DispatcherFunc(
+    pub extern "C" fn(ctx: *mut RuntimeContext, block_func: usize) -> usize
+);
 impl DispatcherFunc {
     pub fn ptr(&self) -> usize { self.0 as usize }
 }
 
 /// Trampoline into the runtime at the specified recompiled block.
+///
+/// Returns the raw value if recompiled code returned an invalid exit code.
 #[no_mangle]
-pub fn trampoline(ctx: &mut RuntimeContext, func: BlockFunc) -> RuntimeExitCode {
-    RuntimeExitCode::from( (ctx.dispatcher.0)(func.ptr()) )
+pub fn trampoline(ctx: &mut RuntimeContext, func: BlockFunc) 
+    -> Result<RuntimeExitCode, usize> 
+{
+    let dispatcher = ctx.dispatcher.0;
+    let res = dispatcher(ctx as *mut RuntimeContext, func.ptr());
+    RuntimeContext::materialize_flags(ctx);
+    ctx.exit_code = 0;
+    ctx.stop = 0;
+    RuntimeExitCode::try_from(res)
 }
 
 /// Runtime environment and interfaces for recompiled code.
@@ -43,12 +59,40 @@ pub struct RuntimeContext {
 
     /// Pointer to the current program status register.
     pub cpsr_ptr: usize,
-    pub cycles: usize,
+
+    /// The remaining cycle budget. Each block subtracts its cost on entry, 
+    /// and returns to the dispatcher (instead of branching to another block)
+    /// once this is zero or negative.
+    pub cycles: isize,
+
+    /// Pointer to the guest MMU, used by runtime helpers.
+    pub mmu_ptr: usize,
+
+    /// Exit code requested by a runtime helper (zero if recompiled code
+    /// should continue executing).
+    pub exit_code: usize,
+
+    /// Nonzero if recompiled code should return to the dispatcher at the end
+    /// of the current block, instead of branching directly into the next 
+    /// block (see [crate::block::BlockExit]).
+    pub stop: usize,
+
+    /// Pointer to the [BranchCache], used to dispatch indirect branches.
+    pub branch_cache_ptr: usize,
+
+    /// The last flag-setting operation, if the guest flags in the CPSR 
+    /// haven't been computed yet (see [LazyFlags]).
+    pub lazy_flags: LazyFlags,
+
+    /// The state of the guest's interrupt lines (see [Interrupts]).
+    pub interrupts: Interrupts,
 
     /// Actual storage for the dispatcher code
     _dispatcher: ExecutableBuffer,
 }
 impl RuntimeContext {
+    /// The physical register reserved for a pointer to this structure.
+    pub const CTX_SELF:     Rq = Rq::RBP;
     /// The physical register reserved for the CPSR.
     pub const CTX_CPSR:     Rq = Rq::R13;
     /// The physical register reserved for the base of "fast memory."
@@ -65,17 +109,34 @@ impl RuntimeContext {
         .len() * std::mem::size_of::<usize>();
 
     /// The set of caller-save registers as-defined-by the SysV ABI.
-    const CALLER_SAVE_REGS: [Rq; 7] = [ 
+    pub const CALLER_SAVE_REGS: [Rq; 7] = [ 
         Rq::RAX, Rq::RCX, Rq::RDX, Rq::R8, Rq::R9, Rq::R10, Rq::R11 
     ];
-    /// The size (in bytes) of the caller-save registers.
-    const CALLER_SAVE_SIZE: usize = Self::CALLER_SAVE_REGS
-        .len() * std::mem::size_of::<usize>();
 
+    /// Offsets of the pointers loaded by the dispatcher.
+    const OFF_REGISTER_PTR: i32 = 0x08;
+    const OFF_FASTMEM_PTR:  i32 = 0x10;
+    const OFF_CPSR_PTR:     i32 = 0x18;
+
+    /// Offset of the `cycles` field (see the layout above).
+    pub const OFF_CYCLES: i32 = 0x20;
+    /// Offset of the `exit_code` field (see the layout above).
+    pub const OFF_EXIT_CODE: i32 = 0x30;
+    /// Offset of the `stop` field (see the layout above).
+    pub const OFF_STOP: i32 = 0x38;
+    /// Offset of the `branch_cache_ptr` field (see the layout above).
+    pub const OFF_BRANCH_CACHE: i32 = 0x40;
+    /// Offsets of the fields in `lazy_flags` (see the layout above).
+    pub const OFF_LAZY_OP: i32 = 0x48;
+    pub const OFF_LAZY_X: i32 = 0x50;
+    pub const OFF_LAZY_Y: i32 = 0x54;
 }
 
 impl RuntimeContext {
-    pub fn new(register_ptr: usize, fastmem_ptr: usize, cpsr_ptr: usize) -> Self {
+    pub fn new(register_ptr: usize, fastmem_ptr: usize, cpsr_ptr: usize, 
+        mmu_ptr: usize, branch_cache_ptr: usize) -> Self {
+        fastmem::install_handler();
+
         let mut asm = Assembler::new().unwrap();
 
         dynasm!(asm
@@ -89,9 +150,10 @@ impl RuntimeContext {
             ; sub   rsp, Self::CALLEE_SAVE_SIZE as _
         );
         dynasm!(asm
-            ; mov   Rq(Self::CTX_REG as u8), QWORD register_ptr as _
-            ; mov   Rq(Self::CTX_FASTMEM as u8), QWORD fastmem_ptr as _
-            ; mov   Rq(Self::CTX_CPSR as u8), QWORD cpsr_ptr as _
+            ; mov   Rq(Self::CTX_SELF as u8), rdi
+            ; mov   Rq(Self::CTX_REG as u8), QWORD [rdi + Self::OFF_REGISTER_PTR]
+            ; mov   Rq(Self::CTX_FASTMEM as u8), QWORD [rdi + Self::OFF_FASTMEM_PTR]
+            ; mov   Rq(Self::CTX_CPSR as u8), QWORD [rdi + Self::OFF_CPSR_PTR]
         );
         dynasm!(asm
             ; call  rsi
@@ -108,26 +170,278 @@ impl RuntimeContext {
         );
 
         let buf = asm.finalize().unwrap();
-        println!("[*] dispatcher @ {:016?}", buf.ptr(AssemblyOffset(0)));
         RuntimeContext {
             dispatcher: unsafe { 
-                std::mem::transmute(buf.ptr(AssemblyOffset(0))) 
+                std::mem::transmute::<*const u8, DispatcherFunc>(
+                    buf.ptr(AssemblyOffset(0))
+                ) 
             },
             _dispatcher: buf,
             register_ptr, fastmem_ptr, cpsr_ptr,
-            cycles: 0
+            cycles: 0,
+            mmu_ptr,
+            exit_code: 0,
+            stop: 0,
+            branch_cache_ptr,
+            lazy_flags: LazyFlags::default(),
+            interrupts: Interrupts::default(),
+        }
+    }
+}
+
+/// A flag-setting operation (see [crate::ir::FlagOp]) and its operands, 
+/// recorded by recompiled code instead of computing the guest flags.
+///
+/// Flags are usually overwritten before anything reads them, so they're only
+/// computed (see [RuntimeContext::materialize_flags]) before recompiled code
+/// reads or writes individual flags, before writing the CPSR, and when 
+/// returning to the dispatcher. Otherwise, this may be carried across linked
+/// blocks.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LazyFlags {
+    /// The operation (zero if the flags in the CPSR are up-to-date).
+    pub op: usize,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// The state of the guest's interrupt lines.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Interrupts {
+    /// The IRQ line is asserted.
+    pub irq: bool,
+    /// The FIQ line is asserted.
+    pub fiq: bool,
+}
+impl Interrupts {
+    /// Return the highest-priority exception which should be taken with
+    /// some value of the CPSR.
+    pub fn pending(&self, cpsr: Psr) -> Option<ExceptionType> {
+        if self.fiq && !cpsr.fiq_disable() {
+            Some(ExceptionType::Fiq)
+        } else if self.irq && !cpsr.irq_disable() {
+            Some(ExceptionType::Irq)
+        } else {
+            None
+        }
+    }
+}
+
+/// An entry in the [BranchCache] (an empty entry has a null host address).
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BranchCacheEntry {
+    /// Guest address.
+    pub pc: u32,
+    /// Host address of the recompiled code for the guest address.
+    pub host: usize,
+}
+impl BranchCacheEntry {
+    const EMPTY: BranchCacheEntry = BranchCacheEntry { pc: 0, host: 0 };
+}
+
+/// Tables used by recompiled code to find the target of an indirect branch
+/// without returning to the dispatcher.
+///
+/// - `table` is a direct-mapped cache of all recompiled blocks,
+/// - `ras` is a return address prediction stack, which is pushed when a 
+///   block branches with link, and popped when a block returns.
+///
+/// The host addresses in both must always be valid, so they're discarded
+/// whenever a block is discarded.
+#[repr(C)]
+pub struct BranchCache {
+    pub table: [BranchCacheEntry; BranchCache::TABLE_SIZE],
+    pub ras: [BranchCacheEntry; BranchCache::RAS_SIZE],
+    /// Index of the top of the return address stack.
+    pub ras_top: usize,
+}
+impl BranchCache {
+    pub const TABLE_SIZE: usize = 0x1000;
+    pub const RAS_SIZE: usize = 16;
+
+    /// Offsets of each field (see the layout above).
+    pub const OFF_TABLE: i32 = 0;
+    pub const OFF_RAS: i32 = (Self::TABLE_SIZE * Self::ENTRY_SIZE) as i32;
+    pub const OFF_RAS_TOP: i32 = Self::OFF_RAS 
+        + (Self::RAS_SIZE * Self::ENTRY_SIZE) as i32;
+    pub const ENTRY_SIZE: usize = std::mem::size_of::<BranchCacheEntry>();
+
+    pub fn new() -> Box<Self> {
+        Box::new(BranchCache {
+            table: [BranchCacheEntry::EMPTY; Self::TABLE_SIZE],
+            ras: [BranchCacheEntry::EMPTY; Self::RAS_SIZE],
+            ras_top: 0,
+        })
+    }
+
+    /// The index of the entry in `table` for a guest address.
+    pub fn index(pc: u32) -> usize { 
+        ((pc >> 2) as usize) & (Self::TABLE_SIZE - 1) 
+    }
+
+    /// Add a recompiled block to the cache.
+    pub fn insert(&mut self, pc: u32, host: *const u8) {
+        self.table[Self::index(pc)] = BranchCacheEntry { pc, host: host as usize };
+    }
+
+    /// Remove a recompiled block from the cache.
+    pub fn remove(&mut self, pc: u32) {
+        let e = &mut self.table[Self::index(pc)];
+        if e.pc == pc {
+            *e = BranchCacheEntry::EMPTY;
+        }
+        // Predictions might point at the block, and it's not worth checking
+        for e in self.ras.iter_mut() {
+            *e = BranchCacheEntry::EMPTY;
+        }
+    }
+
+    /// Remove all recompiled blocks from the cache.
+    pub fn clear(&mut self) {
+        for e in self.table.iter_mut().chain(self.ras.iter_mut()) {
+            *e = BranchCacheEntry::EMPTY;
         }
     }
 }
 
+/// The reason that recompiled code returned to the dispatcher.
+///
+/// For all exits other than `NextBlock`, the program counter points at the
+/// guest instruction which caused the exit.
 #[repr(usize)]
-pub enum RuntimeExitCode { NextBlock, Halt }
-impl From<usize> for RuntimeExitCode {
-    fn from(x: usize) -> Self {
-        match x {
-            0 => RuntimeExitCode::NextBlock,
-            1 => RuntimeExitCode::Halt,
-            _ => panic!("Unhandled block return code {}", x),
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeExitCode { 
+    /// Continue at the program counter.
+    NextBlock, 
+    Halt, 
+    /// A memory access caused a data abort.
+    DataAbort,
+    /// A memory access touched unmapped physical memory.
+    MemoryFault,
+    /// A `bkpt` instruction.
+    Breakpoint,
+    /// An `svc` instruction.
+    Svc,
+    /// An undefined instruction.
+    Undefined,
+    /// A write to the CPSR with an invalid mode.
+    InvalidMode,
+}
+impl TryFrom<usize> for RuntimeExitCode {
+    type Error = usize;
+    fn try_from(x: usize) -> Result<Self, usize> {
+        use RuntimeExitCode::*;
+        Ok(match x {
+            0 => NextBlock, 1 => Halt, 2 => DataAbort, 3 => MemoryFault,
+            4 => Breakpoint, 5 => Svc, 6 => Undefined, 7 => InvalidMode,
+            _ => return Err(x),
+        })
+    }
+}
+
+
+/// Helper functions called from recompiled code.
+///
+/// These are used when a memory access can't be performed directly on fast 
+/// memory (i.e. when the guest MMU is enabled, or after a fast memory access
+/// to memory-mapped I/O has been patched, see [crate::fastmem]). When an access faults, the helper records the fault and requests an 
+/// exit from recompiled code.
+impl RuntimeContext {
+    unsafe fn mmu(&mut self) -> &mut GuestMmu { 
+        &mut *(self.mmu_ptr as *mut GuestMmu) 
+    }
+    unsafe fn privileged(&self) -> bool {
+        (*(self.cpsr_ptr as *const Psr)).is_privileged()
+    }
+    /// NOTE: The guest registers are the first field in [GuestState].
+    unsafe fn state(&mut self) -> &mut GuestState {
+        &mut *(self.register_ptr as *mut GuestState)
+    }
+
+    /// Compute the guest flags from the last flag-setting operation, if 
+    /// there is one (see [LazyFlags]).
+    pub extern "C" fn materialize_flags(ctx: &mut RuntimeContext) {
+        let lazy = ctx.lazy_flags;
+        if let Some(op) = FlagOp::from_usize(lazy.op) {
+            let state = unsafe { ctx.state() };
+            state.cpsr.0 = op.apply(state.cpsr.0, lazy.x, lazy.y);
+        }
+        ctx.lazy_flags.op = 0;
+    }
+
+    /// Write the bits selected by `mask` into the CPSR (only the condition
+    /// flags can be written in user mode).
+    pub extern "C" fn write_cpsr(ctx: &mut RuntimeContext, val: u32, mask: u32) {
+        RuntimeContext::materialize_flags(ctx);
+        let interrupts = ctx.interrupts;
+        let state = unsafe { ctx.state() };
+        let mask = if state.cpsr.is_privileged() { 
+            mask 
+        } else { 
+            mask & 0xf000_0000 
+        };
+        let new_cpsr = Psr((state.cpsr.0 & !mask) | (val & mask));
+        let mode = match CpuMode::try_from(new_cpsr.0 & 0x1f) {
+            Ok(mode) => mode,
+            Err(_) => {
+                ctx.exit_code = RuntimeExitCode::InvalidMode as usize;
+                return;
+            },
+        };
+        state.switch_mode(mode);
+        state.cpsr = new_cpsr;
+
+        // If this unmasked a pending interrupt, we need to take it at the
+        // end of this block
+        if interrupts.pending(new_cpsr).is_some() {
+            ctx.stop = 1;
+        }
+    }
+
+    pub extern "C" fn load32(ctx: &mut RuntimeContext, addr: u32) -> u32 {
+        let privileged = unsafe { ctx.privileged() };
+        let mmu = unsafe { ctx.mmu() };
+        mmu.take_unmapped();
+        let res = mmu.load32(addr, privileged);
+        if let Err(fault) = res {
+            mmu.vmsa.record_fault(fault);
+        }
+        let unmapped = mmu.unmapped().is_some();
+        match res {
+            Ok(val) => {
+                if unmapped {
+                    ctx.exit_code = RuntimeExitCode::MemoryFault as usize;
+                }
+                val
+            },
+            Err(_) => {
+                ctx.exit_code = RuntimeExitCode::DataAbort as usize;
+                0
+            },
+        }
+    }
+
+    pub extern "C" fn store32(ctx: &mut RuntimeContext, addr: u32, val: u32) {
+        let privileged = unsafe { ctx.privileged() };
+        let mmu = unsafe { ctx.mmu() };
+        mmu.take_unmapped();
+        let res = mmu.store32(addr, val, privileged);
+        if let Err(fault) = res {
+            mmu.vmsa.record_fault(fault);
+        }
+        let unmapped = mmu.unmapped().is_some();
+        // Blocks on a page we just wrote may be stale, so we can't follow
+        // any more links into them
+        if mmu.has_dirty_pages() {
+            ctx.stop = 1;
+        }
+        if res.is_err() {
+            ctx.exit_code = RuntimeExitCode::DataAbort as usize;
+        } else if unmapped {
+            ctx.exit_code = RuntimeExitCode::MemoryFault as usize;
         }
     }
 }
@@ -0,0 +1,95 @@
+//! Instruction timing.
+//!
+//! Costs are approximately those of an ARM926EJ-S core (in core clock cycles),
+//! assuming that all memory accesses hit in the caches and that there are no
+//! interlocks. Users can override the cost of any kind of instruction.
+
+use std::collections::HashMap;
+
+use crate::lift::decode::ArmInst;
+
+/// The cost (in cycles) of each kind of ARM instruction.
+pub struct CycleTable {
+    /// User-defined costs, which replace the defaults.
+    overrides: HashMap<ArmInst, usize>,
+}
+impl CycleTable {
+    pub fn new() -> Self {
+        CycleTable { overrides: HashMap::new() }
+    }
+
+    /// Override the cost of some kind of instruction.
+    pub fn set(&mut self, inst: ArmInst, cycles: usize) {
+        self.overrides.insert(inst, cycles);
+    }
+
+    /// The cost of an instruction.
+    pub fn cost(&self, opcd: u32) -> usize {
+        let inst = ArmInst::decode(opcd);
+        match self.overrides.get(&inst) {
+            Some(cycles) => *cycles,
+            None => Self::arm926(inst, opcd),
+        }
+    }
+
+    /// The total cost of a sequence of instructions.
+    pub fn cost_all(&self, opcds: &[u32]) -> usize {
+        opcds.iter().map(|opcd| self.cost(*opcd)).sum()
+    }
+
+    /// The default cost of an instruction.
+    fn arm926(inst: ArmInst, opcd: u32) -> usize {
+        use ArmInst::*;
+
+        // Writing the PC refills the pipeline
+        let rd_pc = (opcd & 0x0000_f000) == 0x0000_f000;
+        let refill = if rd_pc { 2 } else { 0 };
+        let list = (opcd & 0xffff).count_ones() as usize;
+        let list_pc = (opcd & 0x8000) != 0;
+
+        match inst {
+            CmpReg | CmnReg | TstReg | TeqReg |
+            CmpImm | CmnImm | TstImm | TeqImm => 1,
+            CmpRegShiftReg | CmnRegShiftReg | TstRegShiftReg | 
+            TeqRegShiftReg => 2,
+
+            AndRegShiftReg | AdcRegShiftReg | MovRegShiftReg | OrrRegShiftReg |
+            EorRegShiftReg | RscRegShiftReg | MvnRegShiftReg | SbcRegShiftReg |
+            AddRegShiftReg | BicRegShiftReg | RsbRegShiftReg | 
+            SubRegShiftReg => 2 + refill,
+
+            SbcReg | OrrReg | BicReg | AddReg | RscReg | EorReg | MvnReg | 
+            AdcReg | SubReg | MovReg | AndReg | RsbReg |
+            MovImm | AddImm | AdcImm | RsbImm | OrrImm | BicImm | SubImm | 
+            MvnImm | AndImm | RscImm | EorImm | SbcImm | MovImmAlt |
+            Clz | Qdadd | Qsub | Qadd | Qdsub => 1 + refill,
+
+            Mul | Mla | Smulwb | Smlawb | Smlabb | Smulbb => 2,
+            Smull | Umull | Umlal | Smlal | Smlalbb => 3,
+
+            LdrImm | LdrReg | Ldrt | LdrtAlt => if rd_pc { 5 } else { 1 },
+            LdrhImm | LdrbImm | LdrsbImm | LdrshImm | LdrbReg | LdrhReg |
+            LdrsbReg | LdrshReg | Ldrbt | LdrbtAlt => 1,
+            StrImm | StrhImm | StrbImm | StrReg | StrbReg | StrhReg |
+            Strbt | StrbtAlt | Strt | StrtAlt => 1,
+            LdrdImm | LdrdReg | StrdImm | StrdReg => 2,
+
+            Ldm | Ldmda | Ldmib | Ldmdb | LdmRegUser => {
+                list.max(2) + if list_pc { 4 } else { 0 }
+            },
+            Stm | Stmda | Stmib | Stmdb | StmRegUser => list.max(2),
+
+            B | BlImm | Bx | BlxReg | Bxj => 3,
+            MsrImm | MsrReg => 3,
+            Mrs | Mcr | Mrc | Stc | LdcImm => 2,
+            Mcrr | Mrrc => 3,
+            PldReg | PldImm => 1,
+
+            // Exception entry
+            Svc | Bkpt | Undefined => 3,
+        }
+    }
+}
+impl Default for CycleTable {
+    fn default() -> Self { CycleTable::new() }
+}
@@ -0,0 +1,58 @@
+//! Helpers shared by the integration tests.
+
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+
+use nil::block::BasicBlock;
+use nil::guest::{ GuestState, GuestMmu };
+use nil::mem::Endianness;
+use nil::runtime::{ 
+    self, RuntimeContext, RuntimeExitCode, BlockFunc, BranchCache 
+};
+
+/// Write a program into guest memory (in the guest's byte order).
+pub fn load(mmu: &mut GuestMmu, addr: u32, code: &[u32]) {
+    let buf: Vec<u8> = code.iter().flat_map(|op| match mmu.endianness {
+        Endianness::Big => op.to_be_bytes(),
+        Endianness::Little => op.to_le_bytes(),
+    }).collect();
+    mmu.write_buf(addr, &buf);
+}
+
+/// Lift and recompile the block at the program counter.
+pub fn compile(state: &GuestState, mmu: &mut GuestMmu) -> BasicBlock {
+    let mut bb = BasicBlock::lift(state, mmu, &HashSet::new()).unwrap();
+    bb.prune_dead_vars();
+    bb.recompile(mmu).unwrap();
+    bb
+}
+
+/// Run a recompiled block.
+pub fn enter(state: &mut GuestState, mmu: &mut GuestMmu, bb: &BasicBlock) 
+    -> RuntimeExitCode 
+{
+    enter_with(state, mmu, bb, &mut BranchCache::new())
+}
+
+/// Run a recompiled block, with some [BranchCache] for indirect branches.
+pub fn enter_with(state: &mut GuestState, mmu: &mut GuestMmu, 
+    bb: &BasicBlock, cache: &mut BranchCache) -> RuntimeExitCode 
+{
+    let mut ctx = RuntimeContext::new(
+        state.reg.as_ptr() as usize,
+        mmu.fastmem_base(),
+        &state.cpsr as *const _ as usize,
+        mmu as *mut GuestMmu as usize,
+        cache as *mut BranchCache as usize,
+    );
+    ctx.cycles = isize::MAX;
+    runtime::trampoline(&mut ctx, BlockFunc::from_block(bb)).unwrap()
+}
+
+/// Recompile and run the block at the program counter.
+pub fn run_block(state: &mut GuestState, mmu: &mut GuestMmu) -> RuntimeExitCode {
+    let bb = compile(state, mmu);
+    enter(state, mmu, &bb)
+}
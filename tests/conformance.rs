@@ -0,0 +1,733 @@
+//! Conformance tests for recompiled code.
+//!
+//! Each case is a single ARM instruction, which is run through the whole
+//! pipeline (lifting, recompiling, and the dispatcher) from randomized initial
+//! registers and flags. The result is compared against the interpreter in
+//! [nil::interp], which serves as the reference model. Blocks are run in
+//! [Backend::Lockstep], so recompiled code is also checked against the IR
+//! evaluator. Each instruction is followed by a `bkpt`, so that blocks end
+//! the same way as they do in real code (rather than at a breakpoint). Some
+//! sequences of instructions are also run as a single block, where values 
+//! are kept in host registers between instructions.
+//!
+//! Instructions which the lifter doesn't support are run in the interpreter
+//! by the JIT too, so there's nothing to compare for those cases (but they
+//! must never be interpreted when they could have been recompiled).
+
+use std::collections::HashSet;
+
+use nil::{ Jit, Backend, StopReason };
+use nil::block::BasicBlock;
+use nil::guest::{ GuestState, GuestMmu, Psr, CpuMode };
+use nil::runtime::RuntimeExitCode;
+use nil::lift::decode::ArmInst;
+use nil::ir::{ Operation, BindOp };
+
+/// Address of the instruction under test.
+const CODE: u32 = 0x0000_1000;
+/// Pointers into this region are handed out to instructions which access
+/// memory.
+const DATA: u32 = 0x0000_8000;
+/// The region of memory compared after each instruction.
+const DATA_LO: u32 = DATA - 0x800;
+const DATA_HI: u32 = DATA + 0x800;
+
+/// The number of random initial states for each case.
+const ITERATIONS: usize = 16;
+
+/// A small deterministic PRNG (xorshift64), so that failures are
+/// reproducible.
+struct Rng(u64);
+impl Rng {
+    fn new(seed: u32) -> Self {
+        Rng(0x9e37_79b9_7f4a_7c15 ^ ((seed as u64) << 16 | 1))
+    }
+    fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 16) as u32
+    }
+}
+
+/// A single instruction under test.
+#[derive(Clone, Copy)]
+struct Case {
+    opcd: u32,
+    /// Registers which hold a (word-aligned) pointer into [DATA].
+    ptr: u16,
+    /// Registers which hold a small multiple of 4 (i.e. offsets).
+    small: u16,
+}
+impl Case {
+    fn new(opcd: u32) -> Self { Case { opcd, ptr: 0, small: 0 } }
+    fn ptr(mut self, regs: &[u32]) -> Self {
+        for r in regs { self.ptr |= 1 << r; }
+        self
+    }
+    fn small(mut self, regs: &[u32]) -> Self {
+        for r in regs { self.small |= 1 << r; }
+        self
+    }
+
+    /// Generate a random initial state for this case.
+    fn state(&self, rng: &mut Rng) -> GuestState {
+        // Start in supervisor mode with random flags
+        let mut cpsr = Psr((rng.next_u32() & 0xf800_0000) | 0xc0);
+        cpsr.set_mode(CpuMode::Svc);
+        let mut state = GuestState::new(CODE, cpsr.0);
+        for idx in 0..15 {
+            state.reg[idx] = if (self.ptr & (1 << idx)) != 0 {
+                DATA + (rng.next_u32() & 0x3fc)
+            } else if (self.small & (1 << idx)) != 0 {
+                rng.next_u32() & 0x3c
+            } else {
+                rng.next_u32()
+            };
+        }
+        state.set_spsr(Psr((rng.next_u32() & 0xf800_0000) | 0xd3));
+        state
+    }
+}
+
+// Encodings (always with the AL condition)
+
+fn dp_imm(op: u32, s: u32, rn: u32, rd: u32, imm12: u32) -> u32 {
+    0xe200_0000 | op << 21 | s << 20 | rn << 16 | rd << 12 | imm12
+}
+fn dp_reg(op: u32, s: u32, rn: u32, rd: u32, imm5: u32, stype: u32, rm: u32)
+    -> u32
+{
+    0xe000_0000 | op << 21 | s << 20 | rn << 16 | rd << 12
+        | imm5 << 7 | stype << 5 | rm
+}
+fn dp_rsr(op: u32, s: u32, rn: u32, rd: u32, rs: u32, stype: u32, rm: u32)
+    -> u32
+{
+    0xe000_0010 | op << 21 | s << 20 | rn << 16 | rd << 12
+        | rs << 8 | stype << 5 | rm
+}
+#[allow(clippy::too_many_arguments)]
+fn ls_imm(p: u32, u: u32, b: u32, w: u32, l: u32, rn: u32, rt: u32,
+    imm12: u32) -> u32
+{
+    0xe400_0000 | p << 24 | u << 23 | b << 22 | w << 21 | l << 20
+        | rn << 16 | rt << 12 | imm12
+}
+#[allow(clippy::too_many_arguments)]
+fn ls_reg(p: u32, u: u32, b: u32, w: u32, l: u32, rn: u32, rt: u32,
+    imm5: u32, rm: u32) -> u32
+{
+    0xe600_0000 | p << 24 | u << 23 | b << 22 | w << 21 | l << 20
+        | rn << 16 | rt << 12 | imm5 << 7 | rm
+}
+#[allow(clippy::too_many_arguments)]
+fn ls_misc(p: u32, u: u32, i: u32, w: u32, l: u32, rn: u32, rt: u32,
+    sh: u32, lo: u32) -> u32
+{
+    0xe000_0090 | p << 24 | u << 23 | i << 22 | w << 21 | l << 20
+        | rn << 16 | rt << 12 | sh << 5 | lo
+}
+fn ls_multi(p: u32, u: u32, s: u32, w: u32, l: u32, rn: u32, list: u32)
+    -> u32
+{
+    0xe800_0000 | p << 24 | u << 23 | s << 22 | w << 21 | l << 20
+        | rn << 16 | list
+}
+
+/// A `bkpt` after the code under test, which ends the block.
+const BKPT: u32 = 0xe120_0070;
+
+/// Set up a machine with `code` at [CODE] (followed by [BKPT]), and some 
+/// random data.
+fn machine(code: &[u32], state: &GuestState, rng: &mut Rng) -> (Jit, GuestMmu) {
+    let mut jit = Jit::new();
+    let mut mmu = GuestMmu::new();
+    jit.state = *state;
+    for addr in (DATA_LO..DATA_HI).step_by(4) {
+        let val = rng.next_u32();
+        jit.mmu.store32(addr, val, true).unwrap();
+        mmu.store32(addr, val, true).unwrap();
+    }
+    for (idx, opcd) in code.iter().chain([BKPT].iter()).enumerate() {
+        let addr = CODE + idx as u32 * 4;
+        jit.mmu.store32(addr, *opcd, true).unwrap();
+        mmu.store32(addr, *opcd, true).unwrap();
+    }
+    jit.set_backend(Backend::Lockstep);
+    (jit, mmu)
+}
+
+/// Returns true if `code` can be lifted and recompiled into a block (from 
+/// some initial state).
+fn recompilable(code: &[u32], state: &GuestState) -> bool {
+    let mut mmu = GuestMmu::new();
+    for (idx, opcd) in code.iter().chain([BKPT].iter()).enumerate() {
+        mmu.store32(CODE + idx as u32 * 4, *opcd, true).unwrap();
+    }
+    match BasicBlock::lift(state, &mut mmu, &HashSet::new()) {
+        Ok(mut bb) => {
+            bb.prune_dead_vars();
+            bb.recompile(&mmu).is_ok()
+        },
+        Err(_) => false,
+    }
+}
+
+/// Run a case from some random initial states, comparing recompiled code
+/// against the interpreter.
+fn check(case: Case) {
+    check_block(&[case.opcd], case);
+}
+
+/// Run a sequence of instructions (in a single block, when they can all be 
+/// recompiled) from some random initial states, where registers are chosen
+/// by `case`. Recompiled code runs until the end of the first block, and the
+/// interpreter runs the same instructions.
+fn check_block(code: &[u32], case: Case) {
+    let mut rng = Rng::new(case.opcd);
+    for _ in 0..ITERATIONS {
+        let init = case.state(&mut rng);
+        let recompiled = recompilable(code, &init);
+        let (mut jit, mut mmu) = machine(code, &init, &mut rng);
+        let mut reference = init;
+
+        let res = jit.step();
+        assert_eq!(jit.cache.contains_key(&CODE), recompiled,
+            "{:08x}: recompiled code", code[0]);
+        if !recompiled {
+            continue;
+        }
+
+        // Follow the instructions in the block, until one of them branches 
+        // or exits
+        let len = jit.cache[&CODE].guest_ops.len().min(code.len());
+        let mut ref_res = Ok(RuntimeExitCode::NextBlock);
+        for _ in 0..len {
+            let idx = (reference.pc.0.wrapping_sub(CODE) / 4) as usize;
+            if idx >= len {
+                break;
+            }
+            ref_res = nil::interp::step(&mut reference, &mut mmu, code[idx]);
+            if ref_res != Ok(RuntimeExitCode::NextBlock) {
+                break;
+            }
+        }
+        match (&res, &ref_res) {
+            (Ok(_), Ok(_)) => {},
+            (Err(_), Err(_)) => continue,
+            _ => panic!("{:08x?}: {:?} (jit) != {:?} (interp)",
+                code, res, ref_res),
+        }
+
+        let (s, r) = (&jit.state, &reference);
+        for idx in 0..15 {
+            assert_eq!(s.reg[idx], r.reg[idx], "{:08x?}: r{} from {:08x?}",
+                code, idx, init.reg);
+        }
+        assert_eq!(s.pc.0, r.pc.0, "{:08x?}: pc from {:08x?}",
+            code, init.reg);
+        assert_eq!(s.cpsr, r.cpsr, "{:08x?}: cpsr from {:08x} {:08x?}",
+            code, init.cpsr.0, init.reg);
+        assert!(s.bank == r.bank, "{:08x?}: banked registers", code);
+        for addr in (DATA_LO..DATA_HI).step_by(4) {
+            assert_eq!(jit.mmu.load32(addr, true).unwrap(),
+                mmu.load32(addr, true).unwrap(),
+                "{:08x?}: [{:08x}] from {:08x?}", code, addr, init.reg);
+        }
+    }
+}
+
+/// Run a single instruction through recompiled code from a fixed state.
+fn run(opcd: u32, regs: &[(usize, u32)], flags: u32) -> GuestState {
+    let mut rng = Rng::new(opcd);
+    let mut init = Case::new(opcd).state(&mut rng);
+    init.cpsr = Psr((init.cpsr.0 & 0x0fff_ffff) | flags);
+    for (idx, val) in regs.iter() {
+        init.reg[*idx] = *val;
+    }
+    let (mut jit, _) = machine(&[opcd], &init, &mut rng);
+    jit.step().unwrap();
+    jit.state
+}
+
+fn data_processing_cases() -> Vec<Case> {
+    let mut cases = Vec::new();
+    for op in 0..16 {
+        let test = (8..=11).contains(&op);
+        let rn = if op == 13 || op == 15 { 0 } else { 1 };
+        let rd = if test { 0 } else { 3 };
+        for s in if test { 1..2 } else { 0..2 } {
+            for imm12 in [0x000, 0x0ff, 0x4ff, 0xf01, 0x2c0].iter() {
+                cases.push(Case::new(dp_imm(op, s, rn, rd, *imm12)));
+            }
+            for stype in 0..4 {
+                for imm5 in [0, 1, 31].iter() {
+                    cases.push(Case::new(dp_reg(op, s, rn, rd, *imm5, stype, 2)));
+                }
+                cases.push(Case::new(dp_rsr(op, s, rn, rd, 4, stype, 2)));
+                cases.push(Case::new(dp_rsr(op, s, rn, rd, 4, stype, 2))
+                    .small(&[4]));
+            }
+        }
+    }
+
+    // Program counter operands
+    cases.push(Case::new(dp_imm(4, 0, 15, 0, 4)));
+    cases.push(Case::new(dp_reg(13, 0, 0, 0, 0, 0, 15)));
+    cases.push(Case::new(dp_reg(4, 1, 1, 0, 1, 0, 15)));
+    cases.push(Case::new(dp_reg(13, 0, 0, 15, 0, 0, 1)).ptr(&[1]));
+    cases.push(Case::new(dp_imm(4, 0, 15, 15, 8)));
+
+    // Conditions
+    for cond in 0..15 {
+        cases.push(Case::new(dp_imm(4, 0, 1, 0, 1) & 0x0fff_ffff | cond << 28));
+    }
+    cases
+}
+
+fn multiply_cases() -> Vec<Case> {
+    let mut cases = Vec::new();
+    for s in 0..2 {
+        cases.push(Case::new(0xe000_0090 | s << 20 | 2 << 8 | 1));
+        cases.push(Case::new(0xe020_0090 | s << 20 | 3 << 12
+            | 2 << 8 | 1));
+        for op in [0xe080_0090, 0xe0a0_0090, 0xe0c0_0090, 0xe0e0_0090].iter() {
+            cases.push(Case::new(op | s << 20 | 4 << 16 | 5 << 12 | 2 << 8 | 1));
+        }
+    }
+    // Saturating arithmetic
+    for op in [0xe100_0050, 0xe120_0050, 0xe140_0050, 0xe160_0050].iter() {
+        cases.push(Case::new(op | 1 << 16 | 2));
+        cases.push(Case::new(op | 1 << 16 | 2).small(&[1, 2]));
+    }
+    // Halfword multiplies
+    for y in 0..2 {
+        for x in 0..2 {
+            let yx = y << 6 | x << 5;
+            cases.push(Case::new(0xe160_0080 | 2 << 8 | yx | 1));
+            cases.push(Case::new(0xe100_0080 | 3 << 12 | 2 << 8 | yx | 1));
+            cases.push(Case::new(0xe140_0080 | 4 << 16 | 5 << 12 | 2 << 8
+                | yx | 1));
+        }
+        cases.push(Case::new(0xe120_00a0 | 2 << 8 | y << 6 | 1));
+        cases.push(Case::new(0xe120_0080 | 3 << 12 | 2 << 8 | y << 6 | 1));
+    }
+    // Count leading zeros
+    cases.push(Case::new(0xe16f_0f11));
+    cases.push(Case::new(0xe16f_0f11).small(&[1]));
+    cases
+}
+
+fn load_store_cases() -> Vec<Case> {
+    let mut cases = Vec::new();
+    for l in 0..2 {
+        for b in 0..2 {
+            for u in 0..2 {
+                for (p, w) in [(1, 0), (1, 1), (0, 0)].iter() {
+                    for imm12 in [0, 4, 0x40].iter() {
+                        cases.push(Case::new(ls_imm(*p, u, b, *w, l, 1, 0,
+                            *imm12)).ptr(&[1]));
+                    }
+                    for imm5 in [0, 2].iter() {
+                        cases.push(Case::new(ls_reg(*p, u, b, *w, l, 1, 0,
+                            *imm5, 2)).ptr(&[1]).small(&[2]));
+                    }
+                }
+                // Unprivileged (post-indexed) forms
+                cases.push(Case::new(ls_imm(0, u, b, 1, l, 1, 0, 4)).ptr(&[1]));
+                cases.push(Case::new(ls_reg(0, u, b, 1, l, 1, 0, 0, 2))
+                    .ptr(&[1]).small(&[2]));
+            }
+        }
+    }
+    // Program counter operands
+    cases.push(Case::new(ls_imm(1, 0, 0, 0, 1, 15, 0, 8)));
+
+    // Halfword, signed, and doubleword forms
+    for (l, sh) in [(0, 1), (0, 2), (0, 3), (1, 1), (1, 2), (1, 3)].iter() {
+        let rt = if *l == 0 && *sh != 1 { 4 } else { 0 };
+        for u in 0..2 {
+            for (p, w) in [(1, 0), (1, 1), (0, 0)].iter() {
+                cases.push(Case::new(ls_misc(*p, u, 1, *w, *l, 1, rt, *sh, 8))
+                    .ptr(&[1]));
+                cases.push(Case::new(ls_misc(*p, u, 0, *w, *l, 1, rt, *sh, 2))
+                    .ptr(&[1]).small(&[2]));
+            }
+        }
+    }
+
+    // Multiple registers
+    for l in 0..2 {
+        for p in 0..2 {
+            for u in 0..2 {
+                for w in 0..2 {
+                    cases.push(Case::new(ls_multi(p, u, 0, w, l, 13, 0x002f))
+                        .ptr(&[13]));
+                }
+                // User mode registers
+                cases.push(Case::new(ls_multi(p, u, 1, 0, l, 13, 0x6001))
+                    .ptr(&[13]));
+            }
+        }
+    }
+
+    // Preload
+    cases.push(Case::new(0xf5d1_f000).ptr(&[1]));
+    cases.push(Case::new(0xf7d1_f002).ptr(&[1]).small(&[2]));
+    cases
+}
+
+fn branch_cases() -> Vec<Case> {
+    vec![
+        Case::new(0xea00_0010),
+        Case::new(0xeaff_fffe),
+        Case::new(0xeb00_0010),
+        Case::new(0xebff_fff0),
+        Case::new(0x0a00_0010),
+        Case::new(0xe12f_ff11).ptr(&[1]),
+        Case::new(0xe12f_ff21).ptr(&[1]),
+        Case::new(0xe12f_ff31).ptr(&[1]),
+    ]
+}
+
+fn status_cases() -> Vec<Case> {
+    vec![
+        // mrs r0, cpsr; mrs r0, spsr
+        Case::new(0xe10f_0000),
+        Case::new(0xe14f_0000),
+        // msr cpsr_f, r1; msr spsr_fsxc, r1
+        Case::new(0xe128_f001),
+        Case::new(0xe16f_f001),
+        // msr cpsr_f, #0xf0000000; msr cpsr_c, #0xd3; msr cpsr_c, #0xdf
+        Case::new(0xe328_f4f0),
+        Case::new(0xe321_f0d3),
+        Case::new(0xe321_f0df),
+    ]
+}
+
+fn coprocessor_cases() -> Vec<Case> {
+    vec![
+        // mrc p15, 0, r0, c0, c0, 0; mrc p15, 0, r0, c1, c0, 0
+        Case::new(0xee10_0f10),
+        Case::new(0xee11_0f10),
+        // mcr p15, 0, r0, c7, c10, 4
+        Case::new(0xee07_0f9a),
+        // mrc p14, ...
+        Case::new(0xee10_0e10),
+        // mcrr, mrrc, stc, ldc
+        Case::new(0xec41_0f00),
+        Case::new(0xec51_0f00),
+        Case::new(0xed81_0f00).ptr(&[1]),
+        Case::new(0xed91_0f00).ptr(&[1]),
+    ]
+}
+
+fn exception_cases() -> Vec<Case> {
+    vec![
+        // svc, svceq, bkpt, undefined, movw
+        Case::new(0xef00_0042),
+        Case::new(0x0f00_0042),
+        Case::new(0xe121_2374),
+        Case::new(0xe7f0_00f0),
+        Case::new(0xe300_1234),
+    ]
+}
+
+/// Sequences of instructions which are recompiled into a single block (the 
+/// registers for each sequence are chosen by the [Case]).
+fn block_cases() -> Vec<(Vec<u32>, Case)> {
+    vec![
+        // sub r0, r1, #1; mov r2, r0; sub r3, r2, #4; mov r4, #0x10
+        (vec![0xe241_0001, 0xe1a0_2000, 0xe242_3004, 0xe3a0_4010], 
+            Case::new(0)),
+        // msr cpsr_f, r1; mov r0, r2; sub r3, r0, #1
+        (vec![0xe128_f001, 0xe1a0_0002, 0xe240_3001], Case::new(1)),
+        // ldr r0, [r1]; sub r0, r0, #1; str r0, [r1, #4]!; ldr r2, [r1]
+        (vec![0xe591_0000, 0xe240_0001, 0xe5a1_0004, 0xe591_2000],
+            Case::new(2).ptr(&[1])),
+        // stmdb sp!, {r0-r3}; ldr r4, [sp]; ldr r5, [sp, #12]; mov r6, sp
+        (vec![0xe92d_000f, 0xe59d_4000, 0xe59d_500c, 0xe1a0_600d], 
+            Case::new(3).ptr(&[13])),
+        // Values live across many instructions
+        // mov r0, r1; mov r3, r4; sub r6, r7, #1; sub r9, r10, #2; 
+        // mov r12, r0; sub r1, r6, #3; mov r2, r12; sub r4, r2, #4
+        (vec![0xe1a0_0001, 0xe1a0_3004, 0xe247_6001, 0xe24a_9002,
+            0xe1a0_c000, 0xe246_1003, 0xe1a0_200c, 0xe242_4004], 
+            Case::new(4)),
+    ]
+}
+
+/// Sequences which set the flags, and use them later in the block (or at the
+/// end of it).
+fn flag_cases() -> Vec<(Vec<u32>, Case)> {
+    vec![
+        // subs r0, r1, #1; mov r2, r0
+        (vec![0xe251_0001, 0xe1a0_2000], Case::new(8).small(&[1])),
+        (vec![0xe251_0001, 0xe1a0_2000], Case::new(9)),
+        // subs r0, r1, #0x10; cmp r0, #4; mov r3, r0
+        (vec![0xe251_0010, 0xe350_0004, 0xe1a0_3000], 
+            Case::new(10).small(&[1])),
+        // msr cpsr_f, r2; subs r0, r1, #1
+        (vec![0xe128_f002, 0xe251_0001], Case::new(11)),
+        // cmp r1, #4; beq .+8
+        (vec![0xe351_0004, 0x0a00_0000], Case::new(12).small(&[1])),
+        // subs r1, r1, #4; bne CODE
+        (vec![0xe251_1004, 0x1aff_fffd], Case::new(13).small(&[1])),
+        // cmp r1, #0x80000000; bvs .+8
+        (vec![0xe351_0102, 0x6a00_0000], Case::new(14)),
+        // movs r0, #0; beq .+8
+        (vec![0xe3b0_0000, 0x0a00_0000], Case::new(15)),
+        // movs r0, r1; bmi .+8
+        (vec![0xe1b0_0001, 0x4a00_0000], Case::new(16)),
+        // movs r0, r1, lsl #1; bcs .+8
+        (vec![0xe1b0_0081, 0x2a00_0000], Case::new(17)),
+        // movs r0, r1; mov r2, r0
+        (vec![0xe1b0_0001, 0xe1a0_2000], Case::new(18).small(&[1])),
+        // subs r0, r1, #4; movs r2, r0; bgt .+8
+        (vec![0xe251_0004, 0xe1b0_2000, 0xca00_0000], 
+            Case::new(19).small(&[1])),
+        // cmp r1, #4; msr cpsr_c, #0xd3; beq .+8
+        (vec![0xe351_0004, 0xe321_f0d3, 0x0a00_0000], 
+            Case::new(20).small(&[1])),
+        // cmp r1, #4; msr cpsr_f, r2; bne .+8
+        (vec![0xe351_0004, 0xe128_f002, 0x1a00_0000], 
+            Case::new(21).small(&[1])),
+        // subs r0, r1, #4; msr cpsr_c, #0xd3; mov r2, r0
+        (vec![0xe251_0004, 0xe321_f0d3, 0xe1a0_2000], 
+            Case::new(22).small(&[1])),
+    ]
+}
+
+/// Loops where the flags are set in one block, and used by a conditional 
+/// branch in another (linked) block.
+fn linked_flag_cases() -> Vec<(Vec<u32>, Case)> {
+    vec![
+        // loop: subs r1, r1, #4; b .+4; bgt loop
+        (vec![0xe251_1004, 0xeaff_ffff, 0xcaff_fffc], 
+            Case::new(23).small(&[1])),
+        // loop: subs r1, r1, #4; movs r0, r1; b .+4; bgt loop
+        (vec![0xe251_1004, 0xe1b0_0001, 0xeaff_ffff, 0xcaff_fffb], 
+            Case::new(24).small(&[1])),
+        // loop: cmp r1, #0; sub r1, r1, #4; b .+4; bne loop
+        (vec![0xe351_0000, 0xe241_1004, 0xeaff_ffff, 0x1aff_fffb], 
+            Case::new(25).small(&[1])),
+    ]
+}
+
+/// Run a loop (which ends at the [BKPT] after `code`) with linked blocks, 
+/// and compare the result with the interpreter.
+fn check_linked(code: &[u32], case: Case) {
+    let end = CODE + 4 * code.len() as u32;
+    let mut rng = Rng::new(case.opcd);
+    for _ in 0..ITERATIONS {
+        let init = case.state(&mut rng);
+        let (mut jit, mut mmu) = machine(code, &init, &mut rng);
+        jit.set_backend(Backend::Recompile);
+        // Loops run at most 16 times
+        let res = jit.run_for(0x1000).unwrap();
+        assert_eq!(res.reason, StopReason::Bkpt { pc: end, imm: 0 },
+            "{:08x?}: from {:08x?}", code, init.reg);
+
+        let mut reference = init;
+        while reference.pc.0 != end {
+            let idx = ((reference.pc.0 - CODE) / 4) as usize;
+            nil::interp::step(&mut reference, &mut mmu, code[idx]).unwrap();
+        }
+
+        let (s, r) = (&jit.state, &reference);
+        assert_eq!(s.reg, r.reg, "{:08x?}: from {:08x?}", code, init.reg);
+        assert_eq!(s.cpsr, r.cpsr, "{:08x?}: cpsr from {:08x} {:08x?}",
+            code, init.cpsr.0, init.reg);
+    }
+}
+
+fn all_cases() -> Vec<Case> {
+    let mut cases = Vec::new();
+    cases.extend(data_processing_cases());
+    cases.extend(multiply_cases());
+    cases.extend(load_store_cases());
+    cases.extend(branch_cases());
+    cases.extend(status_cases());
+    cases.extend(coprocessor_cases());
+    cases.extend(exception_cases());
+    cases
+}
+
+
+#[test]
+fn data_processing() {
+    data_processing_cases().into_iter().for_each(check);
+}
+
+#[test]
+fn multiply() {
+    multiply_cases().into_iter().for_each(check);
+}
+
+#[test]
+fn load_store() {
+    load_store_cases().into_iter().for_each(check);
+}
+
+#[test]
+fn branch() {
+    branch_cases().into_iter().for_each(check);
+}
+
+#[test]
+fn status() {
+    status_cases().into_iter().for_each(check);
+}
+
+#[test]
+fn coprocessor() {
+    coprocessor_cases().into_iter().for_each(check);
+}
+
+#[test]
+fn exceptions() {
+    exception_cases().into_iter().for_each(check);
+}
+
+#[test]
+fn blocks() {
+    for (code, case) in block_cases() {
+        // The whole sequence is recompiled into one block
+        let mut rng = Rng::new(case.opcd);
+        let init = case.state(&mut rng);
+        let (mut jit, _) = machine(&code, &init, &mut rng);
+        jit.step().unwrap();
+        let len = jit.cache.get(&CODE).map(|bb| bb.guest_ops.len());
+        assert_eq!(len, Some(code.len() + 1), "{:08x?}: block length", code);
+
+        check_block(&code, case);
+    }
+}
+
+#[test]
+fn flags() {
+    for (code, case) in flag_cases() {
+        let mut rng = Rng::new(case.opcd);
+        let init = case.state(&mut rng);
+        assert!(recompilable(&code, &init), "{:08x?}: recompiled", code);
+        check_block(&code, case);
+    }
+}
+
+/// Flags used by a conditional branch in the same block are computed with
+/// the host flags, and others are left for the runtime.
+#[test]
+fn lazy_flags() {
+    let lazy = |code: &[u32]| {
+        let state = GuestState::new(CODE, 0xd3);
+        let mut mmu = GuestMmu::new();
+        for (idx, opcd) in code.iter().enumerate() {
+            mmu.store32(CODE + idx as u32 * 4, *opcd, true).unwrap();
+        }
+        let mut bb = BasicBlock::lift(&state, &mut mmu, &HashSet::new())
+            .unwrap();
+        bb.prune_dead_vars();
+        bb.data.iter().any(|inst| 
+            matches!(inst.rh, Operation::Bind(BindOp::SetFlags(..))))
+    };
+    // cmp r1, #4; beq .+8
+    assert!(!lazy(&[0xe351_0004, 0x0a00_0000]));
+    // cmp r1, #4; b .+8
+    assert!(lazy(&[0xe351_0004, 0xea00_0000]));
+    // cmp r1, #4; msr cpsr_c, #0xd3; beq .+8
+    assert!(lazy(&[0xe351_0004, 0xe321_f0d3, 0x0a00_0000]));
+}
+
+#[test]
+fn linked_flags() {
+    for (code, case) in linked_flag_cases() {
+        check_linked(&code, case);
+    }
+}
+
+/// Every kind of instruction is covered by some case.
+///
+/// `pld` is in the unconditional space, so the decoder never produces
+/// [ArmInst::PldImm] or [ArmInst::PldReg] (these are covered by the cases
+/// for `0xf5d1f000` and `0xf7d1f002` instead).
+#[test]
+fn coverage() {
+    use ArmInst::*;
+    let all = [
+        AndRegShiftReg, AdcRegShiftReg, MovRegShiftReg, OrrRegShiftReg,
+        EorRegShiftReg, RscRegShiftReg, MvnRegShiftReg, SbcRegShiftReg,
+        AddRegShiftReg, BicRegShiftReg, RsbRegShiftReg, SubRegShiftReg,
+        TeqRegShiftReg, CmnRegShiftReg, TstRegShiftReg, CmpRegShiftReg,
+        SbcReg, OrrReg, BicReg, AddReg, RscReg, EorReg, MvnReg, AdcReg,
+        SubReg, MovReg, AndReg, RsbReg, CmpReg, TstReg, CmnReg, TeqReg,
+        MovImm, AddImm, AdcImm, RsbImm, OrrImm, BicImm, SubImm, MvnImm,
+        AndImm, RscImm, EorImm, SbcImm, CmnImm, CmpImm, TstImm, TeqImm,
+        StrImm, StrhImm, StrdImm, StrbImm, StrReg, StrbReg, StrhReg, StrdReg,
+        LdrImm, LdrhImm, LdrdImm, LdrbImm, LdrsbImm, LdrshImm,
+        LdrReg, LdrbReg, LdrhReg, LdrdReg, LdrsbReg, LdrshReg,
+        Qdadd, Qsub, Qadd, Qdsub, Smull, Umlal, Smlal, Umull, Mul, Mla,
+        Smulwb, Smlawb, Smlalbb, Smlabb, Smulbb,
+        Ldrbt, Strbt, Ldrt, Strt,
+        MovImmAlt, LdrbtAlt, StrbtAlt, LdrtAlt, StrtAlt,
+        Stm, Stmda, Ldmda, Ldmib, Ldmdb, Ldm, Stmdb, Stmib,
+        LdmRegUser, StmRegUser,
+        MsrImm, MsrReg, Mrs, Mcrr, Mrrc, Mrc, Mcr, Stc,
+        LdcImm, Clz,
+        B, BlImm, Bx, BlxReg, Bxj,
+        Svc, Bkpt,
+        Undefined,
+    ];
+    let covered: HashSet<ArmInst> = all_cases().iter()
+        .filter(|c| (c.opcd >> 28) != 0xf)
+        .map(|c| ArmInst::decode(c.opcd))
+        .collect();
+    for inst in all.iter() {
+        assert!(covered.contains(inst), "{:?} isn't covered", inst);
+    }
+}
+
+#[test]
+fn shift_by_32() {
+    const C: u32 = 0x2000_0000;
+
+    // movs r0, r1, lsr #32
+    let s = run(0xe1b0_0021, &[(1, 0x8000_0000)], 0);
+    assert_eq!((s.reg[0], s.cpsr.c()), (0, true));
+    // movs r0, r1, asr #32
+    let s = run(0xe1b0_0041, &[(1, 0x8000_0000)], 0);
+    assert_eq!((s.reg[0], s.cpsr.c()), (0xffff_ffff, true));
+    // movs r0, r1, rrx
+    let s = run(0xe1b0_0061, &[(1, 0x0000_0002)], C);
+    assert_eq!((s.reg[0], s.cpsr.c()), (0x8000_0001, false));
+    // movs r0, r1, lsl r2
+    let s = run(0xe1b0_0211, &[(1, 0x0000_0001), (2, 32)], 0);
+    assert_eq!((s.reg[0], s.cpsr.c()), (0, true));
+    let s = run(0xe1b0_0211, &[(1, 0x0000_0001), (2, 33)], C);
+    assert_eq!((s.reg[0], s.cpsr.c()), (0, false));
+    // movs r0, r1, lsr r2
+    let s = run(0xe1b0_0231, &[(1, 0x8000_0000), (2, 32)], 0);
+    assert_eq!((s.reg[0], s.cpsr.c()), (0, true));
+    // movs r0, r1, ror r2
+    let s = run(0xe1b0_0271, &[(1, 0x8000_0001), (2, 32)], 0);
+    assert_eq!((s.reg[0], s.cpsr.c()), (0x8000_0001, true));
+    // movs r0, r1, lsl r2 (only the bottom byte is used)
+    let s = run(0xe1b0_0211, &[(1, 0x0000_0001), (2, 0x101)], 0);
+    assert_eq!((s.reg[0], s.cpsr.c()), (0x0000_0002, false));
+}
+
+#[test]
+fn pc_operands() {
+    // add r0, pc, #4
+    let s = run(0xe28f_0004, &[], 0);
+    assert_eq!(s.reg[0], CODE + 12);
+    // ldr r0, [pc, #-8]
+    let s = run(0xe51f_0008, &[], 0);
+    assert_eq!(s.reg[0], 0xe51f_0008);
+    // mov pc, r1
+    let s = run(0xe1a0_f001, &[(1, 0x0000_2000)], 0);
+    assert_eq!(s.pc.0, 0x0000_2000);
+    // bl #0x40
+    let s = run(0xeb00_000e, &[], 0);
+    assert_eq!((s.pc.0, s.reg[14]), (CODE + 0x40, CODE + 4));
+}
@@ -0,0 +1,86 @@
+//! Tests for guest code which can't be recompiled (and for errors returned
+//! when it can't be interpreted either).
+
+use std::collections::HashSet;
+use nil::{ Jit, StopReason };
+use nil::block::BasicBlock;
+use nil::error::{ Error, ErrorKind };
+use nil::guest::{ GuestState, GuestMmu, Psr };
+
+mod common;
+use common::load;
+
+fn machine(code: &[u32]) -> Jit {
+    let mut jit = Jit::new();
+    load(&mut jit.mmu, 0x1000, code);
+    jit.state.pc.0 = 0x1000;
+    jit
+}
+
+#[test]
+fn interpreter_fallback() {
+    let mut jit = machine(&[
+        0xe3a00001, // mov r0, #1
+        0xe3a0fc01, // mov pc, #0x100 (interpreted)
+    ]);
+    load(&mut jit.mmu, 0x100, &[
+        0xe1a01000, // mov r1, r0
+        0xe1200070, // bkpt #0
+    ]);
+    let res = jit.run_for(100).unwrap();
+    assert_eq!(res.reason, StopReason::Bkpt { pc: 0x104, imm: 0 });
+    assert_eq!((jit.state.reg[0], jit.state.reg[1]), (1, 1));
+
+    // The instructions around the unsupported one are still recompiled
+    assert!(jit.cache.contains_key(&0x1000));
+    assert!(!jit.cache.contains_key(&0x1004));
+    assert!(jit.cache.contains_key(&0x100));
+}
+
+#[test]
+fn unsupported_at_block_start() {
+    let mut jit = machine(&[0xe8900000]); // ldm r0, {}
+    assert_eq!(jit.run(), Err(Error::new(0x1000, 0xe8900000, 
+        ErrorKind::Unsupported("empty register list"))));
+    assert_eq!(jit.state.pc.0, 0x1000);
+}
+
+#[test]
+fn block_ends_before_error() {
+    let mut jit = machine(&[
+        0xe3a00001, // mov r0, #1
+        0xe8900000, // ldm r0, {}
+    ]);
+
+    // Everything before the instruction runs, and the error is reported 
+    // when it's at the start of the next block
+    assert_eq!(jit.run(), Err(Error::new(0x1004, 0xe8900000, 
+        ErrorKind::Unsupported("empty register list"))));
+    assert_eq!(jit.state.reg[0], 1);
+    assert_eq!(jit.state.pc.0, 0x1004);
+}
+
+#[test]
+fn thumb() {
+    let mut jit = machine(&[0xe12fff10]); // bx r0
+    jit.state.reg[0] = 0x2001;
+    assert_eq!(jit.run(), Err(Error::new(0x2001, 0, 
+        ErrorKind::Unsupported("thumb"))));
+    assert_eq!(jit.state.pc.0, 0x2001);
+}
+
+#[test]
+fn invalid_mode() {
+    let mut jit = machine(&[0xe3a00001]);
+    jit.state.cpsr = Psr(0x0000_00c0);
+    assert_eq!(jit.run(), Err(Error::new(0x1000, 0, ErrorKind::InvalidMode)));
+    assert!(jit.state.cpsr.mode().is_err());
+}
+
+#[test]
+fn unmapped_fetch() {
+    let mut mmu = GuestMmu::new();
+    let state = GuestState::new(0xfff0_0000, 0x0000_00d3);
+    assert_eq!(BasicBlock::lift(&state, &mut mmu, &HashSet::new()).err(),
+        Some(Error::new(0xfff0_0000, 0, ErrorKind::Fetch)));
+}
@@ -0,0 +1,92 @@
+//! Tests for taking interrupts at block boundaries.
+
+use nil::Jit;
+use nil::guest::{ CpuMode, ProgramCounter };
+
+mod common;
+use common::load;
+
+const MAIN: u32 = 0x0000_1000;
+const LOOP: u32 = MAIN + 4;
+
+/// A program which unmasks interrupts and loops forever (decrementing r0),
+/// with handlers which write r1 and loop forever.
+fn machine() -> Jit {
+    let mut jit = Jit::new();
+    load(&mut jit.mmu, 0x18, &[
+        0xea000038, // b 0x100 (IRQ)
+        0xea000077, // b 0x200 (FIQ)
+    ]);
+    load(&mut jit.mmu, 0x100, &[0xe3a01001, 0xeafffffe]); // mov r1, #1; b .
+    load(&mut jit.mmu, 0x200, &[0xe3a01002, 0xeafffffe]); // mov r1, #2; b .
+    load(&mut jit.mmu, MAIN, &[
+        0xe321f013, // msr cpsr_c, #0x13
+        0xe2400001, // sub r0, r0, #1
+        0xeafffffd, // b LOOP
+    ]);
+    jit.state.pc = ProgramCounter(MAIN);
+    jit
+}
+
+#[test]
+fn masked_interrupts() {
+    let mut jit = machine();
+    jit.state.pc = ProgramCounter(LOOP);
+    jit.assert_irq();
+    jit.assert_fiq();
+    jit.run_for(40).unwrap();
+    assert_eq!(jit.state.cpsr.mode(), Ok(CpuMode::Svc));
+    assert_eq!(jit.state.reg[0], -10i32 as u32);
+}
+
+#[test]
+fn unmasked_by_msr() {
+    let mut jit = machine();
+    jit.assert_irq();
+    jit.run_for(40).unwrap();
+
+    // The rest of the block runs before the interrupt is taken
+    assert_eq!(jit.state.cpsr.mode(), Ok(CpuMode::Irq));
+    assert!(jit.state.cpsr.irq_disable());
+    assert_eq!(jit.state.reg[0], -1i32 as u32);
+    assert_eq!(jit.state.reg[1], 1);
+    assert_eq!(jit.state.reg[14], LOOP + 4);
+    assert_eq!(jit.state.spsr().0, 0x0000_0013);
+}
+
+#[test]
+fn linked_blocks() {
+    let mut jit = machine();
+    jit.run_for(40).unwrap();
+    assert_eq!(jit.state.cpsr.mode(), Ok(CpuMode::Svc));
+    let r0 = jit.state.reg[0];
+
+    // The loop is linked to itself, but the interrupt is still taken when
+    // the next run starts
+    jit.assert_irq();
+    jit.run_for(40).unwrap();
+    assert_eq!(jit.state.cpsr.mode(), Ok(CpuMode::Irq));
+    assert_eq!(jit.state.reg[0], r0);
+    assert_eq!(jit.state.reg[14], LOOP + 4);
+}
+
+#[test]
+fn fiq_priority() {
+    let mut jit = machine();
+    jit.assert_irq();
+    jit.assert_fiq();
+    jit.run_for(40).unwrap();
+    assert_eq!(jit.state.cpsr.mode(), Ok(CpuMode::Fiq));
+    assert!(jit.state.cpsr.fiq_disable() && jit.state.cpsr.irq_disable());
+    assert_eq!(jit.state.reg[1], 2);
+}
+
+#[test]
+fn deasserted_lines() {
+    let mut jit = machine();
+    jit.assert_irq();
+    jit.deassert_irq();
+    jit.run_for(40).unwrap();
+    assert_eq!(jit.state.cpsr.mode(), Ok(CpuMode::Svc));
+    assert_eq!(jit.state.reg[1], 0);
+}
@@ -0,0 +1,141 @@
+//! Tests for discarding recompiled code when guest code changes.
+
+use std::collections::HashSet;
+
+use nil::Jit;
+use nil::block::BasicBlock;
+use nil::guest::{ GuestState, GuestMmu, ProgramCounter };
+
+mod common;
+use common::{ load, run_block };
+
+const CODE: u32 = 0x0000_0000;
+const WRITER: u32 = 0x0000_2000;
+const MIRROR: u32 = 0x0100_0000;
+
+/// Run a block (on another page) which stores r1 to the address in r0.
+fn store(mmu: &mut GuestMmu, addr: u32, val: u32) {
+    let mut state = GuestState::new(WRITER, 0x0000_00d3);
+    state.reg[0] = addr;
+    state.reg[1] = val;
+    run_block(&mut state, mmu);
+}
+
+#[test]
+fn code_page_writes() {
+    let mut mmu = GuestMmu::new();
+    load(&mut mmu, CODE, &[
+        0xe3a00001, // mov r0, #1
+        0xeafffffe, // b .
+    ]);
+    load(&mut mmu, WRITER, &[
+        0xe5801000, // str r1, [r0]
+        0xeafffffe, // b .
+    ]);
+    let state = GuestState::new(CODE, 0x0000_00d3);
+    let bb = BasicBlock::lift(&state, &mut mmu, &HashSet::new()).unwrap();
+    assert_eq!(bb.code_pages, vec![CODE]);
+    assert!(mmu.take_dirty_pages().is_empty());
+
+    // Stores from recompiled code to the page mark it as dirty, and the page
+    // isn't tracked until code on it is lifted again
+    store(&mut mmu, CODE, 0xe3a00002);
+    assert_eq!(mmu.take_dirty_pages(), vec![CODE]);
+    assert_eq!(mmu.read32(CODE), 0xe3a00002);
+    store(&mut mmu, CODE + 0x10, 0);
+    assert!(mmu.take_dirty_pages().is_empty());
+
+    // Writes to other pages aren't reported
+    BasicBlock::lift(&state, &mut mmu, &HashSet::new()).unwrap();
+    store(&mut mmu, 0x0000_1000, 0);
+    assert!(mmu.take_dirty_pages().is_empty());
+
+    // Writes from the host are reported
+    mmu.write_buf(CODE + 4, &[0; 4]);
+    assert_eq!(mmu.take_dirty_pages(), vec![CODE]);
+}
+
+#[test]
+fn mirrored_code_page_writes() {
+    let mut mmu = GuestMmu::new();
+    load(&mut mmu, CODE, &[0xeafffffe]);
+    load(&mut mmu, WRITER, &[0xe5801000, 0xeafffffe]);
+    let mut state = GuestState::new(CODE, 0x0000_00d3);
+    BasicBlock::lift(&state, &mut mmu, &HashSet::new()).unwrap();
+
+    // Mirrors added after the page is tracked are also write-protected
+    mmu.add_mirror(0x0000_0000, MIRROR);
+    store(&mut mmu, MIRROR + CODE + 8, 0xffff_ffff);
+    assert_eq!(mmu.take_dirty_pages(), vec![CODE]);
+    assert_eq!(mmu.read32(CODE + 8), 0xffff_ffff);
+
+    // Blocks lifted through the mirror track the same page
+    state.pc = ProgramCounter(MIRROR + CODE);
+    let bb = BasicBlock::lift(&state, &mut mmu, &HashSet::new()).unwrap();
+    assert_eq!(bb.code_pages, vec![CODE]);
+    mmu.write32(CODE + 8, 0).unwrap();
+    assert_eq!(mmu.take_dirty_pages(), vec![CODE]);
+}
+
+#[test]
+fn flush() {
+    let mut jit = Jit::new();
+    load(&mut jit.mmu, CODE, &[0xe3a00001, 0xeafffffe]);
+    load(&mut jit.mmu, WRITER, &[0xe5801000, 0xeafffffe]);
+    let bb = BasicBlock::lift(&jit.state, &mut jit.mmu, &HashSet::new()).unwrap();
+    jit.cache.insert(CODE, bb);
+
+    // Flushing discards every block, and code pages are no longer tracked
+    jit.flush();
+    assert!(jit.cache.is_empty());
+    store(&mut jit.mmu, CODE, 0);
+    assert!(jit.mmu.take_dirty_pages().is_empty());
+}
+
+#[test]
+fn invalidate_range() {
+    let mut jit = Jit::new();
+    load(&mut jit.mmu, CODE, &[
+        0xe2400001, // sub r0, r0, #1
+        0xeafffffd, // b CODE
+    ]);
+    jit.run_for(8).unwrap();
+    assert!(jit.cache.contains_key(&CODE));
+
+    // Only blocks on pages overlapping the range are discarded
+    jit.invalidate_range(WRITER, 4);
+    assert!(jit.cache.contains_key(&CODE));
+    jit.invalidate_range(CODE + 0xffc, 8);
+    assert!(jit.cache.is_empty());
+
+    // The block (and the link to itself) is recompiled on the next run
+    jit.run_for(8).unwrap();
+    assert!(jit.cache.contains_key(&CODE));
+    assert_eq!(jit.state.reg[0], -4i32 as u32);
+}
+
+#[test]
+fn linked_code_writes() {
+    const NEXT: u32 = 0x0000_1000;
+    let mut jit = Jit::new();
+    load(&mut jit.mmu, CODE, &[
+        0xe5821000, // str r1, [r2]
+        0xea0003fd, // b NEXT
+    ]);
+    load(&mut jit.mmu, NEXT, &[
+        0xe3a00001, // mov r0, #1
+        0xeafffbfd, // b CODE
+    ]);
+    jit.state.reg[1] = 0xe3a00001;
+    jit.state.reg[2] = WRITER;
+    jit.run_for(40).unwrap();
+    assert_eq!(jit.state.reg[0], 1);
+
+    // The exit into the next block is unlinked when the block is discarded,
+    // so the new code runs (instead of the stale block)
+    jit.state.reg[1] = 0xe3a00002; // mov r0, #2
+    jit.state.reg[2] = NEXT;
+    jit.run_for(40).unwrap();
+    assert_eq!(jit.state.reg[0], 2);
+    assert_eq!(jit.mmu.read32(NEXT), 0xe3a00002);
+}
@@ -0,0 +1,249 @@
+//! Tests for branching between recompiled blocks (without going through the
+//! dispatcher).
+
+use nil::Jit;
+use nil::guest::{ GuestState, GuestMmu, CpuMode, ProgramCounter };
+use nil::runtime::{ RuntimeExitCode, BranchCache };
+
+mod common;
+use common::{ load, compile, enter, enter_with };
+
+const TAKEN: u32 = 0x0000_0100;
+const NOT_TAKEN: u32 = 0x0000_0004;
+const DONE: u32 = 0x0000_0200;
+const FUNC: u32 = 0x0000_0100;
+const RET: u32 = 0x0000_0004;
+
+/// CPSR in supervisor mode with interrupts disabled (and the Z flag set).
+const SVC_Z: u32 = 0x4000_00d3;
+const SVC: u32 = 0x0000_00d3;
+const USR: u32 = 0x0000_00d0;
+
+/// A conditional branch, where each edge runs a block which writes a
+/// different register before branching to `DONE`.
+fn program(mmu: &mut GuestMmu) {
+    load(mmu, 0, &[
+        0x0a00003e, // beq TAKEN
+        0xe3a01001, // mov r1, #1
+        0xea00007c, // b DONE
+    ]);
+    load(mmu, TAKEN, &[
+        0xe3a00001, // mov r0, #1
+        0xea00003d, // b DONE
+    ]);
+}
+
+#[test]
+fn branch_cond_exits() {
+    let mut mmu = GuestMmu::new();
+    program(&mut mmu);
+    let mut state = GuestState::new(0, SVC_Z);
+    let bb = compile(&state, &mut mmu);
+    let targets: Vec<u32> = bb.exits.iter().map(|e| e.target).collect();
+    assert_eq!(targets, vec![TAKEN, NOT_TAKEN]);
+
+    // Without any links, both edges return to the dispatcher
+    assert!(matches!(enter(&mut state, &mut mmu, &bb),
+        RuntimeExitCode::NextBlock));
+    assert_eq!(state.pc.fetch(), TAKEN);
+
+    let mut state = GuestState::new(0, SVC);
+    enter(&mut state, &mut mmu, &bb);
+    assert_eq!(state.pc.fetch(), NOT_TAKEN);
+}
+
+#[test]
+fn link_and_unlink() {
+    let mut mmu = GuestMmu::new();
+    program(&mut mmu);
+    let state = GuestState::new(0, SVC);
+    let bb = compile(&state, &mut mmu);
+    let taken = compile(&GuestState::new(TAKEN, SVC), &mut mmu);
+    let not_taken = compile(&GuestState::new(NOT_TAKEN, SVC), &mut mmu);
+    for exit in bb.exits.iter() {
+        match exit.target {
+            TAKEN => exit.link(taken.entrypoint()),
+            NOT_TAKEN => exit.link(not_taken.entrypoint()),
+            _ => unreachable!(),
+        }
+    }
+
+    // Both edges run straight through into the linked blocks
+    let mut state = GuestState::new(0, SVC_Z);
+    enter(&mut state, &mut mmu, &bb);
+    assert_eq!((state.reg[0], state.reg[1]), (1, 0));
+    assert_eq!(state.pc.fetch(), DONE);
+
+    let mut state = GuestState::new(0, SVC);
+    enter(&mut state, &mut mmu, &bb);
+    assert_eq!((state.reg[0], state.reg[1]), (0, 1));
+    assert_eq!(state.pc.fetch(), DONE);
+
+    // Unlinked exits return to the dispatcher again
+    for exit in bb.exits.iter() {
+        exit.unlink();
+    }
+    let mut state = GuestState::new(0, SVC_Z);
+    enter(&mut state, &mut mmu, &bb);
+    assert_eq!((state.reg[0], state.reg[1]), (0, 0));
+    assert_eq!(state.pc.fetch(), TAKEN);
+}
+
+/// Identity-map the first 64KiB, where one page is only accessible from 
+/// privileged modes, and enable the MMU.
+fn map_pages(mmu: &mut GuestMmu, privileged: u32) {
+    const TTBR: u32 = 0x0000_4000;
+    const COARSE: u32 = 0x0000_8000;
+    let pages: Vec<u32> = (0..16).map(|i| {
+        let ap = if i << 12 == privileged { 0b01 } else { 0b11 };
+        (i << 12) | (ap << 10) | (ap << 8) | (ap << 6) | (ap << 4) | 0b10
+    }).collect();
+    load(mmu, COARSE, &pages);
+    load(mmu, TTBR, &[COARSE | 0b01]);
+    mmu.vmsa.set_ttbr(TTBR);
+    mmu.vmsa.set_dacr(0b01);
+    mmu.vmsa.enabled = true;
+}
+
+#[test]
+fn privileged_blocks() {
+    const USER: u32 = 0x0000_1000;
+    let mut mmu = GuestMmu::new();
+    program(&mut mmu);
+    load(&mut mmu, USER, &[
+        0xe3a00001, // mov r0, #1
+        0xeafffffe, // b .
+    ]);
+
+    // The first page is only accessible from privileged modes
+    map_pages(&mut mmu, 0);
+
+    assert!(compile(&GuestState::new(0, SVC), &mut mmu).privileged);
+    assert!(!compile(&GuestState::new(USER, SVC), &mut mmu).privileged);
+    assert!(!compile(&GuestState::new(USER, USR), &mut mmu).privileged);
+
+    // A privileged block (i.e. entered through a link after a switch to user
+    // mode) returns to the dispatcher without running
+    let bb = compile(&GuestState::new(TAKEN, SVC), &mut mmu);
+    let mut state = GuestState::new(TAKEN, USR);
+    assert!(matches!(enter(&mut state, &mut mmu, &bb),
+        RuntimeExitCode::NextBlock));
+    assert_eq!(state.reg[0], 0);
+    assert_eq!(state.pc.fetch(), TAKEN);
+
+    let mut state = GuestState::new(TAKEN, SVC);
+    enter(&mut state, &mut mmu, &bb);
+    assert_eq!(state.reg[0], 1);
+    assert_eq!(state.pc.fetch(), DONE);
+}
+
+/// A call to a function which sets r0 and returns to a block which sets r1.
+fn call(mmu: &mut GuestMmu) {
+    load(mmu, 0, &[
+        0xeb00003e, // bl FUNC
+        0xe3a01001, // mov r1, #1
+        0xea00007c, // b DONE
+    ]);
+    load(mmu, FUNC, &[
+        0xe3a00001, // mov r0, #1
+        0xe12fff1e, // bx lr
+    ]);
+}
+
+#[test]
+fn branch_cache_hits() {
+    let mut mmu = GuestMmu::new();
+    call(&mut mmu);
+    let func = compile(&GuestState::new(FUNC, SVC), &mut mmu);
+    let ret = compile(&GuestState::new(RET, SVC), &mut mmu);
+    let mut cache = BranchCache::new();
+
+    // Misses return to the dispatcher
+    let mut state = GuestState::new(FUNC, SVC);
+    state.reg[14] = RET;
+    enter_with(&mut state, &mut mmu, &func, &mut cache);
+    assert_eq!((state.reg[0], state.reg[1]), (1, 0));
+    assert_eq!(state.pc.fetch(), RET);
+
+    // Hits branch directly into the cached block
+    cache.insert(RET, ret.entrypoint());
+    let mut state = GuestState::new(FUNC, SVC);
+    state.reg[14] = RET;
+    enter_with(&mut state, &mut mmu, &func, &mut cache);
+    assert_eq!((state.reg[0], state.reg[1]), (1, 1));
+    assert_eq!(state.pc.fetch(), DONE);
+
+    // Entries for other addresses in the same slot don't match
+    cache.clear();
+    cache.insert(RET + 4 * BranchCache::TABLE_SIZE as u32, ret.entrypoint());
+    let mut state = GuestState::new(FUNC, SVC);
+    state.reg[14] = RET;
+    enter_with(&mut state, &mut mmu, &func, &mut cache);
+    assert_eq!(state.reg[1], 0);
+    assert_eq!(state.pc.fetch(), RET);
+}
+
+#[test]
+fn return_stack_hits() {
+    let mut mmu = GuestMmu::new();
+    call(&mut mmu);
+    let caller = compile(&GuestState::new(0, SVC), &mut mmu);
+    let func = compile(&GuestState::new(FUNC, SVC), &mut mmu);
+    let ret = compile(&GuestState::new(RET, SVC), &mut mmu);
+    let mut cache = BranchCache::new();
+
+    // Calls push the return address (and the block, if it's cached)
+    cache.insert(RET, ret.entrypoint());
+    let mut state = GuestState::new(0, SVC);
+    enter_with(&mut state, &mut mmu, &caller, &mut cache);
+    assert_eq!(state.pc.fetch(), FUNC);
+    assert_eq!(state.reg[14], RET);
+    assert_eq!(cache.ras_top, 1);
+    assert_eq!(cache.ras[1].pc, RET);
+    assert_eq!(cache.ras[1].host, ret.entrypoint() as usize);
+
+    // Returns pop the prediction, and use it when it matches (even when 
+    // the table misses)
+    cache.table[BranchCache::index(RET)].host = 0;
+    let mut state = GuestState::new(FUNC, SVC);
+    state.reg[14] = RET;
+    enter_with(&mut state, &mut mmu, &func, &mut cache);
+    assert_eq!(cache.ras_top, 0);
+    assert_eq!((state.reg[0], state.reg[1]), (1, 1));
+    assert_eq!(state.pc.fetch(), DONE);
+
+    // Mispredicted returns fall back to the table
+    cache.ras_top = 1;
+    cache.ras[1].pc = RET + 4;
+    let mut state = GuestState::new(FUNC, SVC);
+    state.reg[14] = RET;
+    enter_with(&mut state, &mut mmu, &func, &mut cache);
+    assert_eq!(cache.ras_top, 0);
+    assert_eq!(state.reg[1], 0);
+    assert_eq!(state.pc.fetch(), RET);
+}
+
+#[test]
+fn privilege_drop() {
+    const MAIN: u32 = 0x0000_1000;
+    const PRIV: u32 = 0x0000_2000;
+    let mut jit = Jit::new();
+    load(&mut jit.mmu, 0x0c, &[0xea0000bb]); // b 0x300 (prefetch abort)
+    load(&mut jit.mmu, 0x300, &[0xe3a01001, 0xeafffffe]); // mov r1, #1; b .
+    load(&mut jit.mmu, MAIN, &[0xea0003fe]); // b PRIV
+    load(&mut jit.mmu, PRIV, &[
+        0xe321f0d0, // msr cpsr_c, #0xd0
+        0xeafffbfd, // b MAIN
+    ]);
+    map_pages(&mut jit.mmu, PRIV);
+    jit.state.pc = ProgramCounter(MAIN);
+
+    // After switching to user mode, the linked branch back into the 
+    // privileged block aborts (instead of running it in user mode)
+    jit.run_for(100).unwrap();
+    assert_eq!(jit.state.cpsr.mode(), Ok(CpuMode::Abt));
+    assert_eq!(jit.state.reg[1], 1);
+    assert_eq!(jit.state.reg[14], PRIV + 4);
+    assert_eq!(jit.mmu.vmsa.ifsr & 0xf, 0b1111);
+    assert_eq!(jit.state.spsr().mode(), Ok(CpuMode::Usr));
+}
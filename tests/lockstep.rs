@@ -0,0 +1,56 @@
+//! Tests for [Backend::Lockstep], which checks recompiled code against the IR
+//! evaluator.
+
+use nil::{ Jit, Backend, StopReason };
+use nil::error::ErrorKind;
+use nil::ir::Operation;
+
+mod common;
+use common::load;
+
+fn machine(code: &[u32]) -> Jit {
+    let mut jit = Jit::new();
+    jit.set_backend(Backend::Lockstep);
+    load(&mut jit.mmu, 0x1000, code);
+    jit.state.pc.0 = 0x1000;
+    jit
+}
+
+/// A block which stores r1 at the address in r2.
+const STORE: [u32; 2] = [
+    0xe5821000, // str r1, [r2]
+    0xe1200070, // bkpt #0
+];
+
+#[test]
+fn agreement() {
+    let mut jit = machine(&STORE);
+    jit.state.reg[1] = 0x1234_5678;
+    jit.state.reg[2] = 0x2000;
+    assert_eq!(jit.run().unwrap(), StopReason::Bkpt { pc: 0x1004, imm: 0 });
+    assert_eq!(jit.mmu.load32(0x2000, true), Ok(0x1234_5678));
+}
+
+/// Remove the store from the IR for a cached block (so that only the 
+/// recompiled code performs it).
+fn drop_stores(jit: &mut Jit, pc: u32) {
+    let bb = jit.cache.get_mut(&pc).unwrap();
+    bb.data.retain(|inst| !matches!(inst.rh, Operation::Memory(_)));
+}
+
+#[test]
+fn native_only_store() {
+    let mut jit = machine(&STORE);
+    jit.state.reg[1] = 0x1234_5678;
+    jit.state.reg[2] = 0x2000;
+    jit.run().unwrap();
+
+    // A store made only by the recompiled code is a divergence
+    drop_stores(&mut jit, 0x1000);
+    jit.state.pc.0 = 0x1000;
+    jit.state.reg[2] = 0x3000;
+    let err = jit.run().unwrap_err();
+    assert_eq!((err.pc, err.opcd), (0x1000, STORE[0]));
+    assert_eq!(err.kind, ErrorKind::Divergence(
+        "[00003000] 00000000 (eval) != 12345678 (native)".to_string()));
+}
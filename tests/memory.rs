@@ -0,0 +1,130 @@
+//! Tests for guest memory accesses from recompiled code.
+//!
+//! Each case writes a small program into guest memory, recompiles the first
+//! block, and runs it once.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use nil::bus::{ MemoryBus, Region, RegionKind, WriteProtectAction };
+use nil::guest::{ GuestState, GuestMmu };
+use nil::mem::Endianness;
+use nil::mmu::FaultStatus;
+use nil::runtime::RuntimeExitCode;
+
+mod common;
+use common::{ load, run_block };
+
+const CODE: u32 = 0x0000_0000;
+const DATA: u32 = 0x0000_1000;
+
+#[test]
+fn fastmem_byte_order() {
+    let mut mmu = GuestMmu::new();
+    for (e, loaded) in [
+        (Endianness::Big, 0x1234_5678), 
+        (Endianness::Little, 0x7856_3412)
+    ].iter() {
+        mmu.endianness = *e;
+        load(&mut mmu, CODE, &[
+            0xe3a00a01, // mov r0, #0x1000
+            0xe5901000, // ldr r1, [r0]
+            0xe5801004, // str r1, [r0, #4]
+            0xe3a020ff, // mov r2, #0xff
+            0xe5802008, // str r2, [r0, #8]
+            0xeafffffe, // b .
+        ]);
+        mmu.write_buf(DATA, &[0x12, 0x34, 0x56, 0x78]);
+
+        let mut state = GuestState::new(CODE, 0x0000_00d3);
+        run_block(&mut state, &mut mmu);
+        assert_eq!(state.reg[1], *loaded, "{:?}", e);
+
+        // Stores write bytes in the guest's byte order
+        let bytes: Vec<u8> = (DATA + 4..DATA + 12).map(|a| mmu.read8(a)).collect();
+        let stored = match e {
+            Endianness::Big => [0x12, 0x34, 0x56, 0x78, 0x00, 0x00, 0x00, 0xff],
+            Endianness::Little => [0x12, 0x34, 0x56, 0x78, 0xff, 0x00, 0x00, 0x00],
+        };
+        assert_eq!(bytes, stored, "{:?}", e);
+        assert_eq!(mmu.read32(DATA + 8), 0xff, "{:?}", e);
+    }
+}
+
+#[test]
+fn mirror_writes() {
+    const MIRROR: u32 = 0x0100_0000;
+    let mut mmu = GuestMmu::new();
+    mmu.add_mirror(0x0000_0000, MIRROR);
+    load(&mut mmu, CODE, &[
+        0xe3a010ff, // mov r1, #0xff
+        0xe5801010, // str r1, [r0, #0x10]
+        0xe5902020, // ldr r2, [r0, #0x20]
+        0xeafffffe, // b .
+    ]);
+    mmu.write32(DATA + 0x20, 0xdead_beef).unwrap();
+
+    // Writes through either address are visible through both
+    mmu.write32(MIRROR + DATA + 0x30, 0x1234_5678).unwrap();
+    assert_eq!(mmu.read32(DATA + 0x30), 0x1234_5678);
+
+    let mut state = GuestState::new(CODE, 0x0000_00d3);
+    state.reg[0] = MIRROR + DATA;
+    run_block(&mut state, &mut mmu);
+    assert_eq!(state.reg[2], 0xdead_beef);
+    assert_eq!(mmu.read32(DATA + 0x10), 0xff);
+    assert_eq!(mmu.read32(MIRROR + DATA + 0x10), 0xff);
+}
+
+/// A memory map with ROM at zero, and RAM above it.
+struct RomBoard;
+impl MemoryBus for RomBoard {
+    fn regions(&self) -> Vec<Region> {
+        vec![
+            Region::new("ROM", 0x0000_0000, 0x0001_0000, RegionKind::Rom),
+            Region::new("RAM", 0x0010_0000, 0x0001_0000, RegionKind::Ram),
+        ]
+    }
+    fn read8(&mut self, addr: u32) -> u8 { panic!("read8 {:08x}", addr) }
+    fn read16(&mut self, addr: u32) -> u16 { panic!("read16 {:08x}", addr) }
+    fn read32(&mut self, addr: u32) -> u32 { panic!("read32 {:08x}", addr) }
+    fn write8(&mut self, addr: u32, _: u8) { panic!("write8 {:08x}", addr) }
+    fn write16(&mut self, addr: u32, _: u16) { panic!("write16 {:08x}", addr) }
+    fn write32(&mut self, addr: u32, _: u32) { panic!("write32 {:08x}", addr) }
+}
+
+#[test]
+fn rom_writes() {
+    for action in [WriteProtectAction::Ignore, WriteProtectAction::Abort].iter() {
+        let mut mmu = GuestMmu::with_bus(Box::new(RomBoard));
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let (log, action) = (writes.clone(), *action);
+        mmu.set_write_protect_handler(Box::new(move |addr, val| {
+            log.borrow_mut().push((addr, val));
+            action
+        }));
+        load(&mut mmu, CODE, &[
+            0xe3a010ff, // mov r1, #0xff
+            0xe5801000, // str r1, [r0]
+            0xeafffffe, // b .
+        ]);
+
+        let mut state = GuestState::new(CODE, 0x0000_00d3);
+        state.reg[0] = 0x0000_0800;
+        let res = run_block(&mut state, &mut mmu);
+
+        // The write is reported, and ROM is left unchanged
+        assert_eq!(*writes.borrow(), vec![(0x0000_0800, 0xff)]);
+        assert_eq!(mmu.read32(0x0000_0800), 0);
+        match action {
+            WriteProtectAction::Ignore => {
+                assert!(matches!(res, RuntimeExitCode::NextBlock));
+            },
+            WriteProtectAction::Abort => {
+                assert!(matches!(res, RuntimeExitCode::DataAbort));
+                assert_eq!(mmu.vmsa.dfsr, FaultStatus::External as u32);
+                assert_eq!(mmu.vmsa.far, 0x0000_0800);
+            },
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Tests for ARMv5 address translation.
+//!
+//! Each case builds translation tables in a small model of guest physical
+//! memory, and checks the result of translating some virtual addresses.
+
+use std::collections::HashMap;
+
+use nil::mmu::{ Access, Fault, FaultStatus, Mmu };
+
+/// Guest physical memory holding translation tables.
+#[derive(Default)]
+struct Tables(HashMap<u32, u32>);
+impl Tables {
+    fn write(&mut self, pa: u32, val: u32) { self.0.insert(pa, val); }
+    fn read(&self, pa: u32) -> Option<u32> { 
+        // Memory above 16MiB isn't backed by anything
+        if pa < 0x0100_0000 { Some(*self.0.get(&pa).unwrap_or(&0)) } else { None }
+    }
+
+    /// Write a first-level descriptor for the 1MiB region containing `va`.
+    fn l1(&mut self, ttbr: u32, va: u32, desc: u32) {
+        self.write(ttbr | ((va >> 20) << 2), desc);
+    }
+    /// Write a coarse second-level descriptor for the page containing `va`.
+    fn l2(&mut self, table: u32, va: u32, desc: u32) {
+        self.write(table | (((va >> 12) & 0xff) << 2), desc);
+    }
+}
+
+const TTBR: u32 = 0x0000_4000;
+const COARSE: u32 = 0x0000_8000;
+
+/// Section descriptor with the provided AP bits and domain.
+fn section(pa: u32, ap: u32, domain: u32) -> u32 {
+    (pa & 0xfff0_0000) | (ap << 10) | (domain << 5) | 0b10
+}
+/// Small page descriptor with the same AP bits for all subpages.
+fn small(pa: u32, ap: u32) -> u32 {
+    (pa & 0xffff_f000) | (ap << 10) | (ap << 8) | (ap << 6) | (ap << 4) | 0b10
+}
+
+fn mmu(dacr: u32) -> Mmu {
+    let mut mmu = Mmu::new();
+    mmu.enabled = true;
+    mmu.set_ttbr(TTBR);
+    mmu.set_dacr(dacr);
+    mmu
+}
+
+fn translate(mmu: &mut Mmu, t: &Tables, va: u32, access: Access, 
+    privileged: bool) -> Result<u32, Fault> 
+{
+    mmu.translate(va, access, privileged, |pa| t.read(pa))
+}
+
+fn status(res: Result<u32, Fault>) -> FaultStatus {
+    match res {
+        Err(fault) => fault.status,
+        Ok(pa) => panic!("expected a fault, translated to {:08x}", pa),
+    }
+}
+
+#[test]
+fn disabled_is_identity() {
+    let mut mmu = Mmu::new();
+    let t = Tables::default();
+    assert_eq!(translate(&mut mmu, &t, 0xdead_beef, Access::Read, false), 
+        Ok(0xdead_beef));
+}
+
+#[test]
+fn section_walk() {
+    let mut t = Tables::default();
+    t.l1(TTBR, 0xc010_0000, section(0x0030_0000, 0b11, 0));
+    let mut mmu = mmu(0b01);
+    assert_eq!(translate(&mut mmu, &t, 0xc012_3456, Access::Read, false), 
+        Ok(0x0032_3456));
+    assert_eq!(status(translate(&mut mmu, &t, 0xc020_0000, Access::Read, false)),
+        FaultStatus::TranslationSection);
+}
+
+#[test]
+fn coarse_page_walk() {
+    let mut t = Tables::default();
+    t.l1(TTBR, 0x4000_0000, COARSE | (2 << 5) | 0b01);
+    t.l2(COARSE, 0x4000_3000, small(0x0012_3000, 0b11));
+    // Large page, with the descriptor repeated for each 4KiB of the page
+    for i in 0..16 {
+        t.l2(COARSE, 0x4001_0000 + (i << 12), 0x0050_0000 | (0xff << 4) | 0b01);
+    }
+    let mut mmu = mmu(0b01 << 4);
+
+    assert_eq!(translate(&mut mmu, &t, 0x4000_3abc, Access::Write, false), 
+        Ok(0x0012_3abc));
+    assert_eq!(translate(&mut mmu, &t, 0x4001_8abc, Access::Fetch, false), 
+        Ok(0x0050_8abc));
+
+    let res = translate(&mut mmu, &t, 0x4000_4000, Access::Read, false);
+    assert_eq!(res.unwrap_err(), Fault { 
+        status: FaultStatus::TranslationPage, domain: 2, 
+        addr: 0x4000_4000, access: Access::Read,
+    });
+    // Tiny pages aren't valid in coarse tables
+    t.l2(COARSE, 0x4000_5000, 0x0060_0000 | 0b11);
+    assert_eq!(status(translate(&mut mmu, &t, 0x4000_5000, Access::Read, false)),
+        FaultStatus::TranslationPage);
+}
+
+#[test]
+fn walk_from_unmapped_memory() {
+    let mut t = Tables::default();
+    t.l1(TTBR, 0x4000_0000, 0x0200_0000 | 0b01);
+    let mut mmu = mmu(0b01);
+    assert_eq!(status(translate(&mut mmu, &t, 0x4000_0000, Access::Read, false)),
+        FaultStatus::ExternalPage);
+    mmu.set_ttbr(0x0200_0000);
+    assert_eq!(status(translate(&mut mmu, &t, 0x4000_0000, Access::Read, false)),
+        FaultStatus::ExternalSection);
+}
+
+#[test]
+fn access_permissions() {
+    let mut t = Tables::default();
+    t.l1(TTBR, 0x0010_0000, section(0x0010_0000, 0b01, 0));
+    t.l1(TTBR, 0x0020_0000, section(0x0020_0000, 0b10, 0));
+    t.l1(TTBR, 0x0030_0000, COARSE | 0b01);
+    t.l2(COARSE, 0x0030_0000, small(0x0030_0000, 0b10));
+    let mut mmu = mmu(0b01);
+
+    // AP=0b01: privileged access only
+    assert!(translate(&mut mmu, &t, 0x0010_0000, Access::Write, true).is_ok());
+    assert_eq!(status(translate(&mut mmu, &t, 0x0010_0000, Access::Read, false)),
+        FaultStatus::PermissionSection);
+
+    // AP=0b10: read-only in user mode
+    assert!(translate(&mut mmu, &t, 0x0020_0000, Access::Read, false).is_ok());
+    assert!(translate(&mut mmu, &t, 0x0020_0000, Access::Write, true).is_ok());
+    assert_eq!(status(translate(&mut mmu, &t, 0x0020_0000, Access::Write, false)),
+        FaultStatus::PermissionSection);
+    assert_eq!(status(translate(&mut mmu, &t, 0x0030_0000, Access::Write, false)),
+        FaultStatus::PermissionPage);
+
+    // AP=0b00: no access unless the S/R bits allow reads
+    t.l1(TTBR, 0x0040_0000, section(0x0040_0000, 0b00, 0));
+    assert_eq!(status(translate(&mut mmu, &t, 0x0040_0000, Access::Read, true)),
+        FaultStatus::PermissionSection);
+    mmu.system = true;
+    assert!(translate(&mut mmu, &t, 0x0040_0000, Access::Read, true).is_ok());
+    assert_eq!(status(translate(&mut mmu, &t, 0x0040_0000, Access::Read, false)),
+        FaultStatus::PermissionSection);
+}
+
+#[test]
+fn domain_faults() {
+    let mut t = Tables::default();
+    t.l1(TTBR, 0x0010_0000, section(0x0010_0000, 0b01, 3));
+    t.l1(TTBR, 0x0020_0000, COARSE | (3 << 5) | 0b01);
+    t.l2(COARSE, 0x0020_0000, small(0x0020_0000, 0b01));
+
+    // No access
+    let mut mmu = mmu(0b00 << 6);
+    let res = translate(&mut mmu, &t, 0x0010_0000, Access::Read, true);
+    assert_eq!(res.unwrap_err().fsr(), (3 << 4) | FaultStatus::DomainSection as u32);
+    assert_eq!(status(translate(&mut mmu, &t, 0x0020_0000, Access::Read, true)),
+        FaultStatus::DomainPage);
+
+    // Managers aren't checked against the AP bits
+    mmu.set_dacr(0b11 << 6);
+    assert!(translate(&mut mmu, &t, 0x0010_0000, Access::Write, false).is_ok());
+}
+
+#[test]
+fn record_fault() {
+    let mut mmu = Mmu::new();
+    let fault = Fault { 
+        status: FaultStatus::PermissionPage, domain: 5, 
+        addr: 0x1234_5678, access: Access::Write 
+    };
+    mmu.record_fault(fault);
+    assert_eq!((mmu.dfsr, mmu.far), (0x5f, 0x1234_5678));
+    mmu.record_fault(Fault { addr: 0, access: Access::Fetch, ..fault });
+    assert_eq!((mmu.ifsr, mmu.far), (0x5f, 0x1234_5678));
+}
+
+#[test]
+fn tlb_invalidation() {
+    let mut t = Tables::default();
+    t.l1(TTBR, 0x0010_0000, section(0x0010_0000, 0b11, 1));
+    let mut mmu = mmu(0b01 << 2);
+    assert_eq!(translate(&mut mmu, &t, 0x0010_0000, Access::Read, false), 
+        Ok(0x0010_0000));
+
+    // Changes to the tables aren't visible until the entry is invalidated
+    t.l1(TTBR, 0x0010_0000, section(0x0090_0000, 0b11, 1));
+    assert_eq!(translate(&mut mmu, &t, 0x0010_0000, Access::Read, false), 
+        Ok(0x0010_0000));
+    mmu.tlb.invalidate(0x0010_0000);
+    assert_eq!(translate(&mut mmu, &t, 0x0010_0000, Access::Read, false), 
+        Ok(0x0090_0000));
+
+    // Writing the DACR applies to cached translations
+    mmu.set_dacr(0);
+    assert_eq!(status(translate(&mut mmu, &t, 0x0010_0000, Access::Read, false)),
+        FaultStatus::DomainSection);
+    mmu.set_dacr(0b01 << 2);
+
+    // Writing the TTBR flushes the TLB
+    let ttbr = 0x0000_c000;
+    t.l1(ttbr, 0x0010_0000, section(0x00a0_0000, 0b11, 1));
+    mmu.set_ttbr(ttbr);
+    assert_eq!(translate(&mut mmu, &t, 0x0010_0000, Access::Read, false), 
+        Ok(0x00a0_0000));
+}
@@ -0,0 +1,337 @@
+//! Tests for register allocation in recompiled code.
+//!
+//! The lifter keeps guest registers in memory between instructions, so real
+//! code rarely has many values live at once. These tests rewrite the IR for
+//! a lifted block to lengthen the lifetimes of its values (without changing
+//! what the block does) before recompiling it. Some tests build a small 
+//! block directly, and check the allocation around calls to runtime helpers.
+
+use std::collections::{ HashMap, HashSet };
+
+use nil::block::{ BasicBlock, BlockLink, BindOpLifter, MemoryOpLifter };
+use nil::guest::{ GuestState, GuestMmu, ProgramCounter };
+use nil::ir::{ 
+    Operation, BindOp, ArithOp, MemoryOp, Instruction, Var, VarKind, Constant 
+};
+use nil::regalloc::{ 
+    IntervalMap, StorageLoc, HostRegister, allocate_registers 
+};
+use nil::runtime::{ RuntimeContext, RuntimeExitCode };
+
+mod common;
+use common::{ load, compile, enter };
+
+const CODE: u32 = 0x0000_1000;
+const DONE: u32 = 0x0000_2000;
+
+/// CPSR in supervisor mode with interrupts disabled (and the Z flag set).
+const SVC_Z: u32 = 0x4000_00d3;
+const SVC: u32 = 0x0000_00d3;
+
+/// `sub r0, r1, #1; sub r1, r2, #2; ...; sub r11, r12, #12`. Each register
+/// is read before it's written.
+fn subs() -> Vec<u32> {
+    (0..12).map(|rd| 0xe240_0000 | (rd + 1) << 16 | rd << 12 | (rd + 1))
+        .collect()
+}
+
+/// Move all writes to guest registers to the end of the block, so that the 
+/// result of each instruction stays live until then.
+fn sink_writes(bb: &mut BasicBlock) {
+    let (writes, mut data): (Vec<_>, Vec<_>) = bb.data.drain(..)
+        .partition(|inst| matches!(inst.rh, 
+            Operation::Bind(BindOp::WriteGuestReg(..))));
+    data.extend(writes);
+    bb.data = data;
+}
+
+/// Replace reads of guest registers which were written earlier in the block
+/// with a copy of the value that was written, so that it stays live until 
+/// then.
+fn forward_reads(bb: &mut BasicBlock) {
+    let id = bb.data.iter().filter_map(|inst| inst.lh).map(|v| v.id)
+        .max().unwrap() + 1;
+    let zero = Var::new_constant(id, 32, 0);
+    let mut latest = HashMap::new();
+    for inst in bb.data.iter_mut() {
+        match inst.rh {
+            Operation::Bind(BindOp::WriteGuestReg(r, v)) => {
+                latest.insert(r, v);
+            },
+            Operation::Bind(BindOp::ReadGuestReg(r)) => {
+                if let Some(v) = latest.get(&r) {
+                    inst.rh = Operation::Arith(ArithOp::Add32(*v, zero));
+                }
+            },
+            _ => {},
+        }
+    }
+    bb.data.insert(0, Instruction::constant(0, zero, Constant::new(32, 0)));
+}
+
+/// Renumber the variables in a block, so that the variables defined by every
+/// other instruction come first.
+fn shuffle_ids(bb: &mut BasicBlock) {
+    let base = bb.data.iter().filter_map(|inst| inst.lh).map(|v| v.id)
+        .max().unwrap() + 1;
+    let len = bb.data.len();
+    let mut ids = HashMap::new();
+    for (idx, inst) in bb.data.iter().enumerate() {
+        let defs = [inst.lh, inst.lh_c, inst.lh_v];
+        for (n, v) in defs.iter().enumerate() {
+            let v = v.filter(|v| !matches!(v.kind, VarKind::Constant(_)));
+            if let Some(v) = v {
+                let id = base + 3 * (idx + (idx % 2) * len) + n;
+                ids.insert(v, Var { id, ..v });
+            }
+        }
+    }
+    let r = |v: &mut Var| if let Some(n) = ids.get(v) { *v = *n; };
+    for inst in bb.data.iter_mut() {
+        for v in [&mut inst.lh, &mut inst.lh_c, &mut inst.lh_v].iter_mut() {
+            if let Some(v) = v.as_mut() { r(v); }
+        }
+        match &mut inst.rh {
+            Operation::Bind(BindOp::Const(_)) |
+            Operation::Bind(BindOp::ReadGuestReg(_)) |
+            Operation::Bind(BindOp::ReadFlag(_)) => {},
+            Operation::Bind(BindOp::WriteGuestReg(_, v)) => r(v),
+            Operation::Arith(ArithOp::Add32(x, y)) |
+            Operation::Arith(ArithOp::Sub32(x, y)) |
+            Operation::Memory(MemoryOp::Store32(x, y)) => { r(x); r(y); },
+            op => panic!("unexpected operation {}", op),
+        }
+    }
+}
+
+/// Lift the block at the program counter, rewrite it, and recompile it.
+fn compile_with(state: &GuestState, mmu: &mut GuestMmu, 
+    rewrite: fn(&mut BasicBlock)) -> BasicBlock 
+{
+    let mut bb = BasicBlock::lift(state, mmu, &HashSet::new()).unwrap();
+    rewrite(&mut bb);
+    bb.prune_dead_vars();
+    bb.recompile(mmu).unwrap();
+    bb
+}
+
+fn initial_state(cpsr: u32) -> GuestState {
+    let mut state = GuestState::new(CODE, cpsr);
+    for idx in 0..13 {
+        state.reg[idx] = 0x1000_0000 * (idx as u32 + 1) + 0x100;
+    }
+    state
+}
+
+fn check_subs(state: &GuestState, init: &GuestState) {
+    for rd in 0..12 {
+        assert_eq!(state.reg[rd], init.reg[rd + 1] - (rd as u32 + 1), 
+            "r{}", rd);
+    }
+}
+
+#[test]
+fn spilled_values() {
+    let mut mmu = GuestMmu::new();
+    let b_done = 0xea00_0000 | ((DONE - (CODE + 12 * 4) - 8) >> 2);
+    load(&mut mmu, CODE, &[subs(), vec![b_done]].concat());
+
+    let init = initial_state(SVC);
+    let bb = compile_with(&init, &mut mmu, sink_writes);
+    assert!(bb.storage.frame_size() != 0);
+
+    let mut state = init;
+    assert!(matches!(enter(&mut state, &mut mmu, &bb),
+        RuntimeExitCode::NextBlock));
+    check_subs(&state, &init);
+    assert_eq!(state.pc.fetch(), DONE);
+}
+
+#[test]
+fn spilled_values_across_links() {
+    const NOT_TAKEN: u32 = CODE + 12 * 4 + 4;
+    const TAKEN: u32 = CODE + 12 * 4 + 12;
+    const END: u32 = CODE + 12 * 4 + 16;
+    let mut mmu = GuestMmu::new();
+    load(&mut mmu, CODE, &[subs(), vec![
+        0x0a00_0001, // beq TAKEN
+        0xe3a0_c001, // mov r12, #1
+        0xea00_0000, // b END
+        0xe3a0_c002, // mov r12, #2
+        0xeaff_fffe, // b .
+    ]].concat());
+
+    let bb = compile_with(&initial_state(SVC), &mut mmu, sink_writes);
+    assert!(bb.storage.frame_size() != 0);
+    let taken = compile(&GuestState::new(TAKEN, SVC), &mut mmu);
+    let not_taken = compile(&GuestState::new(NOT_TAKEN, SVC), &mut mmu);
+
+    // The stack frame is torn down on both edges, whether they return to 
+    // the dispatcher or branch into another block
+    for linked in [false, true].iter() {
+        if *linked {
+            for exit in bb.exits.iter() {
+                match exit.target {
+                    TAKEN => exit.link(taken.entrypoint()),
+                    NOT_TAKEN => exit.link(not_taken.entrypoint()),
+                    _ => unreachable!(),
+                }
+            }
+        }
+        for (cpsr, target, r12) in [(SVC_Z, TAKEN, 2), (SVC, NOT_TAKEN, 1)]
+            .iter() 
+        {
+            let init = initial_state(*cpsr);
+            let mut state = init;
+            enter(&mut state, &mut mmu, &bb);
+            check_subs(&state, &init);
+            if *linked {
+                assert_eq!(state.reg[12], *r12);
+                assert_eq!(state.pc.fetch(), END);
+            } else {
+                assert_eq!(state.reg[12], init.reg[12]);
+                assert_eq!(state.pc.fetch(), *target);
+            }
+        }
+    }
+}
+
+/// Run the instructions in a block through the interpreter.
+fn reference(state: &mut GuestState, mmu: &mut GuestMmu, code: &[u32]) {
+    while let Some(opcd) = code.get(((state.pc.0 - CODE) / 4) as usize) {
+        nil::interp::step(state, mmu, *opcd).unwrap();
+        if state.pc.0 == DONE {
+            break;
+        }
+    }
+}
+
+/// Run a rewritten block (which stores the registers it writes below the 
+/// stack pointer), and compare the results with the interpreter.
+fn check(code: &[u32], rewrite: fn(&mut BasicBlock)) -> BasicBlock {
+    check_with(GuestMmu::new(), code, rewrite)
+}
+
+fn check_with(mut mmu: GuestMmu, code: &[u32], rewrite: fn(&mut BasicBlock))
+    -> BasicBlock 
+{
+    load(&mut mmu, CODE, code);
+    let mut init = initial_state(SVC);
+    init.reg[13] = 0x8000;
+
+    let bb = compile_with(&init, &mut mmu, rewrite);
+    let mut state = init;
+    enter(&mut state, &mut mmu, &bb);
+
+    let mut ref_mmu = GuestMmu::new();
+    load(&mut ref_mmu, CODE, code);
+    let mut ref_state = init;
+    reference(&mut ref_state, &mut ref_mmu, code);
+    assert_eq!(state.reg, ref_state.reg);
+    assert_eq!(state.pc.0, ref_state.pc.0);
+    for addr in (0x8000 - 48..0x8000).step_by(4) {
+        assert_eq!(mmu.load32(addr, true), ref_mmu.load32(addr, true), 
+            "[{:08x}]", addr);
+    }
+    bb
+}
+
+/// `subs()`, followed by `stmdb sp!, {r0-r11}; b DONE`.
+fn subs_and_store() -> Vec<u32> {
+    let b_done = 0xea00_0000 | ((DONE - (CODE + 13 * 4) - 8) >> 2);
+    [subs(), vec![0xe92d_0fff, b_done]].concat()
+}
+
+#[test]
+fn spilled_operands() {
+    // The results of the first instructions are live until they're stored,
+    // so some of the stores use spilled values
+    let bb = check(&subs_and_store(), forward_reads);
+    assert!(bb.storage.frame_size() != 0);
+}
+
+#[test]
+fn reloaded_values() {
+    // Spilled values are reloaded into registers before they're stored
+    let mut bb = check(&subs_and_store(), forward_reads);
+    let reloads = (0..=bb.data.len()).flat_map(|pos| {
+        bb.storage.seek(pos);
+        bb.storage.moves()
+    }).filter(|mv| matches!(mv, (StorageLoc::Spill(_), StorageLoc::Gpr(_))))
+        .count();
+    assert!(reloads != 0);
+}
+
+#[test]
+fn allocation_order() {
+    // Intervals are allocated in the order they start (not in the order of 
+    // the variables)
+    check(&subs_and_store(), |bb| {
+        forward_reads(bb);
+        shuffle_ids(bb);
+    });
+}
+
+/// Identity-map the first 64KiB and enable the MMU, so that every memory
+/// access from recompiled code calls into the runtime.
+fn slowmem() -> GuestMmu {
+    const TTBR: u32 = 0x0000_4000;
+    const COARSE: u32 = 0x0000_5000;
+    let mut mmu = GuestMmu::new();
+    let pages: Vec<u32> = (0..16).map(|i| (i << 12) | 0xff0 | 0b10).collect();
+    load(&mut mmu, COARSE, &pages);
+    load(&mut mmu, TTBR, &[COARSE | 0b01]);
+    mmu.vmsa.set_ttbr(TTBR);
+    mmu.vmsa.set_dacr(0b01);
+    mmu.vmsa.enabled = true;
+    mmu
+}
+
+#[test]
+fn live_across_calls() {
+    // Values in caller-save registers are preserved around each store
+    check_with(slowmem(), &subs_and_store(), forward_reads);
+}
+
+fn block() -> BasicBlock {
+    let mut bb = BasicBlock::new(ProgramCounter(CODE));
+    bb.guest_ops.push(0xe320_f000);
+    bb
+}
+
+/// Allocate registers for a block, and check that `v` is kept in a
+/// caller-save register which is saved around the call at `pos`.
+fn check_saved(mut bb: BasicBlock, v: Var, pos: usize) {
+    bb.link = Some(BlockLink::Exit(RuntimeExitCode::NextBlock, CODE + 4));
+    let intervals = IntervalMap::from_block(&bb);
+    let mut storage = allocate_registers(&intervals);
+
+    let reg = match storage.get_at(&v, pos) {
+        Some(StorageLoc::Gpr(r)) => HostRegister::from(*r),
+        loc => panic!("{} isn't in a register ({:?})", v, loc),
+    };
+    assert!(RuntimeContext::CALLER_SAVE_REGS.iter()
+        .any(|x| *x as u8 == reg as u8),
+        "{:?} isn't caller-save", reg);
+
+    storage.seek(pos);
+    assert!(storage.saved().contains(&reg),
+        "{:?} isn't saved around the call", reg);
+}
+
+#[test]
+fn load32_operand() {
+    let mut bb = block();
+    let addr = bb.read_reg(0);
+    let val = bb.load32(addr);
+    bb.write_reg(1, val);
+    check_saved(bb, addr, 1);
+}
+
+#[test]
+fn write_cpsr_operand() {
+    let mut bb = block();
+    let val = bb.read_reg(0);
+    bb.write_cpsr(val, 0xf000_0000);
+    check_saved(bb, val, 1);
+}
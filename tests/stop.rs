@@ -0,0 +1,78 @@
+//! Tests for the reasons that [Jit::run_for] returns.
+
+use nil::{ Jit, StopReason };
+use nil::guest::{ CpuMode, ExceptionType };
+
+mod common;
+use common::load;
+
+fn machine(code: &[u32]) -> Jit {
+    let mut jit = Jit::new();
+    load(&mut jit.mmu, 0x1000, code);
+    jit.state.pc.0 = 0x1000;
+    jit
+}
+
+#[test]
+fn svc_at_block_start() {
+    // A block with no IR before the terminal
+    let mut jit = machine(&[0xef00_0042]); // svc #0x42
+    assert_eq!(jit.run().unwrap(), StopReason::Svc { pc: 0x1000, imm: 0x42 });
+    assert_eq!(jit.state.pc.0, 0x1000);
+}
+
+#[test]
+fn svc_to_guest() {
+    let mut jit = machine(&[
+        0xe3a00001, // mov r0, #1
+        0x1f000001, // svcne #1
+        0xef000002, // svc #2
+    ]);
+    jit.state.cpsr.set_z(true);
+
+    // The block runs up to the exit, and failed conditions are skipped
+    assert_eq!(jit.run().unwrap(), StopReason::Svc { pc: 0x1008, imm: 2 });
+    assert_eq!(jit.state.reg[0], 1);
+
+    jit.raise_exception(ExceptionType::Svc);
+    assert_eq!(jit.state.cpsr.mode(), Ok(CpuMode::Svc));
+    assert_eq!(jit.state.pc.0, 0x0000_0008);
+    assert_eq!(jit.state.reg[14], 0x100c);
+}
+
+#[test]
+fn bkpt() {
+    let mut jit = machine(&[0xe121_2374]); // bkpt #0x1234
+    assert_eq!(jit.run().unwrap(), StopReason::Bkpt { pc: 0x1000, imm: 0x1234 });
+}
+
+#[test]
+fn breakpoints() {
+    let mut jit = machine(&[
+        0xe2400001, // sub r0, r0, #1
+        0xeafffffd, // b 0x1000
+    ]);
+    jit.add_breakpoint(0x1004);
+
+    // Blocks end before the breakpoint, which is reported once before 
+    // running the instruction
+    assert_eq!(jit.run().unwrap(), StopReason::Breakpoint(0x1004));
+    assert_eq!(jit.state.reg[0], -1i32 as u32);
+    assert_eq!(jit.run().unwrap(), StopReason::Breakpoint(0x1004));
+    assert_eq!(jit.state.reg[0], -2i32 as u32);
+
+    jit.remove_breakpoint(0x1004);
+    assert_eq!(jit.run_for(40).unwrap().reason, StopReason::Budget);
+}
+
+#[test]
+fn step() {
+    let mut jit = machine(&[
+        0xe2400001, // sub r0, r0, #1
+        0xeafffffd, // b 0x1000
+    ]);
+    assert_eq!(jit.step().unwrap(), StopReason::Budget);
+    assert_eq!(jit.state.reg[0], -1i32 as u32);
+    assert_eq!(jit.step().unwrap(), StopReason::Budget);
+    assert_eq!(jit.state.reg[0], -2i32 as u32);
+}
@@ -0,0 +1,56 @@
+//! Tests for cycle counting and [Jit::run_for].
+
+use nil::{ Jit, StopReason };
+use nil::lift::decode::ArmInst;
+
+mod common;
+use common::load;
+
+/// A loop which decrements r0 (4 cycles per iteration, by default).
+fn countdown() -> Jit {
+    let mut jit = Jit::new();
+    load(&mut jit.mmu, 0, &[
+        0xe2400001, // sub r0, r0, #1
+        0xeafffffd, // b 0
+    ]);
+    jit
+}
+
+#[test]
+fn budget_exhaustion() {
+    let mut jit = countdown();
+
+    // Blocks run to completion, so the budget is overshot
+    let res = jit.run_for(10).unwrap();
+    assert_eq!(res.reason, StopReason::Budget);
+    assert_eq!(res.cycles, 12);
+    assert_eq!(jit.state.reg[0], -3i32 as u32);
+    assert_eq!(jit.state.pc.fetch(), 0);
+
+    // The block is linked to itself now, and still stops on time
+    let res = jit.run_for(16).unwrap();
+    assert_eq!(res.cycles, 16);
+    assert_eq!(jit.state.reg[0], -7i32 as u32);
+
+    let res = jit.run_for(1).unwrap();
+    assert_eq!(res.cycles, 4);
+    assert_eq!(jit.state.reg[0], -8i32 as u32);
+
+    // Nothing runs without a budget
+    let res = jit.run_for(0).unwrap();
+    assert_eq!(res, nil::RunResult { cycles: 0, reason: StopReason::Budget });
+    assert_eq!(jit.state.reg[0], -8i32 as u32);
+}
+
+#[test]
+fn cycle_costs() {
+    let mut jit = countdown();
+    jit.run_for(4).unwrap();
+    assert_eq!(jit.state.reg[0], -1i32 as u32);
+
+    // Changing a cost discards the block which was compiled with the old one
+    jit.set_cycle_cost(ArmInst::B, 1);
+    let res = jit.run_for(10).unwrap();
+    assert_eq!(res.cycles, 10);
+    assert_eq!(jit.state.reg[0], -6i32 as u32);
+}